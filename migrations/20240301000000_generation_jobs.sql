@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS generation_jobs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    endpoint_path VARCHAR(255) NOT NULL,
+    quality VARCHAR(32) NOT NULL,
+    prompt TEXT NOT NULL,
+    prompt_hash VARCHAR(64) NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    provider_request JSONB,
+    result JSONB,
+    error TEXT,
+    payer_address VARCHAR(42),
+    payment_tx VARCHAR(66),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_generation_jobs_status
+    ON generation_jobs (status);
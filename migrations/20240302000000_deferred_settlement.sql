@@ -0,0 +1,16 @@
+-- Jobs keep the verified payment so it can be settled once generation succeeds
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS payment_request JSONB;
+
+-- Generations that failed after payment was verified; their payments were never settled
+CREATE TABLE IF NOT EXISTS generation_failures (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    endpoint_path VARCHAR(255) NOT NULL,
+    prompt TEXT NOT NULL,
+    job_id UUID REFERENCES generation_jobs (id) ON DELETE SET NULL,
+    payer_address VARCHAR(42),
+    error TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_generation_failures_created_at
+    ON generation_failures (created_at);
@@ -0,0 +1,2 @@
+-- Settlement receipt returned to clients as X-PAYMENT-RESPONSE when they poll the job
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS payment_response JSONB;
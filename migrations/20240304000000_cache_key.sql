@@ -0,0 +1,12 @@
+-- Cache lookups use a canonical key derived from the full merged provider request body
+-- (request_params + user options + prompt) instead of the prompt alone.
+-- Rows created before this migration are backfilled by the router at startup, since
+-- rebuilding their request bodies needs endpoints.ron (see pipeline::backfill_cache_keys).
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS cache_key VARCHAR(64);
+
+CREATE INDEX IF NOT EXISTS idx_generated_media_cache_key
+    ON generated_media (endpoint_path, cache_key);
+
+-- Jobs keep the exact request they were created for, so resumed jobs submit the same body
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS cache_key VARCHAR(64);
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS request_body JSONB;
@@ -0,0 +1,8 @@
+-- A generation can yield several outputs (e.g. num_images > 1). Each output is its own row;
+-- siblings share a generation_id and are ordered by output_index.
+-- Existing rows become single-output generations.
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS generation_id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS output_index INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS idx_generated_media_generation
+    ON generated_media (generation_id, output_index);
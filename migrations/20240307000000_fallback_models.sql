@@ -0,0 +1,4 @@
+-- Endpoints can fall back to other models; record which one produced each result
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS model TEXT;
+-- The model a job is running on, so a resumed job keeps polling the right one
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS model TEXT;
@@ -0,0 +1,34 @@
+-- Every facilitator verify/settle call, kept forever as the revenue history.
+-- The cleanup worker only ever deletes generated_media rows, never these.
+CREATE TABLE IF NOT EXISTS payments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- verify or settle
+    operation VARCHAR(16) NOT NULL,
+    -- verified, invalid, settled, rejected or error (facilitator unreachable)
+    outcome VARCHAR(16) NOT NULL,
+    resource TEXT NOT NULL,
+    scheme VARCHAR(16) NOT NULL,
+    network TEXT NOT NULL,
+    -- Token contract address
+    asset TEXT NOT NULL,
+    -- Raw token units (maxAmountRequired)
+    amount NUMERIC(78, 0) NOT NULL,
+    pay_to TEXT NOT NULL,
+    payer_address TEXT,
+    -- sha256 of the payment payload, shared by a payment's verify and settle rows
+    payload_hash VARCHAR(64) NOT NULL,
+    requirements JSONB NOT NULL,
+    tx_hash TEXT,
+    error TEXT,
+    -- On settle rows: the verify row of the same payment
+    verification_id UUID REFERENCES payments(id),
+    latency_ms BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_payments_created ON payments (created_at);
+CREATE INDEX IF NOT EXISTS idx_payments_payload_hash ON payments (payload_hash);
+CREATE INDEX IF NOT EXISTS idx_payments_tx_hash ON payments (tx_hash) WHERE tx_hash IS NOT NULL;
+
+-- The settlement that paid for a media row
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS payment_id UUID REFERENCES payments(id);
@@ -0,0 +1,11 @@
+-- One row per X-PAYMENT payload ever presented, so the same signed payload can't be used twice
+CREATE TABLE IF NOT EXISTS payment_payloads (
+    -- sha256 of the decoded payment payload (same as payments.payload_hash)
+    payload_hash VARCHAR(64) PRIMARY KEY,
+    -- verifying, verified, settling, settled or failed (never settled; may be presented again)
+    state VARCHAR(16) NOT NULL,
+    resource TEXT NOT NULL,
+    tx_hash TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
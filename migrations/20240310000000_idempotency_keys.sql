@@ -0,0 +1,27 @@
+-- Outcomes of paid requests sent with an Idempotency-Key, replayed to retries of the same
+-- request: the same key with the same payment payload, or with another payload from the same payer
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    idempotency_key TEXT NOT NULL,
+    payload_hash VARCHAR(64) NOT NULL,
+    -- Set once the payment is verified
+    payer_address TEXT,
+    endpoint_path TEXT NOT NULL,
+    -- sha256 of the route, prompt, params and input; a key can't be reused for another request
+    request_hash VARCHAR(64) NOT NULL,
+    -- in_flight until the first request answers, then completed
+    state VARCHAR(16) NOT NULL DEFAULT 'in_flight',
+    status_code INTEGER,
+    content_type TEXT,
+    response_body BYTEA,
+    -- X-PAYMENT-RESPONSE receipt of the first request
+    payment_response TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ NOT NULL,
+    UNIQUE (idempotency_key, payload_hash)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_idempotency_keys_payer
+    ON idempotency_keys (idempotency_key, lower(payer_address)) WHERE payer_address IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_expires ON idempotency_keys (expires_at);
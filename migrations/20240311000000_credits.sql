@@ -0,0 +1,38 @@
+-- Prepaid credits: one settled x402 top-up funds many generations, paid with a bearer token
+
+-- Balance per payer and asset, in raw token units
+CREATE TABLE IF NOT EXISTS credit_balances (
+    payer_address TEXT NOT NULL,
+    asset_id TEXT NOT NULL,
+    balance NUMERIC(78, 0) NOT NULL DEFAULT 0 CHECK (balance >= 0),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (payer_address, asset_id)
+);
+
+-- Every balance change: topup (linked to its settlement), debit (a generation) or refund (of a
+-- debit whose generation failed)
+CREATE TABLE IF NOT EXISTS credit_transactions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    payer_address TEXT NOT NULL,
+    asset_id TEXT NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    amount NUMERIC(78, 0) NOT NULL,
+    resource TEXT NOT NULL,
+    payment_id UUID REFERENCES payments(id),
+    refund_of UUID UNIQUE REFERENCES credit_transactions(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_credit_transactions_payer ON credit_transactions (payer_address, created_at);
+
+-- Bearer tokens, stored as sha256 hex
+CREATE TABLE IF NOT EXISTS credit_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    payer_address TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+-- Debit paying for an async job, refunded if the job fails
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS credit_debit_id UUID REFERENCES credit_transactions(id);
@@ -0,0 +1,4 @@
+-- Media generated for a payment that then failed to settle. It is kept only so the cleanup
+-- worker removes its objects; cache lookups skip it, so it is never served unpaid or at a
+-- discounted cached price.
+ALTER TABLE generated_media ADD COLUMN IF NOT EXISTS unsettled BOOLEAN NOT NULL DEFAULT FALSE;
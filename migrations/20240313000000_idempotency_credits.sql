@@ -0,0 +1,4 @@
+-- Requests paid with a credit token are claimed per (key, credit payer): their payload_hash is
+-- derived from the payer instead of an X-PAYMENT payload. The debit's headers are replayed too.
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS credit_balance TEXT;
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS credit_asset TEXT;
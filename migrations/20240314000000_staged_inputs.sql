@@ -0,0 +1,9 @@
+-- Input images copied to our storage under inputs/{sha256}.{ext}. Staging the same image again
+-- extends its expiry; the cleanup worker deletes expired objects and their rows.
+CREATE TABLE IF NOT EXISTS staged_inputs (
+    s3_key TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_staged_inputs_expires ON staged_inputs (expires_at);
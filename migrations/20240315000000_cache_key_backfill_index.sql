@@ -0,0 +1,4 @@
+-- The startup backfill of cache keys (pipeline::backfill_cache_keys) looks for rows without
+-- one on every start; once they are all backfilled this index keeps that lookup free.
+CREATE INDEX IF NOT EXISTS idx_generated_media_missing_cache_key
+    ON generated_media (id) WHERE cache_key IS NULL;
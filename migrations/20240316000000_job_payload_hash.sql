@@ -0,0 +1,6 @@
+-- The payment payload an async job holds verified until it settles, so stale `verified`
+-- payloads can be released without taking one from a job that is still running.
+ALTER TABLE generation_jobs ADD COLUMN IF NOT EXISTS payload_hash VARCHAR(64);
+
+CREATE INDEX IF NOT EXISTS idx_generation_jobs_unfinished_payload
+    ON generation_jobs (payload_hash) WHERE status IN ('pending', 'running');
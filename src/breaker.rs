@@ -0,0 +1,176 @@
+//! Per-model circuit breakers. Every provider call reports its outcome; once a model's recent
+//! error rate crosses the threshold its circuit opens, and qualities with no usable model
+//! answer 503 before asking for payment. After a cooldown one trial call is let through,
+//! which closes the circuit again on success.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::BreakerConfig;
+use crate::endpoints::EndpointDef;
+
+/// Calls remembered per model for its error rate and latency.
+const WINDOW: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// Cooldown over: the next call is a trial.
+    HalfOpen,
+}
+
+struct Outcome {
+    ok: bool,
+    latency: Duration,
+}
+
+#[derive(Default)]
+struct ModelHealth {
+    recent: VecDeque<Outcome>,
+    /// Set while the circuit is open; calls are refused until then.
+    open_until: Option<Instant>,
+}
+
+impl ModelHealth {
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.open_until {
+            None => CircuitState::Closed,
+            Some(until) if now < until => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        self.recent.iter().filter(|o| !o.ok).count() as f64 / self.recent.len() as f64
+    }
+
+    /// Mean latency of the recent successful calls.
+    fn avg_latency(&self) -> Option<Duration> {
+        let ok: Vec<Duration> = self.recent.iter().filter(|o| o.ok).map(|o| o.latency).collect();
+        (!ok.is_empty()).then(|| ok.iter().sum::<Duration>() / ok.len() as u32)
+    }
+}
+
+/// A model's health as shown in `/api`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStatus {
+    pub model: String,
+    pub state: CircuitState,
+    /// Share of the recent calls that failed.
+    pub error_rate: f64,
+    pub recent_calls: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_latency_ms: Option<u64>,
+    /// While open: seconds until a trial call is allowed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+}
+
+pub struct Breakers {
+    config: BreakerConfig,
+    models: Mutex<HashMap<String, ModelHealth>>,
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claim a call to `model`. A half-open circuit lets the caller make the trial call and
+    /// refuses others until it reports back (or another cooldown passes).
+    pub fn try_call(&self, model: &str) -> bool {
+        let now = Instant::now();
+        let mut models = self.models.lock().unwrap();
+        let Some(health) = models.get_mut(model) else { return true };
+        match health.state(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                health.open_until = Some(now + self.config.cooldown);
+                true
+            }
+        }
+    }
+
+    /// Report the outcome of a provider call.
+    pub fn record(&self, model: &str, ok: bool, latency: Duration) {
+        let now = Instant::now();
+        let mut models = self.models.lock().unwrap();
+        let health = models.entry(model.to_string()).or_default();
+        if health.recent.len() >= WINDOW {
+            health.recent.pop_front();
+        }
+        health.recent.push_back(Outcome { ok, latency });
+
+        if health.open_until.is_some() {
+            // A trial call (or one started before the circuit opened) decides
+            if ok {
+                tracing::info!("Circuit for {} closed", model);
+                health.open_until = None;
+                health.recent.retain(|o| o.ok);
+            } else {
+                health.open_until = Some(now + self.config.cooldown);
+            }
+        } else if !ok
+            && health.recent.len() >= self.config.min_calls
+            && health.error_rate() >= self.config.error_rate
+        {
+            tracing::warn!(
+                "Circuit for {} opened: {:.0}% of the last {} calls failed; retrying in {}s",
+                model,
+                health.error_rate() * 100.0,
+                health.recent.len(),
+                self.config.cooldown.as_secs()
+            );
+            health.open_until = Some(now + self.config.cooldown);
+        }
+    }
+
+    pub fn status(&self, model: &str) -> ModelStatus {
+        let now = Instant::now();
+        let models = self.models.lock().unwrap();
+        let Some(health) = models.get(model) else {
+            return ModelStatus {
+                model: model.to_string(),
+                state: CircuitState::Closed,
+                error_rate: 0.0,
+                recent_calls: 0,
+                avg_latency_ms: None,
+                retry_after_seconds: None,
+            };
+        };
+        let state = health.state(now);
+        ModelStatus {
+            model: model.to_string(),
+            state,
+            error_rate: health.error_rate(),
+            recent_calls: health.recent.len(),
+            avg_latency_ms: health.avg_latency().map(|d| d.as_millis() as u64),
+            retry_after_seconds: match (state, health.open_until) {
+                (CircuitState::Open, Some(until)) => Some(until.saturating_duration_since(now).as_secs().max(1)),
+                _ => None,
+            },
+        }
+    }
+
+    /// When every model of an endpoint has an open circuit: seconds until the first can be tried.
+    pub fn endpoint_retry_after(&self, endpoint: &EndpointDef) -> Option<u64> {
+        endpoint
+            .targets()
+            .iter()
+            .map(|t| self.status(t.model).retry_after_seconds)
+            .collect::<Option<Vec<u64>>>()
+            .and_then(|waits| waits.into_iter().min())
+    }
+}
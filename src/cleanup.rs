@@ -1,16 +1,16 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use aws_sdk_s3::Client as S3Client;
 use sqlx::PgPool;
 use tokio::sync::broadcast;
 
 use crate::db;
-use crate::s3;
+use crate::idempotency;
+use crate::storage::Storage;
 
 pub async fn run_cleanup_worker(
     pool: PgPool,
-    s3_client: S3Client,
-    s3_bucket: String,
+    storage: Arc<dyn Storage>,
     mut shutdown: broadcast::Receiver<()>,
 ) {
     let mut interval = tokio::time::interval(Duration::from_secs(3600));
@@ -20,7 +20,9 @@ pub async fn run_cleanup_worker(
     loop {
         tokio::select! {
             _ = interval.tick() => {
-                cleanup_expired(&pool, &s3_client, &s3_bucket).await;
+                cleanup_expired(&pool, storage.as_ref()).await;
+                cleanup_staged_inputs(&pool, storage.as_ref()).await;
+                idempotency::cleanup_expired(&pool).await;
             }
             _ = shutdown.recv() => {
                 tracing::info!("Cleanup worker shutting down");
@@ -30,7 +32,11 @@ pub async fn run_cleanup_worker(
     }
 }
 
-async fn cleanup_expired(pool: &PgPool, s3_client: &S3Client, s3_bucket: &str) {
+/// Delete expired media objects and their `generated_media` rows. The payments ledger they
+/// link to is kept. Outputs are stored under their cache key, so a later generation of the same
+/// request (e.g. a paid retry of one that failed to settle) reuses an expired row's object:
+/// objects an unexpired row still points at are kept.
+pub async fn cleanup_expired(pool: &PgPool, storage: &dyn Storage) {
     let expired = match db::find_expired(pool).await {
         Ok(records) => records,
         Err(e) => {
@@ -47,9 +53,18 @@ async fn cleanup_expired(pool: &PgPool, s3_client: &S3Client, s3_bucket: &str) {
     tracing::info!("Cleaning up {} expired media records", expired.len());
 
     for record in &expired {
-        if let Err(e) = s3::delete_file(s3_client, s3_bucket, &record.s3_key).await {
-            tracing::error!("Failed to delete S3 object {}: {}", record.s3_key, e);
-            continue;
+        match db::media_key_in_use(pool, &record.s3_key).await {
+            Ok(true) => tracing::debug!("Keeping {}: a live row still uses it", record.s3_key),
+            Ok(false) => {
+                if let Err(e) = storage.delete(&record.s3_key).await {
+                    tracing::error!("Failed to delete stored object {}: {}", record.s3_key, e);
+                    continue;
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to check whether {} is still used: {}", record.s3_key, e);
+                continue;
+            }
         }
 
         if let Err(e) = db::delete_by_id(pool, record.id).await {
@@ -59,3 +74,27 @@ async fn cleanup_expired(pool: &PgPool, s3_client: &S3Client, s3_bucket: &str) {
         }
     }
 }
+
+/// Delete expired staged input images and their `staged_inputs` rows.
+pub async fn cleanup_staged_inputs(pool: &PgPool, storage: &dyn Storage) {
+    let expired = match db::find_expired_staged_inputs(pool).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            tracing::error!("Failed to query expired staged inputs: {}", e);
+            return;
+        }
+    };
+
+    for s3_key in &expired {
+        if let Err(e) = storage.delete(s3_key).await {
+            tracing::error!("Failed to delete stored object {}: {}", s3_key, e);
+            continue;
+        }
+
+        if let Err(e) = db::delete_staged_input(pool, s3_key).await {
+            tracing::error!("Failed to delete staged input record {}: {}", s3_key, e);
+        } else {
+            tracing::info!("Cleaned up expired staged input: {}", s3_key);
+        }
+    }
+}
@@ -1,28 +1,208 @@
 use std::env;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::x402::PaymentScheme;
+
+/// A token on a specific network that endpoints can be paid in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PaymentAsset {
+    /// Key used by `prices` in endpoints.ron, e.g. "usdc-base".
+    pub id: String,
+    pub network: String,
+    /// Token contract address.
+    pub address: String,
+    pub symbol: String,
+    pub decimals: u8,
+    /// EIP-712 domain name of the token.
+    pub name: String,
+    /// EIP-712 domain version of the token.
+    pub version: String,
+    pub schemes: Vec<PaymentScheme>,
+    /// Receiving address on this network; defaults to WALLET_ADDRESS.
+    #[serde(default)]
+    pub pay_to: Option<String>,
+    /// Smallest credit top-up accepted in this asset (human amount); any positive amount if unset.
+    #[serde(default)]
+    pub min_topup: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AssetsConfig {
+    assets: Vec<PaymentAsset>,
+}
+
+fn load_assets(path: &str) -> Vec<PaymentAsset> {
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read payment assets config '{}': {}", path, e));
+    let config: AssetsConfig = ron::from_str(&content)
+        .unwrap_or_else(|e| panic!("Failed to parse payment assets config '{}': {}", path, e));
+    config.assets
+}
+
+/// Single asset described by the PAYMENT_TOKEN_* variables, used when no assets file is configured.
+fn legacy_asset_from_env() -> PaymentAsset {
+    let network = env::var("PAYMENT_NETWORK").unwrap_or_else(|_| "base".to_string());
+    let symbol = env::var("PAYMENT_TOKEN_SYMBOL").unwrap_or_else(|_| "STARKBOT".to_string());
+    PaymentAsset {
+        id: format!("{}-{}", symbol.to_lowercase(), network),
+        network,
+        address: env::var("PAYMENT_TOKEN_ADDRESS")
+            .unwrap_or_else(|_| "0x587Cd533F418825521f3A1daa7CCd1E7339A1B07".to_string()),
+        symbol,
+        decimals: env::var("PAYMENT_TOKEN_DECIMALS")
+            .unwrap_or_else(|_| "18".to_string())
+            .parse()
+            .expect("PAYMENT_TOKEN_DECIMALS must be a valid number"),
+        name: env::var("PAYMENT_TOKEN_NAME").unwrap_or_else(|_| "StarkBot".to_string()),
+        version: env::var("PAYMENT_TOKEN_VERSION").unwrap_or_else(|_| "1".to_string()),
+        schemes: env::var("PAYMENT_SCHEMES")
+            .unwrap_or_else(|_| "permit".to_string())
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| PaymentScheme::parse(s).unwrap_or_else(|e| panic!("PAYMENT_SCHEMES: {}", e)))
+            .collect(),
+        pay_to: None,
+        min_topup: env::var("PAYMENT_MIN_TOPUP").ok().filter(|s| !s.is_empty()),
+    }
+}
+
+/// S3-compatible object storage (DigitalOcean Spaces, AWS, MinIO, ...).
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub cdn_url: String,
+}
+
+/// Where generated media is stored.
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    S3(S3Config),
+    /// Files under `dir`, served by the router itself under PUBLIC_URL.
+    Local { dir: String },
+}
+
+/// Variables the `s3` backend can't run without.
+const S3_REQUIRED: [&str; 4] = ["S3_ENDPOINT", "S3_BUCKET", "S3_ACCESS_KEY", "S3_SECRET_KEY"];
+
+/// STORAGE_BACKEND picks the backend. Unset, any of the S3 variables selects `s3`; with none
+/// of them the router refuses to start rather than quietly write media to its own disk.
+fn storage_from_env() -> StorageConfig {
+    let is_set = |name: &str| env::var(name).is_ok_and(|v| !v.is_empty());
+    let backend = match env::var("STORAGE_BACKEND") {
+        Ok(backend) => backend,
+        Err(_) if S3_REQUIRED.iter().any(|name| is_set(name)) => "s3".to_string(),
+        Err(_) => panic!("STORAGE_BACKEND must be set to 's3' or 'local' (or set the S3_* variables)"),
+    };
+
+    match backend.as_str() {
+        "s3" => {
+            let missing: Vec<&str> = S3_REQUIRED.iter().copied().filter(|name| !is_set(name)).collect();
+            if !missing.is_empty() {
+                panic!("S3 storage is missing {}", missing.join(", "));
+            }
+            let bucket = env::var("S3_BUCKET").expect("S3_BUCKET must be set");
+            let region = env::var("S3_REGION").unwrap_or_else(|_| "nyc3".to_string());
+            StorageConfig::S3(S3Config {
+                endpoint: env::var("S3_ENDPOINT").expect("S3_ENDPOINT must be set"),
+                access_key: env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY must be set"),
+                secret_key: env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY must be set"),
+                cdn_url: env::var("S3_CDN_URL")
+                    .unwrap_or_else(|_| format!("https://{}.{}.digitaloceanspaces.com", bucket, region)),
+                bucket,
+                region,
+            })
+        }
+        "local" => StorageConfig::Local {
+            dir: env::var("LOCAL_STORAGE_DIR").unwrap_or_else(|_| "public".to_string()),
+        },
+        other => panic!("STORAGE_BACKEND must be 's3' or 'local', got '{}'", other),
+    }
+}
+
+/// When a model's circuit breaker opens (see `breaker`).
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Share of recent calls that must fail.
+    pub error_rate: f64,
+    /// Recent calls needed before the error rate counts.
+    pub min_calls: usize,
+    /// How long an open circuit refuses calls before letting a trial through.
+    pub cooldown: Duration,
+}
+
+fn breaker_from_env() -> BreakerConfig {
+    BreakerConfig {
+        error_rate: env::var("BREAKER_ERROR_RATE")
+            .unwrap_or_else(|_| "0.5".to_string())
+            .parse()
+            .expect("BREAKER_ERROR_RATE must be a number between 0 and 1"),
+        min_calls: env::var("BREAKER_MIN_CALLS")
+            .unwrap_or_else(|_| "5".to_string())
+            .parse()
+            .expect("BREAKER_MIN_CALLS must be a valid number"),
+        cooldown: Duration::from_secs(
+            env::var("BREAKER_COOLDOWN_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("BREAKER_COOLDOWN_SECONDS must be a valid number"),
+        ),
+    }
+}
+
+/// Prompt checks run before payment (see `moderation`).
+#[derive(Debug, Clone)]
+pub struct ModerationConfig {
+    /// RON file of blocked keywords and patterns.
+    pub blocklist_path: Option<String>,
+    /// External moderation API, e.g. "openai".
+    pub provider: Option<String>,
+    pub api_key: Option<String>,
+    pub url: String,
+}
+
+fn moderation_from_env() -> ModerationConfig {
+    ModerationConfig {
+        blocklist_path: env::var("MODERATION_BLOCKLIST").ok().filter(|s| !s.is_empty()),
+        provider: env::var("MODERATION_PROVIDER").ok().filter(|s| !s.is_empty()),
+        api_key: env::var("MODERATION_API_KEY").ok().filter(|s| !s.is_empty()),
+        url: env::var("MODERATION_URL").unwrap_or_else(|_| "https://api.openai.com/v1/moderations".to_string()),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub test_mode: bool,
     pub port: u16,
     pub facilitator_url: String,
+    /// Path of the facilitator's verify endpoint, appended to `facilitator_url`.
+    pub facilitator_verify_path: String,
+    /// Path of the facilitator's settle endpoint, appended to `facilitator_url`.
+    pub facilitator_settle_path: String,
     pub facilitator_signer: String,
     pub wallet_address: String,
-    pub payment_network: String,
-    pub payment_token_address: String,
-    pub payment_token_symbol: String,
-    pub payment_token_decimals: u8,
-    pub payment_token_name: String,
-    pub payment_token_version: String,
+    /// Accepted payment assets. The first one is the primary asset priced by an endpoint's `cost`.
+    pub payment_assets: Vec<PaymentAsset>,
     pub fal_key: String,
+    /// Base URL for synchronous fal requests.
+    pub fal_base_url: String,
+    /// Base URL for fal's queue API (async jobs).
+    pub fal_queue_url: String,
     pub public_url: String,
     pub endpoints_config_path: String,
-    pub s3_endpoint: String,
-    pub s3_bucket: String,
-    pub s3_region: String,
-    pub s3_access_key: String,
-    pub s3_secret_key: String,
-    pub s3_cdn_url: String,
+    pub storage: StorageConfig,
     pub database_url: String,
+    /// HMAC key for signing job webhooks. Requests can only ask for a `callback_url` when set.
+    pub webhook_secret: Option<String>,
+    /// Let callback and input image URLs reach private and loopback addresses (local development).
+    pub allow_private_urls: bool,
+    pub breaker: BreakerConfig,
+    pub moderation: ModerationConfig,
 }
 
 impl Config {
@@ -39,40 +219,53 @@ impl Config {
                 .expect("PORT must be a valid number"),
             facilitator_url: env::var("FACILITATOR_URL")
                 .unwrap_or_else(|_| "https://facilitator.x402.org".to_string()),
+            facilitator_verify_path: env::var("FACILITATOR_VERIFY_PATH")
+                .unwrap_or_else(|_| "/verify".to_string()),
+            facilitator_settle_path: env::var("FACILITATOR_SETTLE_PATH")
+                .unwrap_or_else(|_| "/settle".to_string()),
             facilitator_signer: env::var("FACILITATOR_SIGNER")
                 .unwrap_or_else(|_| if test_mode { String::new() } else { panic!("FACILITATOR_SIGNER must be set") }),
             wallet_address: env::var("WALLET_ADDRESS")
                 .unwrap_or_else(|_| if test_mode { String::new() } else { panic!("WALLET_ADDRESS must be set") }),
-            payment_network: env::var("PAYMENT_NETWORK")
-                .unwrap_or_else(|_| "base".to_string()),
-            payment_token_address: env::var("PAYMENT_TOKEN_ADDRESS")
-                .unwrap_or_else(|_| "0x587Cd533F418825521f3A1daa7CCd1E7339A1B07".to_string()),
-            payment_token_symbol: env::var("PAYMENT_TOKEN_SYMBOL")
-                .unwrap_or_else(|_| "STARKBOT".to_string()),
-            payment_token_decimals: env::var("PAYMENT_TOKEN_DECIMALS")
-                .unwrap_or_else(|_| "18".to_string())
-                .parse()
-                .expect("PAYMENT_TOKEN_DECIMALS must be a valid number"),
-            payment_token_name: env::var("PAYMENT_TOKEN_NAME")
-                .unwrap_or_else(|_| "StarkBot".to_string()),
-            payment_token_version: env::var("PAYMENT_TOKEN_VERSION")
-                .unwrap_or_else(|_| "1".to_string()),
+            payment_assets: match env::var("PAYMENT_ASSETS_CONFIG") {
+                Ok(path) => load_assets(&path),
+                Err(_) => vec![legacy_asset_from_env()],
+            },
             fal_key: env::var("FAL_KEY").expect("FAL_KEY must be set"),
+            fal_base_url: env::var("FAL_BASE_URL")
+                .unwrap_or_else(|_| "https://fal.run".to_string()),
+            fal_queue_url: env::var("FAL_QUEUE_URL")
+                .unwrap_or_else(|_| "https://queue.fal.run".to_string()),
             public_url: env::var("PUBLIC_URL")
                 .unwrap_or_else(|_| "http://localhost:3402".to_string()),
             endpoints_config_path: env::var("ENDPOINTS_CONFIG")
                 .unwrap_or_else(|_| "endpoints.ron".to_string()),
-            s3_endpoint: env::var("S3_ENDPOINT").expect("S3_ENDPOINT must be set"),
-            s3_bucket: env::var("S3_BUCKET").expect("S3_BUCKET must be set"),
-            s3_region: env::var("S3_REGION").unwrap_or_else(|_| "nyc3".to_string()),
-            s3_access_key: env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY must be set"),
-            s3_secret_key: env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY must be set"),
-            s3_cdn_url: env::var("S3_CDN_URL").unwrap_or_else(|_| {
-                let bucket = env::var("S3_BUCKET").expect("S3_BUCKET must be set");
-                let region = env::var("S3_REGION").unwrap_or_else(|_| "nyc3".to_string());
-                format!("https://{}.{}.digitaloceanspaces.com", bucket, region)
-            }),
+            storage: storage_from_env(),
             database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            webhook_secret: env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()),
+            allow_private_urls: env::var("ALLOW_PRIVATE_URLS")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            breaker: breaker_from_env(),
+            moderation: moderation_from_env(),
         }
     }
+
+    /// The asset an endpoint's `cost` is denominated in.
+    pub fn primary_asset(&self) -> &PaymentAsset {
+        &self.payment_assets[0]
+    }
+
+    /// Full URL of a facilitator endpoint, e.g. `facilitator_endpoint(&self.facilitator_verify_path)`.
+    pub fn facilitator_endpoint(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.facilitator_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    pub fn find_asset(&self, id: &str) -> Option<&PaymentAsset> {
+        self.payment_assets.iter().find(|a| a.id == id)
+    }
 }
@@ -0,0 +1,332 @@
+//! Prepaid credits. `POST /credits/topup` settles one x402 payment into the payer's balance for
+//! that asset and issues a bearer token; generation requests with `Authorization: Bearer <token>`
+//! are then paid by debiting the balance instead of with an X-PAYMENT header. Debits of failed
+//! generations are refunded.
+
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::config::PaymentAsset;
+use crate::db;
+use crate::domain_types::DomainU256;
+use crate::handler::with_receipt;
+use crate::pricing::Quote;
+use crate::x402;
+
+pub const TOPUP_ROUTE: &str = "/credits/topup";
+/// Remaining balance, in the debited asset, on responses paid with credits.
+pub const BALANCE_HEADER: &str = "X-Credit-Balance";
+/// Asset id the credits were debited in.
+pub const ASSET_HEADER: &str = "X-Credit-Asset";
+
+const TOKEN_PREFIX: &str = "x402c_";
+const TOPUP_PAYMENT_TIMEOUT: u64 = 300;
+
+/// Credits taken for one generation.
+#[derive(Debug, Clone)]
+pub struct Debit {
+    pub id: Uuid,
+    pub payer: String,
+    pub asset_id: String,
+    /// Remaining balance, human-readable.
+    pub balance: String,
+}
+
+#[derive(Serialize)]
+struct BalanceInfo {
+    asset: String,
+    symbol: String,
+    /// Human-readable amount.
+    balance: String,
+    /// Raw token units.
+    balance_raw: String,
+}
+
+#[derive(Deserialize)]
+pub struct TopupRequest {
+    /// Human-readable amount of the asset, e.g. "100" or "2.5".
+    pub amount: serde_json::Value,
+    /// Asset id from the payment assets config; defaults to the primary asset.
+    pub asset: Option<String>,
+}
+
+fn error(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({ "error": message }))
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(serde_json::json!({ "error": "Invalid or revoked credit token" }))
+}
+
+fn unavailable(e: sqlx::Error) -> HttpResponse {
+    tracing::error!("Credits query failed: {}", e);
+    error(StatusCode::SERVICE_UNAVAILABLE, "Credits are temporarily unavailable")
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A new random token. Only its hash is stored.
+fn new_token() -> String {
+    format!("{}{}{}", TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// The bearer token of a request, if it sent one.
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn human(raw: &str, asset: Option<&PaymentAsset>) -> String {
+    match (DomainU256::from_string(raw), asset) {
+        (Ok(amount), Some(asset)) => amount.to_human_amount(asset.decimals),
+        _ => raw.to_string(),
+    }
+}
+
+/// The payer a request's bearer token belongs to. None without a token; 401 for an unknown one.
+pub async fn authenticate(pool: &PgPool, headers: &HeaderMap) -> Result<Option<String>, HttpResponse> {
+    let Some(token) = bearer(headers) else {
+        return Ok(None);
+    };
+    match db::use_credit_token(pool, &hash_token(token)).await {
+        Ok(Some(payer)) => Ok(Some(payer)),
+        Ok(None) => Err(unauthorized()),
+        Err(e) => Err(unavailable(e)),
+    }
+}
+
+async fn balances(state: &AppState, payer: &str) -> Result<Vec<BalanceInfo>, HttpResponse> {
+    let rows = db::find_credit_balances(&state.db_pool, payer).await.map_err(unavailable)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let asset = state.config.find_asset(&row.asset_id);
+            BalanceInfo {
+                symbol: asset.map(|a| a.symbol.clone()).unwrap_or_default(),
+                balance: human(&row.balance, asset),
+                asset: row.asset_id,
+                balance_raw: row.balance,
+            }
+        })
+        .collect())
+}
+
+/// Debit the price of a request, in the first quoted asset the payer's balance covers.
+/// Answers 402 with the balances and prices when none does.
+pub async fn debit(state: &AppState, payer: &str, quotes: &[Quote<'_>], resource: &str) -> Result<Debit, HttpResponse> {
+    for quote in quotes {
+        let amount = quote.amount.to_string();
+        match db::debit_credits(&state.db_pool, payer, &quote.asset.id, &amount, resource).await {
+            Ok(Some((id, balance))) => {
+                tracing::info!("[{}] Debited {} {} credits from {}", resource, quote.human, quote.asset.symbol, payer);
+                return Ok(Debit {
+                    id,
+                    payer: payer.to_string(),
+                    asset_id: quote.asset.id.clone(),
+                    balance: human(&balance, Some(quote.asset)),
+                });
+            }
+            Ok(None) => {}
+            Err(e) => return Err(unavailable(e)),
+        }
+    }
+
+    let prices: serde_json::Map<String, serde_json::Value> = quotes
+        .iter()
+        .map(|q| (q.asset.id.clone(), serde_json::Value::String(q.human.clone())))
+        .collect();
+    Err(HttpResponse::PaymentRequired().json(serde_json::json!({
+        "error": "Insufficient credits",
+        "prices": prices,
+        "balances": balances(state, payer).await?,
+        "topup": TOPUP_ROUTE,
+    })))
+}
+
+/// Return a debit whose generation failed. Safe to call more than once.
+pub async fn refund(pool: &PgPool, debit_id: Uuid) {
+    match db::refund_credits(pool, debit_id).await {
+        Ok(true) => tracing::info!("Refunded credit debit {}", debit_id),
+        Ok(false) => {}
+        Err(e) => tracing::error!("Failed to refund credit debit {}: {}", debit_id, e),
+    }
+}
+
+/// Attach the remaining balance to a response paid with credits.
+pub fn with_balance(mut builder: HttpResponseBuilder, debit: Option<&Debit>) -> HttpResponseBuilder {
+    if let Some(debit) = debit {
+        builder.insert_header((BALANCE_HEADER, debit.balance.as_str()));
+        builder.insert_header((ASSET_HEADER, debit.asset_id.as_str()));
+    }
+    builder
+}
+
+/// POST /credits/topup — buy credits with one x402 payment. Answers with the payer's balances
+/// and, unless the request already carries a token of the same payer, a new bearer token.
+pub async fn topup(req: HttpRequest, state: web::Data<AppState>, body: web::Bytes) -> HttpResponse {
+    let request: TopupRequest = if body.is_empty() {
+        match web::Query::<TopupRequest>::from_query(req.query_string()) {
+            Ok(q) => q.into_inner(),
+            Err(e) => return error(StatusCode::BAD_REQUEST, &format!("Invalid query: {}", e)),
+        }
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(r) => r,
+            Err(e) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Invalid JSON body: {}. Expected: {{\"amount\": \"100\", \"asset\": \"...\"}}", e),
+                );
+            }
+        }
+    };
+    if state.config.test_mode {
+        return error(StatusCode::BAD_REQUEST, "Credits are unavailable in TEST_MODE");
+    }
+
+    let asset = match &request.asset {
+        Some(id) => match state.config.find_asset(id) {
+            Some(asset) => asset,
+            None => return error(StatusCode::BAD_REQUEST, &format!("Unknown asset '{}'", id)),
+        },
+        None => state.config.primary_asset(),
+    };
+    let human_amount = match &request.amount {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => return error(StatusCode::BAD_REQUEST, "'amount' must be a decimal string or number"),
+    };
+    let amount = match DomainU256::from_human_amount(&human_amount, asset.decimals) {
+        Ok(amount) if !amount.0.is_zero() => amount,
+        Ok(_) => return error(StatusCode::BAD_REQUEST, "'amount' must be positive"),
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("Invalid amount: {}", e)),
+    };
+    if let Some(min) = &asset.min_topup
+        && let Ok(min_amount) = DomainU256::from_human_amount(min, asset.decimals)
+        && amount.0 < min_amount.0
+    {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!("Top-ups in {} start at {} {}", asset.id, min, asset.symbol),
+        );
+    }
+
+    let presented = match authenticate(&state.db_pool, req.headers()).await {
+        Ok(payer) => payer,
+        Err(resp) => return resp,
+    };
+
+    let quotes = [Quote {
+        asset,
+        human: amount.to_human_amount(asset.decimals),
+        amount,
+        cache_hit: None,
+    }];
+    let description = format!("Prepaid credits: {} {}", quotes[0].human, asset.symbol);
+    let payment = match x402::verify_x402_payment(
+        &state.config,
+        &state.http_client,
+        &state.db_pool,
+        req.headers(),
+        &quotes,
+        TOPUP_ROUTE,
+        &description,
+        TOPUP_PAYMENT_TIMEOUT,
+    )
+    .await
+    {
+        Ok(Some(payment)) => payment,
+        Ok(None) => return error(StatusCode::BAD_REQUEST, "Credits are unavailable in TEST_MODE"),
+        Err(resp) => return resp,
+    };
+    let Some(payer) = payment.payer.as_deref().map(str::to_lowercase) else {
+        x402::release_payment(&state.db_pool, &payment).await;
+        return error(StatusCode::BAD_GATEWAY, "Facilitator did not report the payer");
+    };
+
+    let settlement =
+        match x402::settle_x402_payment(&state.config, &state.http_client, &state.db_pool, &payment).await {
+            Ok(settlement) => settlement,
+            Err(e) => return e.to_response(),
+        };
+
+    let amount_raw = amount.to_string();
+    if let Err(e) = db::credit_topup(&state.db_pool, &payer, &asset.id, &amount_raw, settlement.payment_id).await {
+        tracing::error!(
+            "[{}] Settled {} but failed to credit {} {} to {}: {}",
+            TOPUP_ROUTE,
+            settlement.transaction.as_deref().unwrap_or("?"),
+            quotes[0].human,
+            asset.symbol,
+            payer,
+            e
+        );
+        return with_receipt(HttpResponse::InternalServerError(), Some(&settlement))
+            .body("Payment settled but the credits could not be recorded; contact the operator with the receipt");
+    }
+    tracing::info!("[{}] {} topped up {} {}", TOPUP_ROUTE, payer, quotes[0].human, asset.symbol);
+
+    // A token the request already carries for this payer stays in use; otherwise issue one
+    let token = if presented.as_deref() == Some(payer.as_str()) {
+        None
+    } else {
+        let token = new_token();
+        if let Err(e) = db::insert_credit_token(&state.db_pool, &hash_token(&token), &payer).await {
+            tracing::error!("[{}] Failed to issue credit token for {}: {}", TOPUP_ROUTE, payer, e);
+            return with_receipt(HttpResponse::InternalServerError(), Some(&settlement))
+                .body("Credits were added but no token could be issued; contact the operator with the receipt");
+        }
+        Some(token)
+    };
+
+    let balances = match balances(&state, &payer).await {
+        Ok(balances) => balances,
+        Err(resp) => return resp,
+    };
+    let mut body = serde_json::json!({
+        "payer": payer,
+        "asset": asset.id,
+        "credited": quotes[0].human,
+        "balances": balances,
+    });
+    if let Some(token) = token {
+        body["token"] = serde_json::Value::String(token);
+    }
+    with_receipt(HttpResponse::Ok(), Some(&settlement)).json(body)
+}
+
+/// GET /credits — the balances of the bearer token's payer.
+pub async fn get_credits(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let payer = match authenticate(&state.db_pool, req.headers()).await {
+        Ok(Some(payer)) => payer,
+        Ok(None) => return unauthorized(),
+        Err(resp) => return resp,
+    };
+    match balances(&state, &payer).await {
+        Ok(balances) => HttpResponse::Ok().json(serde_json::json!({ "payer": payer, "balances": balances })),
+        Err(resp) => resp,
+    }
+}
+
+/// DELETE /credits/token — revoke the bearer token. The balance stays with the payer.
+pub async fn revoke_token(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let Some(token) = bearer(req.headers()) else {
+        return unauthorized();
+    };
+    match db::revoke_credit_token(&state.db_pool, &hash_token(token)).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => unauthorized(),
+        Err(e) => unavailable(e),
+    }
+}
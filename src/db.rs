@@ -10,6 +10,7 @@ pub struct MediaRecord {
     pub endpoint_path: String,
     pub prompt: String,
     pub prompt_hash: String,
+    pub cache_key: Option<String>,
     pub s3_key: String,
     pub s3_url: String,
     pub media_type: String,
@@ -18,6 +19,13 @@ pub struct MediaRecord {
     pub payment_tx: Option<String>,
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
+    pub generation_id: Uuid,
+    pub output_index: i32,
+    pub model: Option<String>,
+    /// The settlement (in `payments`) that paid for this row.
+    pub payment_id: Option<Uuid>,
+    /// Its payment failed to settle; never served from the cache.
+    pub unsettled: bool,
 }
 
 pub async fn create_pool(database_url: &str) -> PgPool {
@@ -28,17 +36,24 @@ pub async fn create_pool(database_url: &str) -> PgPool {
         .expect("Failed to connect to database")
 }
 
-pub async fn find_by_prompt_hash(
+/// Outputs of the latest unexpired generation for a cache key, in output order. Empty on a miss.
+pub async fn find_by_cache_key(
     pool: &PgPool,
-    prompt_hash: &str,
+    cache_key: &str,
     endpoint_path: &str,
-) -> Result<Option<MediaRecord>, sqlx::Error> {
+) -> Result<Vec<MediaRecord>, sqlx::Error> {
     sqlx::query_as::<_, MediaRecord>(
-        "SELECT * FROM generated_media WHERE prompt_hash = $1 AND endpoint_path = $2 AND expires_at > NOW() LIMIT 1",
+        "SELECT * FROM generated_media
+         WHERE expires_at > NOW() AND generation_id = (
+             SELECT generation_id FROM generated_media
+             WHERE cache_key = $1 AND endpoint_path = $2 AND expires_at > NOW() AND NOT unsettled
+             ORDER BY created_at DESC LIMIT 1
+         )
+         ORDER BY output_index",
     )
-    .bind(prompt_hash)
+    .bind(cache_key)
     .bind(endpoint_path)
-    .fetch_optional(pool)
+    .fetch_all(pool)
     .await
 }
 
@@ -48,38 +63,84 @@ pub async fn insert_media(
     endpoint_path: &str,
     prompt: &str,
     prompt_hash: &str,
+    cache_key: &str,
     s3_key: &str,
     s3_url: &str,
     media_type: &str,
     file_size_bytes: i64,
     payer_address: Option<&str>,
     payment_tx: Option<&str>,
+    generation_id: Uuid,
+    output_index: i32,
+    model: &str,
+    payment_id: Option<Uuid>,
+    unsettled: bool,
 ) -> Result<Uuid, sqlx::Error> {
     let rec = sqlx::query_scalar::<_, Uuid>(
-        "INSERT INTO generated_media (endpoint_path, prompt, prompt_hash, s3_key, s3_url, media_type, file_size_bytes, payer_address, payment_tx)
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "INSERT INTO generated_media (endpoint_path, prompt, prompt_hash, cache_key, s3_key, s3_url, media_type, file_size_bytes, payer_address, payment_tx, generation_id, output_index, model, payment_id, unsettled)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
          RETURNING id",
     )
     .bind(endpoint_path)
     .bind(prompt)
     .bind(prompt_hash)
+    .bind(cache_key)
     .bind(s3_key)
     .bind(s3_url)
     .bind(media_type)
     .bind(file_size_bytes)
     .bind(payer_address)
     .bind(payment_tx)
+    .bind(generation_id)
+    .bind(output_index)
+    .bind(model)
+    .bind(payment_id)
+    .bind(unsettled)
     .fetch_one(pool)
     .await?;
     Ok(rec)
 }
 
+/// Unexpired outputs paid for by a settlement, in output order.
+pub async fn find_media_by_payment(pool: &PgPool, payment_id: Uuid) -> Result<Vec<MediaRecord>, sqlx::Error> {
+    sqlx::query_as::<_, MediaRecord>(
+        "SELECT * FROM generated_media WHERE payment_id = $1 AND expires_at > NOW() ORDER BY output_index",
+    )
+    .bind(payment_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// (id, endpoint_path, prompt) of media recorded before cache keys existed.
+pub async fn find_media_without_cache_key(pool: &PgPool) -> Result<Vec<(Uuid, String, String)>, sqlx::Error> {
+    sqlx::query_as("SELECT id, endpoint_path, prompt FROM generated_media WHERE cache_key IS NULL")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn set_media_cache_key(pool: &PgPool, id: Uuid, cache_key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE generated_media SET cache_key = $2 WHERE id = $1")
+        .bind(id)
+        .bind(cache_key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 pub async fn find_expired(pool: &PgPool) -> Result<Vec<MediaRecord>, sqlx::Error> {
     sqlx::query_as::<_, MediaRecord>("SELECT * FROM generated_media WHERE expires_at <= NOW()")
         .fetch_all(pool)
         .await
 }
 
+/// Whether an unexpired media row points at `s3_key`.
+pub async fn media_key_in_use(pool: &PgPool, s3_key: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM generated_media WHERE s3_key = $1 AND expires_at > NOW())")
+        .bind(s3_key)
+        .fetch_one(pool)
+        .await
+}
+
 pub async fn delete_by_id(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
     sqlx::query("DELETE FROM generated_media WHERE id = $1")
         .bind(id)
@@ -87,3 +148,749 @@ pub async fn delete_by_id(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
         .await?;
     Ok(())
 }
+
+/// Record a staged input image, or push back the expiry of one staged before.
+pub async fn record_staged_input(pool: &PgPool, s3_key: &str, ttl: std::time::Duration) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO staged_inputs (s3_key, expires_at) VALUES ($1, NOW() + make_interval(secs => $2))
+         ON CONFLICT (s3_key) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+    )
+    .bind(s3_key)
+    .bind(ttl.as_secs_f64())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn find_expired_staged_inputs(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT s3_key FROM staged_inputs WHERE expires_at <= NOW()")
+        .fetch_all(pool)
+        .await
+}
+
+/// Delete a staged input's row, unless it was staged again since it expired.
+pub async fn delete_staged_input(pool: &PgPool, s3_key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM staged_inputs WHERE s3_key = $1 AND expires_at <= NOW()")
+        .bind(s3_key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+#[allow(dead_code)]
+pub struct JobRecord {
+    pub id: Uuid,
+    pub endpoint_path: String,
+    pub quality: String,
+    pub prompt: String,
+    pub prompt_hash: String,
+    pub status: String,
+    pub provider_request: Option<serde_json::Value>,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub payer_address: Option<String>,
+    pub payment_tx: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub payment_request: Option<serde_json::Value>,
+    pub payment_response: Option<serde_json::Value>,
+    pub cache_key: Option<String>,
+    pub request_body: Option<serde_json::Value>,
+    pub callback_url: Option<String>,
+    pub model: Option<String>,
+    pub credit_debit_id: Option<Uuid>,
+    /// `payload_hash` of the payment held verified until the job settles.
+    pub payload_hash: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_job(
+    pool: &PgPool,
+    endpoint_path: &str,
+    quality: &str,
+    prompt: &str,
+    prompt_hash: &str,
+    cache_key: &str,
+    request_body: &serde_json::Value,
+    status: &str,
+    result: Option<&serde_json::Value>,
+    payer_address: Option<&str>,
+    payment_tx: Option<&str>,
+    payment_request: Option<&serde_json::Value>,
+    callback_url: Option<&str>,
+    credit_debit_id: Option<Uuid>,
+    payload_hash: Option<&str>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO generation_jobs (endpoint_path, quality, prompt, prompt_hash, cache_key, request_body, status, result, payer_address, payment_tx, payment_request, callback_url, credit_debit_id, payload_hash)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+         RETURNING id",
+    )
+    .bind(endpoint_path)
+    .bind(quality)
+    .bind(prompt)
+    .bind(prompt_hash)
+    .bind(cache_key)
+    .bind(request_body)
+    .bind(status)
+    .bind(result)
+    .bind(payer_address)
+    .bind(payment_tx)
+    .bind(payment_request)
+    .bind(callback_url)
+    .bind(credit_debit_id)
+    .bind(payload_hash)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn find_job(pool: &PgPool, id: Uuid) -> Result<Option<JobRecord>, sqlx::Error> {
+    sqlx::query_as::<_, JobRecord>("SELECT * FROM generation_jobs WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn find_unfinished_jobs(pool: &PgPool) -> Result<Vec<JobRecord>, sqlx::Error> {
+    sqlx::query_as::<_, JobRecord>(
+        "SELECT * FROM generation_jobs WHERE status IN ('pending', 'running') ORDER BY created_at",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn set_job_payload_hash(pool: &PgPool, id: Uuid, payload_hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE generation_jobs SET payload_hash = $2 WHERE id = $1")
+        .bind(id)
+        .bind(payload_hash)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Record the provider request a job is waiting on and the model it was submitted to.
+pub async fn mark_job_running(
+    pool: &PgPool,
+    id: Uuid,
+    provider_request: &serde_json::Value,
+    model: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE generation_jobs SET status = 'running', provider_request = $2, model = $3, updated_at = NOW() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(provider_request)
+    .bind(model)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn mark_job_succeeded(
+    pool: &PgPool,
+    id: Uuid,
+    result: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE generation_jobs SET status = 'succeeded', result = $2, updated_at = NOW() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(result)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn mark_job_failed(pool: &PgPool, id: Uuid, error: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE generation_jobs SET status = 'failed', error = $2, updated_at = NOW() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_job_payment(
+    pool: &PgPool,
+    id: Uuid,
+    payer_address: Option<&str>,
+    payment_tx: Option<&str>,
+    payment_response: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE generation_jobs SET payer_address = COALESCE($2, payer_address), payment_tx = $3, payment_response = $4, updated_at = NOW() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(payer_address)
+    .bind(payment_tx)
+    .bind(payment_response)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_generation_failure(
+    pool: &PgPool,
+    endpoint_path: &str,
+    prompt: &str,
+    job_id: Option<Uuid>,
+    payer_address: Option<&str>,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO generation_failures (endpoint_path, prompt, job_id, payer_address, error)
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(endpoint_path)
+    .bind(prompt)
+    .bind(job_id)
+    .bind(payer_address)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+#[allow(dead_code)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub job_id: Uuid,
+    pub url: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Insert a delivery already claimed for `lease`, so the worker leaves it to the caller's first attempt.
+pub async fn insert_webhook_delivery(
+    pool: &PgPool,
+    job_id: Uuid,
+    url: &str,
+    payload: &serde_json::Value,
+    lease: std::time::Duration,
+) -> Result<WebhookDelivery, sqlx::Error> {
+    sqlx::query_as::<_, WebhookDelivery>(
+        "INSERT INTO webhook_deliveries (job_id, url, payload, next_attempt_at)
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))
+         RETURNING *",
+    )
+    .bind(job_id)
+    .bind(url)
+    .bind(payload)
+    .bind(lease.as_secs_f64())
+    .fetch_one(pool)
+    .await
+}
+
+/// Claim pending deliveries that are due, pushing their next attempt out by `lease`
+/// so concurrent workers (or a crash mid-attempt) don't double deliver.
+pub async fn claim_due_webhooks(
+    pool: &PgPool,
+    limit: i64,
+    lease: std::time::Duration,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    sqlx::query_as::<_, WebhookDelivery>(
+        "UPDATE webhook_deliveries SET next_attempt_at = NOW() + make_interval(secs => $2), updated_at = NOW()
+         WHERE id IN (
+             SELECT id FROM webhook_deliveries
+             WHERE status = 'pending' AND next_attempt_at <= NOW()
+             ORDER BY next_attempt_at
+             LIMIT $1
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING *",
+    )
+    .bind(limit)
+    .bind(lease.as_secs_f64())
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_webhook_attempt(
+    pool: &PgPool,
+    delivery_id: Uuid,
+    attempt: i32,
+    status_code: Option<i32>,
+    error: Option<&str>,
+    duration_ms: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO webhook_attempts (delivery_id, attempt, status_code, error, duration_ms)
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(delivery_id)
+    .bind(attempt)
+    .bind(status_code)
+    .bind(error)
+    .bind(duration_ms)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Record the outcome of an attempt. `retry_in` is None once delivered or given up (`status`).
+pub async fn update_webhook_delivery(
+    pool: &PgPool,
+    id: Uuid,
+    status: &str,
+    attempts: i32,
+    status_code: Option<i32>,
+    error: Option<&str>,
+    retry_in: Option<std::time::Duration>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE webhook_deliveries
+         SET status = $2, attempts = $3, last_status_code = $4, last_error = $5,
+             next_attempt_at = NOW() + make_interval(secs => $6),
+             delivered_at = CASE WHEN $2 = 'delivered' THEN NOW() ELSE delivered_at END,
+             updated_at = NOW()
+         WHERE id = $1",
+    )
+    .bind(id)
+    .bind(status)
+    .bind(attempts)
+    .bind(status_code)
+    .bind(error)
+    .bind(retry_in.unwrap_or_default().as_secs_f64())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// A facilitator call for the payments ledger.
+pub struct NewPayment<'a> {
+    /// "verify" or "settle".
+    pub operation: &'a str,
+    pub outcome: &'a str,
+    pub requirements: &'a crate::x402::PaymentRequirements,
+    pub payload_hash: &'a str,
+    pub payer_address: Option<&'a str>,
+    pub tx_hash: Option<&'a str>,
+    pub error: Option<&'a str>,
+    pub verification_id: Option<Uuid>,
+    pub latency: std::time::Duration,
+}
+
+pub async fn insert_payment(pool: &PgPool, payment: &NewPayment<'_>) -> Result<Uuid, sqlx::Error> {
+    let requirements = payment.requirements;
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO payments (operation, outcome, resource, scheme, network, asset, amount, pay_to, payer_address,
+                               payload_hash, requirements, tx_hash, error, verification_id, latency_ms)
+         VALUES ($1, $2, $3, $4, $5, $6, $7::NUMERIC, $8, $9, $10, $11, $12, $13, $14, $15)
+         RETURNING id",
+    )
+    .bind(payment.operation)
+    .bind(payment.outcome)
+    .bind(&requirements.resource)
+    .bind(&requirements.scheme)
+    .bind(&requirements.network)
+    .bind(&requirements.asset)
+    .bind(&requirements.max_amount_required)
+    .bind(&requirements.pay_to)
+    .bind(payment.payer_address)
+    .bind(payment.payload_hash)
+    .bind(serde_json::to_value(requirements).unwrap_or_default())
+    .bind(payment.tx_hash)
+    .bind(payment.error)
+    .bind(payment.verification_id)
+    .bind(payment.latency.as_millis() as i64)
+    .fetch_one(pool)
+    .await
+}
+
+/// (id, network, payer_address, tx_hash) of a payload's successful settle call, if the ledger has one.
+pub async fn find_settlement(
+    pool: &PgPool,
+    payload_hash: &str,
+) -> Result<Option<(Uuid, String, Option<String>, Option<String>)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, network, payer_address, tx_hash FROM payments
+         WHERE payload_hash = $1 AND operation = 'settle' AND outcome = 'settled'
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(payload_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+#[allow(dead_code)]
+pub struct PaymentPayload {
+    pub payload_hash: String,
+    pub state: String,
+    pub resource: String,
+    pub tx_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Claim a payload for verification. Payloads seen before can only be claimed again once they
+/// failed (were never settled), or were left `verifying` or `verified` for `stale_after` by a
+/// request that died. A payload an unfinished async job is waiting to settle is never stale.
+/// Returns None when claimed, or the existing record that blocks it.
+pub async fn claim_payment_payload(
+    pool: &PgPool,
+    payload_hash: &str,
+    resource: &str,
+    stale_after: std::time::Duration,
+) -> Result<Option<PaymentPayload>, sqlx::Error> {
+    let claimed = sqlx::query_scalar::<_, String>(
+        "INSERT INTO payment_payloads (payload_hash, resource, state) VALUES ($1, $2, 'verifying')
+         ON CONFLICT (payload_hash) DO UPDATE
+         SET state = 'verifying', resource = EXCLUDED.resource, tx_hash = NULL, updated_at = NOW()
+         WHERE payment_payloads.state = 'failed'
+            OR (payment_payloads.state IN ('verifying', 'verified')
+                AND payment_payloads.updated_at < NOW() - make_interval(secs => $3)
+                AND NOT EXISTS (
+                    SELECT 1 FROM generation_jobs
+                    WHERE generation_jobs.payload_hash = payment_payloads.payload_hash
+                      AND generation_jobs.status IN ('pending', 'running')
+                ))
+         RETURNING payload_hash",
+    )
+    .bind(payload_hash)
+    .bind(resource)
+    .bind(stale_after.as_secs_f64())
+    .fetch_optional(pool)
+    .await?;
+    if claimed.is_some() {
+        return Ok(None);
+    }
+    find_payment_payload(pool, payload_hash).await
+}
+
+/// Move a payload to `state` if it is currently in one of `from`.
+pub async fn update_payment_payload(
+    pool: &PgPool,
+    payload_hash: &str,
+    from: &[&str],
+    state: &str,
+    tx_hash: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let from: Vec<String> = from.iter().map(|s| s.to_string()).collect();
+    let result = sqlx::query(
+        "UPDATE payment_payloads SET state = $3, tx_hash = COALESCE($4, tx_hash), updated_at = NOW()
+         WHERE payload_hash = $1 AND state = ANY($2)",
+    )
+    .bind(payload_hash)
+    .bind(&from)
+    .bind(state)
+    .bind(tx_hash)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn find_payment_payload(pool: &PgPool, payload_hash: &str) -> Result<Option<PaymentPayload>, sqlx::Error> {
+    sqlx::query_as::<_, PaymentPayload>("SELECT * FROM payment_payloads WHERE payload_hash = $1")
+        .bind(payload_hash)
+        .fetch_optional(pool)
+        .await
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+#[allow(dead_code)]
+pub struct IdempotencyRecord {
+    pub id: Uuid,
+    pub idempotency_key: String,
+    pub payload_hash: String,
+    pub payer_address: Option<String>,
+    pub endpoint_path: String,
+    pub request_hash: String,
+    pub state: String,
+    pub status_code: Option<i32>,
+    pub content_type: Option<String>,
+    pub response_body: Option<Vec<u8>>,
+    pub payment_response: Option<String>,
+    /// X-Credit-Balance and X-Credit-Asset of a request paid with credits.
+    pub credit_balance: Option<String>,
+    pub credit_asset: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Claim a key for a payload, first dropping expired records of the key and in-flight ones
+/// older than `stale_after` (their request died). Returns None if (key, payload) is taken.
+pub async fn insert_idempotency_key(
+    pool: &PgPool,
+    key: &str,
+    payload_hash: &str,
+    endpoint_path: &str,
+    request_hash: &str,
+    ttl: std::time::Duration,
+    stale_after: std::time::Duration,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query(
+        "DELETE FROM idempotency_keys
+         WHERE idempotency_key = $1
+           AND (expires_at < NOW()
+                OR (state = 'in_flight' AND created_at < NOW() - make_interval(secs => $2)))",
+    )
+    .bind(key)
+    .bind(stale_after.as_secs_f64())
+    .execute(pool)
+    .await?;
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO idempotency_keys (idempotency_key, payload_hash, endpoint_path, request_hash, expires_at)
+         VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+         ON CONFLICT (idempotency_key, payload_hash) DO NOTHING
+         RETURNING id",
+    )
+    .bind(key)
+    .bind(payload_hash)
+    .bind(endpoint_path)
+    .bind(request_hash)
+    .bind(ttl.as_secs_f64())
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn find_idempotency_key(
+    pool: &PgPool,
+    key: &str,
+    payload_hash: &str,
+) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+    sqlx::query_as::<_, IdempotencyRecord>(
+        "SELECT * FROM idempotency_keys WHERE idempotency_key = $1 AND payload_hash = $2",
+    )
+    .bind(key)
+    .bind(payload_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn find_idempotency_key_by_id(pool: &PgPool, id: Uuid) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+    sqlx::query_as::<_, IdempotencyRecord>("SELECT * FROM idempotency_keys WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The other record holding `key` for `payer`, if any.
+pub async fn find_idempotency_key_for_payer(
+    pool: &PgPool,
+    key: &str,
+    payer_address: &str,
+    except: Uuid,
+) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+    sqlx::query_as::<_, IdempotencyRecord>(
+        "SELECT * FROM idempotency_keys
+         WHERE idempotency_key = $1 AND lower(payer_address) = lower($2) AND id <> $3",
+    )
+    .bind(key)
+    .bind(payer_address)
+    .bind(except)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Attach the verified payer. Fails with a unique violation if the payer already used the key.
+pub async fn set_idempotency_payer(pool: &PgPool, id: Uuid, payer_address: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE idempotency_keys SET payer_address = $2 WHERE id = $1")
+        .bind(id)
+        .bind(payer_address)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_idempotency_key(
+    pool: &PgPool,
+    id: Uuid,
+    status_code: i32,
+    content_type: Option<&str>,
+    response_body: &[u8],
+    payment_response: Option<&str>,
+    credit_balance: Option<&str>,
+    credit_asset: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE idempotency_keys
+         SET state = 'completed', status_code = $2, content_type = $3, response_body = $4,
+             payment_response = $5, credit_balance = $6, credit_asset = $7, completed_at = NOW()
+         WHERE id = $1",
+    )
+    .bind(id)
+    .bind(status_code)
+    .bind(content_type)
+    .bind(response_body)
+    .bind(payment_response)
+    .bind(credit_balance)
+    .bind(credit_asset)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_idempotency_key(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_expired_idempotency_keys(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM idempotency_keys WHERE expires_at < NOW()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct CreditBalance {
+    pub asset_id: String,
+    /// Raw token units as a decimal string.
+    pub balance: String,
+}
+
+/// Add a settled top-up to a payer's balance. Returns the new balance (raw units).
+pub async fn credit_topup(
+    pool: &PgPool,
+    payer_address: &str,
+    asset_id: &str,
+    amount: &str,
+    payment_id: Option<Uuid>,
+) -> Result<String, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let balance = sqlx::query_scalar::<_, String>(
+        "INSERT INTO credit_balances (payer_address, asset_id, balance) VALUES ($1, $2, $3::NUMERIC)
+         ON CONFLICT (payer_address, asset_id) DO UPDATE
+         SET balance = credit_balances.balance + EXCLUDED.balance, updated_at = NOW()
+         RETURNING balance::TEXT",
+    )
+    .bind(payer_address)
+    .bind(asset_id)
+    .bind(amount)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO credit_transactions (payer_address, asset_id, kind, amount, resource, payment_id)
+         VALUES ($1, $2, 'topup', $3::NUMERIC, '/credits/topup', $4)",
+    )
+    .bind(payer_address)
+    .bind(asset_id)
+    .bind(amount)
+    .bind(payment_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(balance)
+}
+
+/// Take `amount` from a payer's balance if it covers it. Returns the debit's id and the
+/// remaining balance, or None when the balance is short.
+pub async fn debit_credits(
+    pool: &PgPool,
+    payer_address: &str,
+    asset_id: &str,
+    amount: &str,
+    resource: &str,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let Some(balance) = sqlx::query_scalar::<_, String>(
+        "UPDATE credit_balances SET balance = balance - $3::NUMERIC, updated_at = NOW()
+         WHERE payer_address = $1 AND asset_id = $2 AND balance >= $3::NUMERIC
+         RETURNING balance::TEXT",
+    )
+    .bind(payer_address)
+    .bind(asset_id)
+    .bind(amount)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let id = sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO credit_transactions (payer_address, asset_id, kind, amount, resource)
+         VALUES ($1, $2, 'debit', $3::NUMERIC, $4)
+         RETURNING id",
+    )
+    .bind(payer_address)
+    .bind(asset_id)
+    .bind(amount)
+    .bind(resource)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some((id, balance)))
+}
+
+/// Return a debit to its payer's balance. A debit is only ever refunded once.
+pub async fn refund_credits(pool: &PgPool, debit_id: Uuid) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let refund = sqlx::query_as::<_, (String, String, String)>(
+        "INSERT INTO credit_transactions (payer_address, asset_id, kind, amount, resource, refund_of)
+         SELECT payer_address, asset_id, 'refund', amount, resource, id
+         FROM credit_transactions WHERE id = $1 AND kind = 'debit'
+         ON CONFLICT (refund_of) DO NOTHING
+         RETURNING payer_address, asset_id, amount::TEXT",
+    )
+    .bind(debit_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((payer_address, asset_id, amount)) = refund else {
+        return Ok(false);
+    };
+    sqlx::query(
+        "UPDATE credit_balances SET balance = balance + $3::NUMERIC, updated_at = NOW()
+         WHERE payer_address = $1 AND asset_id = $2",
+    )
+    .bind(&payer_address)
+    .bind(&asset_id)
+    .bind(&amount)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn find_credit_balances(pool: &PgPool, payer_address: &str) -> Result<Vec<CreditBalance>, sqlx::Error> {
+    sqlx::query_as::<_, CreditBalance>(
+        "SELECT asset_id, balance::TEXT AS balance FROM credit_balances WHERE payer_address = $1 ORDER BY asset_id",
+    )
+    .bind(payer_address)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_credit_token(pool: &PgPool, token_hash: &str, payer_address: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO credit_tokens (token_hash, payer_address) VALUES ($1, $2)")
+        .bind(token_hash)
+        .bind(payer_address)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The payer of a live (unrevoked) token, noting its use.
+pub async fn use_credit_token(pool: &PgPool, token_hash: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "UPDATE credit_tokens SET last_used_at = NOW()
+         WHERE token_hash = $1 AND revoked_at IS NULL
+         RETURNING payer_address",
+    )
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn revoke_credit_token(pool: &PgPool, token_hash: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE credit_tokens SET revoked_at = NOW() WHERE token_hash = $1 AND revoked_at IS NULL")
+        .bind(token_hash)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
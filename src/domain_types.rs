@@ -56,6 +56,20 @@ impl DomainU256 {
     }
 }
 
+impl DomainU256 {
+    /// Inverse of `from_human_amount`: raw units as a decimal string without trailing zeros.
+    pub fn to_human_amount(self, decimals: u8) -> String {
+        let unit = U256::exp10(decimals as usize);
+        let integer = self.0 / unit;
+        let frac = self.0 % unit;
+        if frac.is_zero() {
+            return integer.to_string();
+        }
+        let frac = format!("{:0>width$}", frac.to_string(), width = decimals as usize);
+        format!("{}.{}", integer, frac.trim_end_matches('0'))
+    }
+}
+
 impl fmt::Display for DomainU256 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
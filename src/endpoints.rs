@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -11,15 +11,214 @@ pub struct EndpointDef {
     pub route: String,
     pub quality: String,
     pub path: String,
-    pub fal_model: String,
+    /// Name of the generation provider in the `ProviderRegistry` (defaults to "fal").
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    /// Provider-specific model identifier, e.g. "fal-ai/flux/schnell".
+    #[serde(alias = "fal_model")]
+    pub model: String,
+    /// Overrides the provider's base URL for this endpoint (its queue URL for `Async` endpoints),
+    /// e.g. a staging proxy or regional gateway.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Price in the primary payment asset (human-readable amount).
     pub cost: String,
+    /// Prices in other payment assets, keyed by asset id (e.g. "usdc-base": "0.05").
+    #[serde(default)]
+    pub prices: HashMap<String, String>,
+    /// Price of a cache hit in the primary asset. Unset, hits cost `cost`. Hits are served free
+    /// when this and every `cached_prices` entry (one per priced asset) is "0".
+    #[serde(default)]
+    pub cached_cost: Option<String>,
+    /// Cache hit prices in other assets, keyed like `prices`. Assets without one charge full price.
+    #[serde(default)]
+    pub cached_prices: HashMap<String, String>,
+    /// Scales `cost`/`prices` by request params, e.g. price per second of video.
+    #[serde(default)]
+    pub pricing: Option<PricingRule>,
     pub description: String,
     pub response_url_path: String,
     pub request_params: HashMap<String, serde_json::Value>,
+    /// Models tried in order when the primary one fails or times out.
+    #[serde(default)]
+    pub fallbacks: Vec<FallbackModel>,
+    /// Time limit per model attempt. Unset, sync requests wait as long as the provider takes
+    /// and async jobs up to what is left of `job_time_limit`.
+    #[serde(default)]
+    pub provider_timeout_seconds: Option<u64>,
+    /// Set for image-to-image/video routes: the caller's input image is staged to storage
+    /// and its URL passed to the provider.
+    #[serde(default)]
+    pub input_image: Option<InputImageSpec>,
+    /// Provider params callers may set per request, merged over `request_params`.
+    #[serde(default)]
+    pub allowed_params: HashMap<String, ParamSpec>,
     pub default_prompt: String,
     pub media_type: String,
     pub output_extension: String,
     pub post_process: PostProcess,
+    /// `Sync` holds the request open until the media is ready; `Async` returns a job id immediately.
+    #[serde(default)]
+    pub mode: GenerationMode,
+}
+
+impl EndpointDef {
+    /// Whether cache hits are priced differently from fresh generations.
+    pub fn discounts_cache_hits(&self) -> bool {
+        self.cached_cost.is_some() || !self.cached_prices.is_empty()
+    }
+
+    /// How long a payment authorization must stay valid: it is settled only after generation,
+    /// which for async jobs can take many minutes.
+    pub fn payment_timeout_seconds(&self) -> u64 {
+        match self.mode {
+            GenerationMode::Sync => 300,
+            GenerationMode::Async => 3600,
+        }
+    }
+
+    /// How long an async job may run in total, across every model it tries: what is left of its
+    /// payment authorization after keeping 5 minutes to upload the outputs and settle.
+    pub fn job_time_limit(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.payment_timeout_seconds().saturating_sub(300))
+    }
+
+    /// The primary model followed by the fallbacks, in the order they are tried.
+    pub fn targets(&self) -> Vec<ModelTarget<'_>> {
+        let primary = ModelTarget {
+            provider: &self.provider,
+            model: &self.model,
+            base_url: self.base_url.as_deref(),
+            request_params: &self.request_params,
+            response_url_path: &self.response_url_path,
+        };
+        std::iter::once(primary)
+            .chain(self.fallbacks.iter().map(|f| ModelTarget {
+                provider: f.provider.as_deref().unwrap_or(&self.provider),
+                model: &f.model,
+                base_url: f.base_url.as_deref(),
+                request_params: &f.request_params,
+                response_url_path: &f.response_url_path,
+            }))
+            .collect()
+    }
+
+    pub fn provider_timeout(&self) -> Option<std::time::Duration> {
+        self.provider_timeout_seconds.map(std::time::Duration::from_secs)
+    }
+}
+
+/// A model to fall back to. Callers' params are passed to it as to the primary model,
+/// merged over its own `request_params`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FallbackModel {
+    /// Defaults to the endpoint's provider.
+    #[serde(default)]
+    pub provider: Option<String>,
+    pub model: String,
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(default)]
+    pub request_params: HashMap<String, serde_json::Value>,
+    pub response_url_path: String,
+}
+
+/// One model an endpoint can generate with: its primary model or a fallback.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelTarget<'a> {
+    pub provider: &'a str,
+    pub model: &'a str,
+    pub base_url: Option<&'a str>,
+    pub request_params: &'a HashMap<String, serde_json::Value>,
+    pub response_url_path: &'a str,
+}
+
+fn default_provider() -> String {
+    "fal".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum GenerationMode {
+    #[default]
+    Sync,
+    Async,
+}
+
+/// How an endpoint takes an input image (multipart `image` upload or `image_url`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputImageSpec {
+    /// Provider body field that receives the staged image URL, e.g. "image_url".
+    pub param: String,
+    #[serde(default = "default_true")]
+    pub required: bool,
+    /// Largest accepted image, in bytes.
+    #[serde(default = "default_max_input_bytes")]
+    pub max_bytes: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_input_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+/// Price formula: the endpoint's unit price (`cost`/`prices`) is multiplied by
+/// `base + Σ value(param) × per_unit[param]`. Values come from the request's params,
+/// falling back to `request_params`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingRule {
+    #[serde(default = "default_pricing_base")]
+    pub base: String,
+    #[serde(default)]
+    pub per_unit: HashMap<String, String>,
+}
+
+fn default_pricing_base() -> String {
+    "0".to_string()
+}
+
+/// Schema for one caller-supplied param in `allowed_params`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParamSpec {
+    pub kind: ParamKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Allowed values; any value of `kind` when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub one_of: Vec<serde_json::Value>,
+    /// Inclusive bounds for Integer and Number params.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// Used when the caller omits the param; otherwise it is left to `request_params`/the provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+    /// Price multipliers keyed by chosen value, e.g. {"10": "2"} doubles the price for duration 10.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub price_multipliers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParamKind {
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl ParamKind {
+    /// JSON Schema type name.
+    pub fn json_type(&self) -> &'static str {
+        match self {
+            ParamKind::String => "string",
+            ParamKind::Integer => "integer",
+            ParamKind::Number => "number",
+            ParamKind::Boolean => "boolean",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -49,28 +248,51 @@ pub fn group_by_route(endpoints: &[EndpointDef]) -> HashMap<String, QualityMap>
 pub fn load_endpoints(path: &str) -> EndpointsConfig {
     let content = std::fs::read_to_string(path)
         .unwrap_or_else(|e| panic!("Failed to read endpoints config '{}': {}", path, e));
-    ron::from_str(&content)
+    // implicit_some lets optional fields be written as `min: 0` rather than `min: Some(0)`
+    ron::Options::default()
+        .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+        .from_str(&content)
         .unwrap_or_else(|e| panic!("Failed to parse endpoints config '{}': {}", path, e))
 }
 
-/// Extract a value from nested JSON using a dot-separated path.
-/// Numeric segments index into arrays, string segments index into objects.
-/// e.g. "images.0.url" or "video.url"
-pub fn extract_url(json: &serde_json::Value, dot_path: &str) -> Result<String, String> {
-    let mut current = json;
+/// Extract values from nested JSON using a dot-separated path.
+/// Numeric segments index into arrays, string segments index into objects and `*` takes
+/// every element of an array, e.g. "images.0.url", "images.*.url" or "video.url".
+pub fn extract_urls(json: &serde_json::Value, dot_path: &str) -> Result<Vec<String>, String> {
+    let mut current = vec![json];
     for segment in dot_path.split('.') {
-        if let Ok(idx) = segment.parse::<usize>() {
-            current = current
-                .get(idx)
-                .ok_or_else(|| format!("Array index {} not found in path '{}'", idx, dot_path))?;
-        } else {
-            current = current
-                .get(segment)
-                .ok_or_else(|| format!("Key '{}' not found in path '{}'", segment, dot_path))?;
+        let mut next = Vec::new();
+        for value in current {
+            if segment == "*" {
+                let items = value
+                    .as_array()
+                    .ok_or_else(|| format!("Value at '*' in path '{}' is not an array", dot_path))?;
+                next.extend(items.iter());
+            } else if let Ok(idx) = segment.parse::<usize>() {
+                next.push(
+                    value
+                        .get(idx)
+                        .ok_or_else(|| format!("Array index {} not found in path '{}'", idx, dot_path))?,
+                );
+            } else {
+                next.push(
+                    value
+                        .get(segment)
+                        .ok_or_else(|| format!("Key '{}' not found in path '{}'", segment, dot_path))?,
+                );
+            }
         }
+        current = next;
+    }
+    if current.is_empty() {
+        return Err(format!("No values at path '{}'", dot_path));
     }
     current
-        .as_str()
-        .map(|s| s.to_string())
-        .ok_or_else(|| format!("Value at path '{}' is not a string", dot_path))
+        .into_iter()
+        .map(|v| {
+            v.as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| format!("Value at path '{}' is not a string", dot_path))
+        })
+        .collect()
 }
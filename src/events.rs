@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::db::{self, JobRecord};
+use crate::jobs::{STATUS_FAILED, STATUS_SUCCEEDED};
+
+/// Events kept per job, replayed to subscribers that connect late.
+const MAX_HISTORY: usize = 500;
+/// Comment line sent when a job is quiet, so proxies keep the stream open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How often a stream for a job this process isn't running re-reads its row.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A stage of a job's lifecycle.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEventKind {
+    PaymentVerified,
+    Queued { provider: String, model: String, request_id: String },
+    /// A model failed; the job moves on to the next one in the endpoint's fallback chain.
+    FallingBack { from: String, to: String, error: String },
+    InQueue { position: Option<u64> },
+    /// New provider log lines since the previous event.
+    InProgress { logs: Vec<String> },
+    Downloading { output: usize, outputs: usize },
+    PostProcessing { output: usize, outputs: usize },
+    Uploaded { output: usize, outputs: usize, url: String },
+    Succeeded { result: serde_json::Value },
+    Failed { error: String },
+}
+
+impl JobEventKind {
+    /// Rough completion fraction for progress bars. Providers don't report one, so this is by stage.
+    fn progress(&self) -> f32 {
+        match self {
+            Self::PaymentVerified => 0.05,
+            Self::Queued { .. } | Self::FallingBack { .. } => 0.1,
+            Self::InQueue { .. } => 0.15,
+            Self::InProgress { .. } => 0.5,
+            Self::Downloading { output, outputs } => 0.8 + 0.15 * (*output as f32 / *outputs as f32),
+            Self::PostProcessing { output, outputs } => 0.85 + 0.1 * (*output as f32 / *outputs as f32),
+            Self::Uploaded { output, outputs, .. } => 0.8 + 0.15 * ((*output + 1) as f32 / *outputs as f32),
+            Self::Succeeded { .. } | Self::Failed { .. } => 1.0,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::PaymentVerified => "payment_verified",
+            Self::Queued { .. } => "queued",
+            Self::FallingBack { .. } => "falling_back",
+            Self::InQueue { .. } => "in_queue",
+            Self::InProgress { .. } => "in_progress",
+            Self::Downloading { .. } => "downloading",
+            Self::PostProcessing { .. } => "post_processing",
+            Self::Uploaded { .. } => "uploaded",
+            Self::Succeeded { .. } => "succeeded",
+            Self::Failed { .. } => "failed",
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        matches!(self, Self::Succeeded { .. } | Self::Failed { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobEvent {
+    /// Position in the job's stream, also sent as the SSE `id`.
+    pub seq: u64,
+    #[serde(flatten)]
+    pub kind: JobEventKind,
+    pub progress: f32,
+    pub at: DateTime<Utc>,
+}
+
+impl JobEvent {
+    fn to_sse(&self) -> web::Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        web::Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", self.seq, self.kind.name(), data))
+    }
+}
+
+struct JobChannel {
+    sender: broadcast::Sender<JobEvent>,
+    history: Vec<JobEvent>,
+    next_seq: u64,
+}
+
+impl JobChannel {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self {
+            sender,
+            history: Vec::new(),
+            next_seq: 1,
+        }
+    }
+}
+
+/// In-process fan-out of job lifecycle events to SSE subscribers. Only jobs this process
+/// runs have a channel, from `open` until `close`.
+#[derive(Default)]
+pub struct JobEvents {
+    channels: Mutex<HashMap<Uuid, JobChannel>>,
+}
+
+impl JobEvents {
+    /// Start recording events for a job this process is about to run.
+    pub fn open(&self, job_id: Uuid) {
+        self.channels.lock().unwrap().entry(job_id).or_insert_with(JobChannel::new);
+    }
+
+    /// Record an event for a job and send it to current subscribers. Ignored for jobs
+    /// without an open channel.
+    pub fn emit(&self, job_id: Uuid, kind: JobEventKind) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(channel) = channels.get_mut(&job_id) else {
+            return;
+        };
+        let event = JobEvent {
+            seq: channel.next_seq,
+            progress: kind.progress(),
+            kind,
+            at: Utc::now(),
+        };
+        channel.next_seq += 1;
+        if channel.history.len() >= MAX_HISTORY {
+            channel.history.remove(0);
+        }
+        channel.history.push(event.clone());
+        // No receivers is fine: nobody is watching yet
+        let _ = channel.sender.send(event);
+    }
+
+    /// Like `emit`, for code shared with synchronous generations that have no job.
+    pub fn emit_for(&self, job_id: Option<Uuid>, kind: JobEventKind) {
+        if let Some(job_id) = job_id {
+            self.emit(job_id, kind);
+        }
+    }
+
+    /// Drop a finished job's channel; live subscribers drain what they have and end.
+    pub fn close(&self, job_id: Uuid) {
+        self.channels.lock().unwrap().remove(&job_id);
+    }
+
+    /// Past events and a receiver for new ones, atomically so nothing falls in between.
+    /// None if this process isn't running the job.
+    fn subscribe(&self, job_id: Uuid) -> Option<(Vec<JobEvent>, broadcast::Receiver<JobEvent>)> {
+        let channels = self.channels.lock().unwrap();
+        let channel = channels.get(&job_id)?;
+        Some((channel.history.clone(), channel.sender.subscribe()))
+    }
+}
+
+/// The terminal event of a job that already finished, rebuilt from its DB row.
+fn finished_event(job: &JobRecord) -> Option<JobEvent> {
+    let kind = match job.status.as_str() {
+        STATUS_SUCCEEDED => JobEventKind::Succeeded {
+            result: job.result.clone().unwrap_or_default(),
+        },
+        STATUS_FAILED => JobEventKind::Failed {
+            error: job.error.clone().unwrap_or_default(),
+        },
+        _ => return None,
+    };
+    Some(JobEvent {
+        seq: 0,
+        progress: kind.progress(),
+        kind,
+        at: job.updated_at,
+    })
+}
+
+/// Where a stream's events come from once its backlog is sent.
+enum Source {
+    /// The job runs in this process: follow its channel.
+    Live(broadcast::Receiver<JobEvent>),
+    /// The job runs elsewhere (or hasn't been resumed yet): poll its row until it finishes.
+    Polling { db_pool: sqlx::PgPool, job_id: Uuid },
+}
+
+struct StreamState {
+    backlog: std::vec::IntoIter<JobEvent>,
+    source: Option<Source>,
+    last_seq: u64,
+}
+
+/// GET /jobs/{id}/events — Server-Sent Events stream of a job's lifecycle, ending with
+/// `succeeded` or `failed`. Honors `Last-Event-ID` when reconnecting.
+pub async fn job_events(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let Ok(id) = Uuid::parse_str(&path.into_inner()) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid job id"}));
+    };
+    let last_seq: u64 = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    if !matches!(db::find_job(&state.db_pool, id).await, Ok(Some(_))) {
+        return HttpResponse::NotFound().json(serde_json::json!({"error": "Job not found"}));
+    }
+
+    // Subscribe before re-reading the status, so a job finishing in between isn't missed
+    let subscription = state.events.subscribe(id);
+    let finished = match db::find_job(&state.db_pool, id).await {
+        Ok(Some(job)) => finished_event(&job),
+        _ => None,
+    };
+
+    let (backlog, source) = match (finished, subscription) {
+        (Some(event), subscription) => {
+            let history = subscription.map(|(history, _)| history).unwrap_or_default();
+            let mut backlog: Vec<JobEvent> = history.into_iter().filter(|e| !e.kind.is_terminal()).collect();
+            backlog.push(event);
+            (backlog, None)
+        }
+        (None, Some((history, receiver))) => (history, Some(Source::Live(receiver))),
+        (None, None) => (
+            Vec::new(),
+            Some(Source::Polling {
+                db_pool: state.db_pool.clone(),
+                job_id: id,
+            }),
+        ),
+    };
+
+    let stream = futures_util::stream::unfold(
+        StreamState {
+            backlog: backlog.into_iter(),
+            source,
+            last_seq,
+        },
+        |mut s| async move {
+            for event in s.backlog.by_ref() {
+                if event.seq == 0 || event.seq > s.last_seq {
+                    s.last_seq = event.seq.max(s.last_seq);
+                    if event.kind.is_terminal() {
+                        s.source = None;
+                    }
+                    return Some((Ok::<_, actix_web::Error>(event.to_sse()), s));
+                }
+            }
+
+            let receiver = match s.source.as_mut()? {
+                Source::Live(receiver) => receiver,
+                Source::Polling { db_pool, job_id } => {
+                    let quiet_since = tokio::time::Instant::now();
+                    loop {
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        match db::find_job(db_pool, *job_id).await {
+                            Ok(Some(job)) => {
+                                if let Some(event) = finished_event(&job) {
+                                    s.source = None;
+                                    return Some((Ok(event.to_sse()), s));
+                                }
+                            }
+                            // Deleted from under us: nothing more will come
+                            Ok(None) => return None,
+                            Err(e) => tracing::warn!("[job {}] Failed to poll for events: {}", job_id, e),
+                        }
+                        if quiet_since.elapsed() >= KEEP_ALIVE {
+                            return Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), s));
+                        }
+                    }
+                }
+            };
+            loop {
+                match tokio::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                    Err(_) => return Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), s)),
+                    Ok(Ok(event)) if event.seq <= s.last_seq => continue,
+                    Ok(Ok(event)) => {
+                        s.last_seq = event.seq;
+                        if event.kind.is_terminal() {
+                            s.source = None;
+                        }
+                        return Some((Ok(event.to_sse()), s));
+                    }
+                    // Fell behind; the next event still carries current progress
+                    Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                    Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                }
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream)
+}
@@ -1,50 +1,53 @@
-use std::path::Path;
+use std::time::Instant;
 
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
 use crate::AppState;
+use crate::credits::{self, Debit};
 use crate::db;
-use crate::domain_types::DomainU256;
-use crate::endpoints::{EndpointDef, PostProcess, QualityMap, extract_url};
-use crate::s3;
-use crate::x402;
+use crate::endpoints::{EndpointDef, GenerationMode, ModelTarget, QualityMap};
+use crate::idempotency::{self, Claim};
+use crate::inputs::{self, InputImage};
+use crate::jobs::{self, JobResponse};
+use crate::moderation;
+use crate::pipeline::{self, GenerationRequest, Generated};
+use crate::params;
+use crate::pricing;
+use crate::webhooks;
+use crate::x402::{self, SettleResponse, SettlementError, VerifiedPayment};
 
 #[derive(Deserialize)]
 pub struct PromptQuery {
     pub prompt: Option<String>,
     #[serde(default = "default_quality")]
     pub quality: String,
+    /// Async routes only: where to POST the finished job (see `webhooks`).
+    pub callback_url: Option<String>,
+    /// Everything else: provider params, checked against the endpoint's `allowed_params`.
+    #[serde(flatten)]
+    pub params: serde_json::Map<String, serde_json::Value>,
 }
 
 fn default_quality() -> String {
     "low".to_string()
 }
 
-#[derive(Serialize)]
-struct GenerateResponse {
-    url: String,
-    prompt: String,
-    cached: bool,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerateResponse {
+    /// The first output; kept for single-output clients.
+    pub url: String,
+    /// Every output, in provider order.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// The model that produced the result; differs from the endpoint's when a fallback was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub prompt: String,
+    pub cached: bool,
     #[serde(rename = "type")]
-    media_type: String,
-    quality: String,
-}
-
-async fn download_url(http_client: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
-    let resp = http_client
-        .get(url)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to download {}: {}", url, e))?;
-    if !resp.status().is_success() {
-        return Err(format!("Download failed with status {}", resp.status()));
-    }
-    resp.bytes()
-        .await
-        .map(|b| b.to_vec())
-        .map_err(|e| format!("Failed to read download bytes: {}", e))
+    pub media_type: String,
+    pub quality: String,
 }
 
 pub async fn handle_generate(
@@ -53,14 +56,33 @@ pub async fn handle_generate(
     quality_map: web::Data<QualityMap>,
     body: web::Bytes,
 ) -> HttpResponse {
-    // Try JSON body first, then fall back to query params
-    let query: PromptQuery = if body.is_empty() {
+    // Multipart (for image uploads), then JSON body, then query params
+    let mut upload = None;
+    let mut query: PromptQuery = if inputs::is_multipart(&req) {
+        let fields = match inputs::read_multipart(&req, body).await {
+            Ok((fields, image)) => {
+                upload = image;
+                fields
+            }
+            Err(e) => return HttpResponse::BadRequest().json(serde_json::json!({ "error": e })),
+        };
+        match serde_json::from_value(serde_json::Value::Object(fields)) {
+            Ok(q) => q,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Invalid form fields: {}", e)
+                }));
+            }
+        }
+    } else if body.is_empty() {
         // No body — try query params
         match web::Query::<PromptQuery>::from_query(req.query_string()) {
             Ok(q) => q.into_inner(),
             Err(_) => PromptQuery {
                 prompt: None,
                 quality: default_quality(),
+                callback_url: None,
+                params: serde_json::Map::new(),
             },
         }
     } else {
@@ -87,230 +109,457 @@ pub async fn handle_generate(
         }
     };
 
-    match handle_endpoint_inner(&state, &req, query.prompt.as_deref(), endpoint, quality).await {
+    let input = match (upload, query.params.remove(inputs::IMAGE_URL_FIELD)) {
+        (Some(upload), _) => Some(upload),
+        (None, Some(serde_json::Value::String(url))) => Some(InputImage::Url(url)),
+        (None, Some(_)) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("'{}' must be a string", inputs::IMAGE_URL_FIELD)
+            }));
+        }
+        (None, None) => None,
+    };
+    if let Err(e) = inputs::check_presence(endpoint, input.as_ref()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+    }
+
+    if let Some(url) = &query.callback_url {
+        let check = if endpoint.mode == GenerationMode::Async {
+            webhooks::check_callback_url(&state.config, url).await
+        } else {
+            Err("callback_url is only supported on async routes".to_string())
+        };
+        if let Err(e) = check {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+        }
+    }
+
+    // Don't ask for payment while every model of this quality is failing
+    if let Some(retry_after) = state.breakers.endpoint_retry_after(endpoint) {
+        tracing::warn!("[{}] Refusing request: circuit open", endpoint.path);
+        let mut available: Vec<&String> = quality_map
+            .iter()
+            .filter(|(_, ep)| state.breakers.endpoint_retry_after(ep).is_none())
+            .map(|(q, _)| q)
+            .collect();
+        available.sort();
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(serde_json::json!({
+                "error": format!("Quality '{}' is temporarily unavailable: its provider is failing", quality),
+                "retry_after": retry_after,
+                "available_qualities": available,
+            }));
+    }
+
+    // Reject bad params before asking for payment
+    let params = match params::validate(endpoint, &query.params) {
+        Ok(params) => params,
+        Err(errors) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Invalid parameters",
+                "details": errors,
+                "allowed_params": endpoint.allowed_params,
+            }));
+        }
+    };
+
+    // Refuse prompts we won't serve before asking for payment
+    let texts: Vec<&str> = query
+        .prompt
+        .as_deref()
+        .into_iter()
+        .chain(params.values().filter_map(|v| v.as_str()))
+        .collect();
+    if let Err(e) = state.moderation.check(&state.http_client, &texts).await {
+        if let moderation::ModerationError::Unavailable(reason) = &e {
+            tracing::error!("[{}] Moderation unavailable: {}", endpoint.path, reason);
+        } else {
+            tracing::info!("[{}] Prompt rejected by moderation", endpoint.path);
+        }
+        return e.to_response();
+    }
+
+    // A retry of a request already paid for gets that request's response
+    let request_hash =
+        idempotency::request_hash(endpoint, quality, query.prompt.as_deref(), &params, input.as_ref());
+    let claim = match idempotency::begin(&state, &req, endpoint, &request_hash).await {
+        Ok(claim) => claim,
+        Err(resp) => return resp,
+    };
+
+    let resp = match handle_endpoint_inner(
+        &state,
+        &req,
+        query.prompt.as_deref(),
+        endpoint,
+        quality,
+        params,
+        input,
+        query.callback_url.as_deref(),
+        claim.as_ref(),
+    )
+    .await
+    {
         Ok(resp) => resp,
         Err(resp) => resp,
+    };
+    match claim {
+        Some(claim) => idempotency::finish(&state, claim, resp).await,
+        None => resp,
     }
 }
 
-async fn handle_endpoint_inner(
+/// Settle a verified payment, if there is one (TEST_MODE has none).
+async fn settle_if_paid(
     state: &AppState,
+    payment: Option<&VerifiedPayment>,
+) -> Result<Option<SettleResponse>, SettlementError> {
+    match payment {
+        Some(payment) => x402::settle_x402_payment(&state.config, &state.http_client, &state.db_pool, payment)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Release a verified payment that won't be settled, so the client can present it again,
+/// or refund the credits debited instead.
+async fn release_if_paid(state: &AppState, payment: Option<&VerifiedPayment>, debit: Option<&Debit>) {
+    if let Some(payment) = payment {
+        x402::release_payment(&state.db_pool, payment).await;
+    }
+    if let Some(debit) = debit {
+        credits::refund(&state.db_pool, debit.id).await;
+    }
+}
+
+/// Attach the X-PAYMENT-RESPONSE settlement receipt to a paid response.
+pub fn with_receipt(
+    mut builder: HttpResponseBuilder,
+    settlement: Option<&SettleResponse>,
+) -> HttpResponseBuilder {
+    if let Some(settlement) = settlement {
+        builder.insert_header((
+            x402::PAYMENT_RESPONSE_HEADER,
+            x402::encode_payment_response(settlement),
+        ));
+    }
+    builder
+}
+
+/// Attach what a paid response was charged: the settlement receipt or the remaining credits.
+fn with_charge(
+    builder: HttpResponseBuilder,
+    settlement: Option<&SettleResponse>,
+    debit: Option<&Debit>,
+) -> HttpResponseBuilder {
+    credits::with_balance(with_receipt(builder, settlement), debit)
+}
+
+/// Run a synchronous generation and upload its outputs, falling back through the endpoint's
+/// models on provider errors or timeouts. Nothing is settled or recorded here.
+async fn generate_sync(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    request: &GenerationRequest,
+) -> Result<Generated, String> {
+    let targets = endpoint.targets();
+    let mut errors = Vec::new();
+    for (i, target) in targets.iter().enumerate() {
+        if !state.breakers.try_call(target.model) {
+            errors.push(format!("{}: circuit open", target.model));
+            continue;
+        }
+        let started = Instant::now();
+        let result = submit_to(state, endpoint, target, request).await;
+        state.breakers.record(target.model, result.is_ok(), started.elapsed());
+        match result {
+            Ok(result_urls) => {
+                if i > 0 {
+                    tracing::info!("[{}] Fallback model {} succeeded", endpoint.path, target.model);
+                }
+                let cache_key = request.cache_key_for(endpoint, target);
+                let outputs = pipeline::store_results(state, endpoint, &cache_key, &result_urls, None).await?;
+                return Ok(Generated {
+                    model: target.model.to_string(),
+                    cache_key,
+                    outputs,
+                });
+            }
+            Err(e) => {
+                tracing::error!("[{}] {} failed: {}", endpoint.path, target.model, e);
+                errors.push(format!("{}: {}", target.model, e));
+            }
+        }
+    }
+    Err(pipeline::all_models_failed(errors))
+}
+
+/// Generate with one model and return its result URLs.
+async fn submit_to(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    target: &ModelTarget<'_>,
+    request: &GenerationRequest,
+) -> Result<Vec<String>, String> {
+    let provider = state
+        .providers
+        .get(target.provider)
+        .ok_or_else(|| format!("Unknown provider '{}'", target.provider))?;
+
+    let body = request.body_for(endpoint, target);
+    let submit = provider.submit(&state.http_client, target.base_url, target.model, &body);
+    let resp_json = match endpoint.provider_timeout() {
+        Some(limit) => tokio::time::timeout(limit, submit)
+            .await
+            .map_err(|_| format!("Timed out after {}s", limit.as_secs()))??,
+        None => submit.await?,
+    };
+
+    provider
+        .extract_result_urls(&resp_json, target.response_url_path)
+        .map_err(|e| {
+            tracing::error!(
+                "[{}] Failed to extract URL from {} response: {}. Response: {}",
+                endpoint.path,
+                target.provider,
+                e,
+                serde_json::to_string_pretty(&resp_json).unwrap_or_default()
+            );
+            format!("No result URL in {} response: {}", target.provider, e)
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_endpoint_inner(
+    state: &web::Data<AppState>,
     req: &HttpRequest,
     prompt: Option<&str>,
     endpoint: &EndpointDef,
     quality: &str,
+    mut params: serde_json::Map<String, serde_json::Value>,
+    input: Option<InputImage>,
+    callback_url: Option<&str>,
+    claim: Option<&Claim>,
 ) -> Result<HttpResponse, HttpResponse> {
-    let cost = DomainU256::from_human_amount(&endpoint.cost, state.config.payment_token_decimals)
-        .map_err(|e| {
-            tracing::error!("Bad cost in endpoint {}: {}", endpoint.path, e);
-            HttpResponse::InternalServerError().body(format!("Internal config error: {}", e))
-        })?;
-
-    let (payment_tx, payer_address) = x402::require_x402_payment(
-        &state.config,
-        &state.http_client,
-        req.headers(),
-        cost,
-        &endpoint.path,
-        &endpoint.description,
-    )
-    .await?;
-
     let effective = prompt.unwrap_or(&endpoint.default_prompt);
-
-    let hash = {
-        let mut h = Sha256::new();
-        h.update(effective.trim().to_lowercase().as_bytes());
-        hex::encode(h.finalize())
+    let has_input = input.is_some();
+
+    // Cache pre-check, so a hit is quoted at the endpoint's cached price. Requests with an input
+    // image are keyed by its staged copy, which is only made once paid for: they pay full price.
+    let mut cached = Vec::new();
+    if !has_input {
+        let cache_key = GenerationRequest::new(endpoint, effective, &params).cache_key;
+        if let Ok(records) = db::find_by_cache_key(&state.db_pool, &cache_key, &endpoint.path).await {
+            cached = records;
+        }
+    }
+    let cache_hit = !cached.is_empty();
+    let quotes = if cache_hit {
+        pricing::cached_quotes(&state.config, endpoint, &params)
+    } else {
+        pricing::quotes(&state.config, endpoint, &params)
+    };
+    let mut quotes = quotes.map_err(|e| {
+        tracing::error!("{}", e);
+        HttpResponse::InternalServerError().body(format!("Internal config error: {}", e))
+    })?;
+    for quote in &mut quotes {
+        quote.cache_hit = Some(cache_hit);
+    }
+    // A hit priced zero in every asset is free: nothing to verify, debit or settle
+    let free = cache_hit && quotes.iter().all(|q| q.amount.0.is_zero());
+
+    // A credit token pays by debiting the balance; otherwise verify now and settle only once
+    // the media has been delivered
+    let debit = match credits::authenticate(&state.db_pool, req.headers()).await? {
+        Some(payer) if !free => Some(credits::debit(state, &payer, &quotes, &endpoint.path).await?),
+        _ => None,
+    };
+    let payment = if free || debit.is_some() {
+        None
+    } else {
+        x402::verify_x402_payment(
+            &state.config,
+            &state.http_client,
+            &state.db_pool,
+            req.headers(),
+            &quotes,
+            &endpoint.path,
+            &endpoint.description,
+            endpoint.payment_timeout_seconds(),
+        )
+        .await?
     };
+    if let (Some(claim), Some(payment)) = (claim, &payment) {
+        idempotency::bind_payer(state, endpoint, claim, payment).await?;
+    }
+    let payer_address = payment
+        .as_ref()
+        .and_then(|p| p.payer.clone())
+        .or_else(|| debit.as_ref().map(|d| d.payer.clone()));
+
+    // Stage the input image before generating; a bad image is never charged
+    if let (Some(input), Some(spec)) = (input, &endpoint.input_image) {
+        let url = match inputs::stage(state, endpoint, input).await {
+            Ok(url) => url,
+            Err(e) => {
+                tracing::warn!("[{}] Rejected input image: {}", endpoint.path, e);
+                release_if_paid(state, payment.as_ref(), debit.as_ref()).await;
+                return Err(HttpResponse::BadRequest().json(serde_json::json!({ "error": e })));
+            }
+        };
+        params.insert(spec.param.clone(), serde_json::Value::String(url));
+    }
+
+    let request = GenerationRequest::new(endpoint, effective, &params);
 
-    // Cache check: query DB instead of filesystem
-    if let Ok(Some(record)) = db::find_by_prompt_hash(&state.db_pool, &hash, &endpoint.path).await
+    // Cache check: keyed by the full provider request, not just the prompt. Requests with an
+    // input image can only be checked now that it is staged.
+    if has_input
+        && let Ok(records) = db::find_by_cache_key(&state.db_pool, &request.cache_key, &endpoint.path).await
     {
+        cached = records;
+    }
+    if !cached.is_empty() {
         tracing::info!(
             "[{}] Cache hit for prompt: {}",
             endpoint.path,
             effective
         );
-        return Ok(HttpResponse::Ok().json(GenerateResponse {
-            url: record.s3_url,
+        let settlement = settle_if_paid(state, payment.as_ref())
+            .await
+            .map_err(|e| e.to_response())?;
+        let payment_tx = settlement.as_ref().and_then(|s| s.transaction.clone());
+        let payer_address = settlement
+            .as_ref()
+            .and_then(|s| s.payer.clone())
+            .or(payer_address);
+
+        let model = cached[0].model.clone();
+        let urls: Vec<String> = cached.into_iter().map(|r| r.s3_url).collect();
+        let cached = GenerateResponse {
+            url: urls[0].clone(),
+            urls,
+            model,
             prompt: effective.to_string(),
             cached: true,
             media_type: endpoint.media_type.clone(),
             quality: quality.to_string(),
-        }));
+        };
+        if endpoint.mode == GenerationMode::Async {
+            // Async routes always answer with a job; record the hit as an already finished one
+            let result = serde_json::to_value(&cached).unwrap_or_default();
+            let id = db::insert_job(
+                &state.db_pool,
+                &endpoint.path,
+                quality,
+                effective,
+                &request.prompt_hash,
+                &request.cache_key,
+                &request.body,
+                jobs::STATUS_SUCCEEDED,
+                Some(&result),
+                payer_address.as_deref(),
+                payment_tx.as_deref(),
+                None,
+                callback_url,
+                debit.as_ref().map(|d| d.id),
+                None,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("[{}] Failed to create job: {}", endpoint.path, e);
+                HttpResponse::InternalServerError().body(format!("Failed to create job: {}", e))
+            })?;
+            if let Some(settlement) = &settlement {
+                let receipt = serde_json::to_value(settlement).unwrap_or_default();
+                if let Err(e) = db::set_job_payment(
+                    &state.db_pool,
+                    id,
+                    payer_address.as_deref(),
+                    payment_tx.as_deref(),
+                    &receipt,
+                )
+                .await
+                {
+                    tracing::error!("[job {}] Failed to record payment: {}", id, e);
+                }
+            }
+            return match db::find_job(&state.db_pool, id).await {
+                Ok(Some(job)) => {
+                    webhooks::notify_job(state, job.clone()).await;
+                    Ok(with_charge(HttpResponse::Accepted(), settlement.as_ref(), debit.as_ref()).json(JobResponse::from(job)))
+                }
+                _ => Err(HttpResponse::InternalServerError().body("Failed to load job")),
+            };
+        }
+        return Ok(with_charge(HttpResponse::Ok(), settlement.as_ref(), debit.as_ref()).json(cached));
     }
 
-    tracing::info!("[{}] Generating for: {}", endpoint.path, effective);
-
-    // Build fal request body: merge request_params + prompt
-    let mut body_map = serde_json::Map::new();
-    for (k, v) in &endpoint.request_params {
-        body_map.insert(k.clone(), v.clone());
-    }
-    body_map.insert(
-        "prompt".to_string(),
-        serde_json::Value::String(effective.to_string()),
-    );
-    let request_body = serde_json::Value::Object(body_map);
-
-    let fal_url = format!("https://fal.run/{}", endpoint.fal_model);
-    let response = state
-        .http_client
-        .post(&fal_url)
-        .header(
-            "Authorization",
-            format!("Key {}", state.config.fal_key),
-        )
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| {
-            tracing::error!("[{}] FAL request failed: {}", endpoint.path, e);
-            HttpResponse::InternalServerError().body(format!("FAL request failed: {}", e))
-        })?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        tracing::error!("[{}] FAL error {}: {}", endpoint.path, status, body);
-        return Err(HttpResponse::InternalServerError().body(format!("FAL error {}: {}", status, body)));
+    if endpoint.mode == GenerationMode::Async {
+        let job = match jobs::start_job(state, endpoint, quality, &request, payment.as_ref(), debit.as_ref(), callback_url)
+            .await
+        {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::error!("[{}] {}", endpoint.path, e);
+                release_if_paid(state, payment.as_ref(), debit.as_ref()).await;
+                return Err(HttpResponse::InternalServerError().body(e));
+            }
+        };
+        return Ok(credits::with_balance(HttpResponse::Accepted(), debit.as_ref()).json(JobResponse::from(job)));
     }
 
-    let resp_json: serde_json::Value = response.json().await.map_err(|e| {
-        tracing::error!("[{}] Failed to parse FAL response: {}", endpoint.path, e);
-        HttpResponse::InternalServerError().body(format!("Failed to parse FAL response: {}", e))
-    })?;
-
-    let result_url = extract_url(&resp_json, &endpoint.response_url_path).map_err(|e| {
-        tracing::error!(
-            "[{}] Failed to extract URL from FAL response: {}. Response: {}",
-            endpoint.path,
-            e,
-            serde_json::to_string_pretty(&resp_json).unwrap_or_default()
-        );
-        HttpResponse::InternalServerError().body(format!("No result URL in FAL response: {}", e))
-    })?;
+    tracing::info!("[{}] Generating for: {}", endpoint.path, effective);
 
-    let result_bytes = download_url(&state.http_client, &result_url)
+    let generated = match x402::keep_claimed(&state.db_pool, payment.as_ref(), generate_sync(state, endpoint, &request))
         .await
-        .map_err(|e| {
-            tracing::error!("[{}] Download failed: {}", endpoint.path, e);
-            HttpResponse::InternalServerError().body(e)
-        })?;
-
-    // Post-process if needed (ffmpeg uses tmp/ dir for temp files)
-    let final_bytes = match &endpoint.post_process {
-        PostProcess::None => result_bytes,
-        PostProcess::FfmpegToGif {
-            input_extension,
-            ffmpeg_args,
-        } => {
-            std::fs::create_dir_all("tmp").map_err(|e| {
-                HttpResponse::InternalServerError().body(format!("Failed to create tmp dir: {}", e))
-            })?;
-
-            let tmp_input = Path::new("tmp").join(format!("{}.{}", hash, input_extension));
-            let tmp_output = Path::new("tmp").join(format!("{}.{}", hash, endpoint.output_extension));
-
-            std::fs::write(&tmp_input, &result_bytes).map_err(|e| {
-                tracing::error!("[{}] Failed to save temp file: {}", endpoint.path, e);
-                HttpResponse::InternalServerError().body(format!("Failed to save temp file: {}", e))
-            })?;
-
-            let mut cmd_args = vec![
-                "-i".to_string(),
-                tmp_input.to_string_lossy().to_string(),
-            ];
-            cmd_args.extend(ffmpeg_args.iter().cloned());
-            cmd_args.push("-y".to_string());
-            cmd_args.push(tmp_output.to_string_lossy().to_string());
-
-            let output = tokio::process::Command::new("ffmpeg")
-                .args(&cmd_args)
-                .output()
-                .await
-                .map_err(|e| {
-                    tracing::error!("[{}] ffmpeg failed: {}", endpoint.path, e);
-                    HttpResponse::InternalServerError().body(format!(
-                        "ffmpeg failed to execute (is it installed?): {}",
-                        e
-                    ))
-                })?;
-
-            let _ = std::fs::remove_file(&tmp_input);
-
-            if !output.status.success() {
-                let _ = std::fs::remove_file(&tmp_output);
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                tracing::error!("[{}] ffmpeg conversion failed: {}", endpoint.path, stderr);
-                return Err(HttpResponse::InternalServerError().body(format!(
-                    "ffmpeg conversion failed: {}",
-                    stderr
-                )));
-            }
-
-            let converted = std::fs::read(&tmp_output).map_err(|e| {
-                HttpResponse::InternalServerError().body(format!("Failed to read ffmpeg output: {}", e))
-            })?;
-            let _ = std::fs::remove_file(&tmp_output);
-            converted
+    {
+        Ok(generated) => generated,
+        Err(e) => {
+            pipeline::record_failure(state, endpoint, effective, None, payer_address.as_deref(), &e)
+                .await;
+            release_if_paid(state, payment.as_ref(), debit.as_ref()).await;
+            let body = if payment.is_some() {
+                format!("{} (payment was not settled)", e)
+            } else if debit.is_some() {
+                format!("{} (credits were refunded)", e)
+            } else {
+                e
+            };
+            return Err(HttpResponse::InternalServerError().body(body));
         }
     };
 
-    // S3 key: endpoint_path_without_leading_slash/hash.ext
-    let path_segment = endpoint.path.trim_start_matches('/');
-    let s3_key = format!("{}/{}.{}", path_segment, hash, endpoint.output_extension);
-
-    let content_type = match endpoint.output_extension.as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "mp4" => "video/mp4",
-        "webp" => "image/webp",
-        _ => "application/octet-stream",
+    let settlement = match settle_if_paid(state, payment.as_ref()).await {
+        Ok(settlement) => settlement,
+        Err(e) => {
+            // Keep a row so cleanup removes the media, but never serve it from the cache
+            pipeline::record_unsettled_media(state, endpoint, &request, &generated).await;
+            return Err(e.to_response());
+        }
     };
-
-    let file_size = final_bytes.len() as i64;
-
-    // Upload to S3
-    s3::upload_file(
-        &state.s3_client,
-        &state.config.s3_bucket,
-        &s3_key,
-        final_bytes,
-        content_type,
-    )
-    .await
-    .map_err(|e| {
-        tracing::error!("[{}] S3 upload failed: {}", endpoint.path, e);
-        HttpResponse::InternalServerError().body(e)
-    })?;
-
-    let cdn_url = s3::cdn_url(&state.config, &s3_key);
-
-    tracing::info!("[{}] Uploaded to S3: {}", endpoint.path, cdn_url);
-
-    // Insert DB record
-    if let Err(e) = db::insert_media(
-        &state.db_pool,
-        &endpoint.path,
-        effective,
-        &hash,
-        &s3_key,
-        &cdn_url,
-        &endpoint.media_type,
-        file_size,
+    let payer_address = settlement
+        .as_ref()
+        .and_then(|s| s.payer.clone())
+        .or(payer_address);
+
+    pipeline::record_media(
+        state,
+        endpoint,
+        &request,
+        &generated,
+        settlement.as_ref(),
         payer_address.as_deref(),
-        payment_tx.as_deref(),
     )
-    .await
-    {
-        tracing::error!("[{}] Failed to insert DB record: {}", endpoint.path, e);
-    }
+    .await;
 
-    Ok(HttpResponse::Ok().json(GenerateResponse {
-        url: cdn_url,
+    Ok(with_charge(HttpResponse::Ok(), settlement.as_ref(), debit.as_ref()).json(GenerateResponse {
+        url: generated.outputs[0].cdn_url.clone(),
+        urls: generated.outputs.iter().map(|m| m.cdn_url.clone()).collect(),
+        model: Some(generated.model.clone()),
         prompt: effective.to_string(),
         cached: false,
         media_type: endpoint.media_type.clone(),
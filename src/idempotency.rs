@@ -0,0 +1,336 @@
+//! `Idempotency-Key` for paid generation requests. The first request's response (status, body
+//! and X-PAYMENT-RESPONSE receipt) is stored and replayed to later requests with the same key
+//! from the same payer — whether they resend the same payment or sign a new one — so a client
+//! retrying after a dropped connection is never charged twice. A retry that arrives while the
+//! first request is still running waits for its outcome. Requests paid with a credit token are
+//! keyed by the token's payer instead, and replay the original debit rather than debiting again.
+
+use std::time::{Duration, Instant};
+
+use actix_web::body::{self, BoxBody};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::credits;
+use crate::db::{self, IdempotencyRecord};
+use crate::endpoints::EndpointDef;
+use crate::inputs::InputImage;
+use crate::x402::{self, VerifiedPayment};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+/// Set on responses replayed from an earlier request.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LEN: usize = 255;
+/// How long a key's outcome is kept.
+const KEY_TTL: Duration = Duration::from_secs(24 * 3600);
+/// An in-flight key this old belongs to a request that died; it's dropped on the next use.
+const STALE_IN_FLIGHT: Duration = Duration::from_secs(15 * 60);
+/// How long a retry waits for the first request before giving up with a 409.
+const IN_FLIGHT_WAIT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Races with requests finishing or giving up their key are retried this many times.
+const ATTEMPTS: usize = 3;
+const STATE_COMPLETED: &str = "completed";
+
+/// A key held by the current request until `finish`.
+pub struct Claim {
+    id: Uuid,
+    key: String,
+    payload_hash: String,
+    request_hash: String,
+    /// Paid with credits rather than an X-PAYMENT payload.
+    credits: bool,
+}
+
+enum Waited {
+    Done(HttpResponse),
+    /// The first request gave the key up (it charged nothing); this one may take it.
+    Gone,
+}
+
+/// Fingerprint of what a request asks for, so a key can't be reused for a different one.
+pub fn request_hash(
+    endpoint: &EndpointDef,
+    quality: &str,
+    prompt: Option<&str>,
+    params: &serde_json::Map<String, serde_json::Value>,
+    input: Option<&InputImage>,
+) -> String {
+    let input = match input {
+        Some(InputImage::Url(url)) => Some(url.clone()),
+        Some(InputImage::Upload(bytes)) => Some(hex::encode(Sha256::digest(bytes))),
+        None => None,
+    };
+    let fingerprint = serde_json::json!({
+        "path": endpoint.path,
+        "quality": quality,
+        "prompt": prompt,
+        "params": params,
+        "input": input,
+    });
+    hex::encode(Sha256::digest(serde_json::to_vec(&fingerprint).unwrap_or_default()))
+}
+
+fn error(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({ "error": message }))
+}
+
+fn unavailable(e: sqlx::Error) -> HttpResponse {
+    tracing::error!("Idempotency key lookup failed: {}", e);
+    error(StatusCode::SERVICE_UNAVAILABLE, "Idempotency keys are temporarily unavailable")
+}
+
+fn in_progress() -> HttpResponse {
+    HttpResponse::Conflict()
+        .insert_header(("Retry-After", "5"))
+        .json(serde_json::json!({ "error": "A request with this Idempotency-Key is still in progress" }))
+}
+
+fn check_same_request(record: &IdempotencyRecord, request_hash: &str) -> Result<(), HttpResponse> {
+    if record.request_hash == request_hash {
+        Ok(())
+    } else {
+        Err(error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used for a different request",
+        ))
+    }
+}
+
+fn replay(record: &IdempotencyRecord) -> HttpResponse {
+    let status = record
+        .status_code
+        .and_then(|code| StatusCode::from_u16(code as u16).ok())
+        .unwrap_or(StatusCode::OK);
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((REPLAYED_HEADER, "true"));
+    if let Some(content_type) = &record.content_type {
+        builder.content_type(content_type.as_str());
+    }
+    if let Some(receipt) = &record.payment_response {
+        builder.insert_header((x402::PAYMENT_RESPONSE_HEADER, receipt.as_str()));
+    }
+    if let (Some(balance), Some(asset)) = (&record.credit_balance, &record.credit_asset) {
+        builder.insert_header((credits::BALANCE_HEADER, balance.as_str()));
+        builder.insert_header((credits::ASSET_HEADER, asset.as_str()));
+    }
+    builder.body(record.response_body.clone().unwrap_or_default())
+}
+
+/// Wait for the request holding record `id` to finish.
+async fn wait_for(state: &AppState, id: Uuid) -> Result<Waited, HttpResponse> {
+    let deadline = Instant::now() + IN_FLIGHT_WAIT;
+    loop {
+        match db::find_idempotency_key_by_id(&state.db_pool, id).await {
+            Ok(Some(record)) if record.state == STATE_COMPLETED => return Ok(Waited::Done(replay(&record))),
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(Waited::Gone),
+            Err(e) => return Err(unavailable(e)),
+        }
+        if Instant::now() >= deadline {
+            return Err(in_progress());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Stands in for the payload hash of requests paid with credits, so their keys are per payer.
+fn credit_scope(payer: &str) -> String {
+    hex::encode(Sha256::digest(format!("credits:{}", payer.to_lowercase())))
+}
+
+/// Claim the request's `Idempotency-Key` before its payment is verified or its credits debited.
+/// Returns the response to send instead when the same key and payment (or credit payer) were
+/// seen before. Requests without a key or a payment (TEST_MODE, or about to get a 402) get None.
+pub async fn begin(
+    state: &AppState,
+    req: &HttpRequest,
+    endpoint: &EndpointDef,
+    request_hash: &str,
+) -> Result<Option<Claim>, HttpResponse> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!("{} must be 1-{} visible ASCII characters", IDEMPOTENCY_KEY_HEADER, MAX_KEY_LEN),
+            ));
+        }
+    };
+    if state.config.test_mode {
+        return Ok(None);
+    }
+    // A credit token takes precedence over X-PAYMENT, as when the request is paid
+    let (payload_hash, credits) = match credits::authenticate(&state.db_pool, req.headers()).await? {
+        Some(payer) => (credit_scope(&payer), true),
+        None => match x402::header_payload_hash(req.headers()) {
+            Some(hash) => (hash, false),
+            None => return Ok(None),
+        },
+    };
+
+    for _ in 0..ATTEMPTS {
+        let inserted = db::insert_idempotency_key(
+            &state.db_pool,
+            &key,
+            &payload_hash,
+            &endpoint.path,
+            request_hash,
+            KEY_TTL,
+            STALE_IN_FLIGHT,
+        )
+        .await
+        .map_err(unavailable)?;
+        if let Some(id) = inserted {
+            return Ok(Some(Claim {
+                id,
+                key,
+                payload_hash,
+                request_hash: request_hash.to_string(),
+                credits,
+            }));
+        }
+
+        let Some(existing) = db::find_idempotency_key(&state.db_pool, &key, &payload_hash)
+            .await
+            .map_err(unavailable)?
+        else {
+            continue;
+        };
+        check_same_request(&existing, request_hash)?;
+        tracing::info!("[{}] Idempotency-Key {} seen before with this payment", endpoint.path, key);
+        match wait_for(state, existing.id).await? {
+            Waited::Done(resp) => return Err(resp),
+            Waited::Gone => continue,
+        }
+    }
+    Err(in_progress())
+}
+
+/// Attach the verified payer to the claim. If the payer already used the key with another
+/// payment, this one is released uncharged and the earlier request's response is returned.
+pub async fn bind_payer(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    claim: &Claim,
+    payment: &VerifiedPayment,
+) -> Result<(), HttpResponse> {
+    // Without a payer from the facilitator the key is scoped to the payment payload alone
+    let Some(payer) = &payment.payer else {
+        return Ok(());
+    };
+
+    for _ in 0..ATTEMPTS {
+        match db::set_idempotency_payer(&state.db_pool, claim.id, payer).await {
+            Ok(()) => return Ok(()),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {}
+            Err(e) => {
+                tracing::error!("[{}] Failed to record idempotency key payer: {}", endpoint.path, e);
+                return Ok(());
+            }
+        }
+
+        let other = match db::find_idempotency_key_for_payer(&state.db_pool, &claim.key, payer, claim.id).await {
+            Ok(Some(other)) => other,
+            Ok(None) => continue,
+            Err(e) => return Err(give_up(state, claim, payment, unavailable(e)).await),
+        };
+        if let Err(resp) = check_same_request(&other, &claim.request_hash) {
+            return Err(give_up(state, claim, payment, resp).await);
+        }
+        tracing::info!(
+            "[{}] Idempotency-Key {} seen before from {}; not charging this payment",
+            endpoint.path,
+            claim.key,
+            payer
+        );
+        match wait_for(state, other.id).await {
+            Ok(Waited::Done(resp)) => return Err(give_up(state, claim, payment, resp).await),
+            Ok(Waited::Gone) => continue,
+            Err(resp) => return Err(give_up(state, claim, payment, resp).await),
+        }
+    }
+    Err(give_up(state, claim, payment, in_progress()).await)
+}
+
+/// Release the payment and the claim of a request that is answered without running.
+async fn give_up(state: &AppState, claim: &Claim, payment: &VerifiedPayment, resp: HttpResponse) -> HttpResponse {
+    x402::release_payment(&state.db_pool, payment).await;
+    forget(state, claim).await;
+    resp
+}
+
+async fn forget(state: &AppState, claim: &Claim) {
+    if let Err(e) = db::delete_idempotency_key(&state.db_pool, claim.id).await {
+        tracing::error!("Failed to release Idempotency-Key {}: {}", claim.key, e);
+    }
+}
+
+/// Store the response for replay and return it. Responses that charged nothing (the payment
+/// was rejected or released, or the credits refunded) aren't kept, so a retry runs again.
+pub async fn finish(state: &AppState, claim: Claim, resp: HttpResponse) -> HttpResponse {
+    // Debited credits are refunded whenever a request fails, so only successes keep their debit
+    let charged = if claim.credits {
+        resp.status().is_success()
+    } else {
+        x402::payload_may_be_charged(&state.db_pool, &claim.payload_hash).await
+    };
+    if !charged {
+        forget(state, &claim).await;
+        return resp;
+    }
+
+    let status = resp.status();
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let content_type = header(header::CONTENT_TYPE.as_str());
+    let receipt = header(x402::PAYMENT_RESPONSE_HEADER);
+    let credit_balance = header(credits::BALANCE_HEADER);
+    let credit_asset = header(credits::ASSET_HEADER);
+    let (head, resp_body) = resp.into_parts();
+    let bytes = match body::to_bytes(resp_body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to read response for Idempotency-Key {}: {}", claim.key, e);
+            forget(state, &claim).await;
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if let Err(e) = db::complete_idempotency_key(
+        &state.db_pool,
+        claim.id,
+        status.as_u16() as i32,
+        content_type.as_deref(),
+        &bytes,
+        receipt.as_deref(),
+        credit_balance.as_deref(),
+        credit_asset.as_deref(),
+    )
+    .await
+    {
+        tracing::error!("Failed to store response for Idempotency-Key {}: {}", claim.key, e);
+        forget(state, &claim).await;
+    }
+    head.set_body(BoxBody::new(bytes))
+}
+
+/// Delete idempotency keys past their retention.
+pub async fn cleanup_expired(pool: &sqlx::PgPool) {
+    match db::delete_expired_idempotency_keys(pool).await {
+        Ok(0) => {}
+        Ok(n) => tracing::info!("Cleaned up {} expired idempotency keys", n),
+        Err(e) => tracing::error!("Failed to delete expired idempotency keys: {}", e),
+    }
+}
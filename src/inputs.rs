@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use actix_multipart::Multipart;
+use actix_web::error::PayloadError;
+use actix_web::{web, HttpRequest};
+use futures_util::StreamExt;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::AppState;
+use crate::db;
+use crate::endpoints::EndpointDef;
+use crate::outbound;
+
+/// JSON/query/form field carrying an input image URL.
+pub const IMAGE_URL_FIELD: &str = "image_url";
+/// Multipart file field carrying an uploaded input image.
+pub const IMAGE_FILE_FIELD: &str = "image";
+/// How long a staged input is kept after it was last staged, like generated media.
+const STAGED_INPUT_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Input image URLs that don't deliver within this are refused, before anything is charged.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An input image as supplied by the caller, before staging.
+pub enum InputImage {
+    Url(String),
+    Upload(Vec<u8>),
+}
+
+pub fn is_multipart(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("multipart/form-data"))
+}
+
+/// Read a multipart/form-data body: text fields become string request fields,
+/// the `image` file part is the input image.
+pub async fn read_multipart(
+    req: &HttpRequest,
+    body: web::Bytes,
+) -> Result<(Map<String, Value>, Option<InputImage>), String> {
+    let stream = futures_util::stream::once(async move { Ok::<_, PayloadError>(body) });
+    let mut multipart = Multipart::new(req.headers(), stream);
+
+    let mut fields = Map::new();
+    let mut upload = None;
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|e| format!("Invalid multipart body: {}", e))?;
+        let name = field.name().unwrap_or_default().to_string();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| format!("Invalid multipart body: {}", e))?;
+            data.extend_from_slice(&chunk);
+        }
+
+        if name == IMAGE_FILE_FIELD {
+            upload = Some(InputImage::Upload(data));
+        } else {
+            let text = String::from_utf8(data).map_err(|_| format!("Field '{}' is not valid UTF-8", name))?;
+            fields.insert(name, Value::String(text));
+        }
+    }
+    Ok((fields, upload))
+}
+
+/// Check an input image is supplied exactly when the endpoint takes one.
+pub fn check_presence(endpoint: &EndpointDef, input: Option<&InputImage>) -> Result<(), String> {
+    match (&endpoint.input_image, input) {
+        (None, Some(_)) => Err("This endpoint does not take an input image".to_string()),
+        (Some(spec), None) if spec.required => Err(format!(
+            "This endpoint requires an input image: upload it as the '{}' multipart field or pass '{}'",
+            IMAGE_FILE_FIELD, IMAGE_URL_FIELD
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// File extension and content type of a supported image, from its magic bytes.
+fn sniff_image(bytes: &[u8]) -> Option<(&'static str, &'static str)> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("png", "image/png"))
+    } else if bytes.starts_with(b"\xFF\xD8\xFF") {
+        Some(("jpg", "image/jpeg"))
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(("gif", "image/gif"))
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some(("webp", "image/webp"))
+    } else {
+        None
+    }
+}
+
+/// Fetch a caller's image URL with the outbound client, which refuses non-public hosts and
+/// redirects to them.
+fn fetch_error(action: &str, e: reqwest::Error) -> String {
+    if e.is_timeout() {
+        format!("Timed out fetching input image after {}s", FETCH_TIMEOUT.as_secs())
+    } else {
+        format!("Failed to {} input image: {}", action, e)
+    }
+}
+
+async fn download_limited(state: &AppState, url: &str, max_bytes: usize) -> Result<Vec<u8>, String> {
+    let url = outbound::check_url(url, IMAGE_URL_FIELD, state.config.allow_private_urls).await?;
+    let mut response = state
+        .outbound_client
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| fetch_error("fetch", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Fetching input image failed with status {}", response.status()));
+    }
+    if response.content_length().is_some_and(|len| len as usize > max_bytes) {
+        return Err(format!("Input image is larger than {} bytes", max_bytes));
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| fetch_error("read", e))?
+    {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > max_bytes {
+            return Err(format!("Input image is larger than {} bytes", max_bytes));
+        }
+    }
+    Ok(bytes)
+}
+
+/// Copy the input image into our storage (keyed by content hash) and return its public URL.
+/// It is recorded in `staged_inputs` so the cleanup worker removes it once it expires.
+pub async fn stage(state: &AppState, endpoint: &EndpointDef, input: InputImage) -> Result<String, String> {
+    let spec = endpoint
+        .input_image
+        .as_ref()
+        .ok_or_else(|| "This endpoint does not take an input image".to_string())?;
+
+    let bytes = match input {
+        InputImage::Url(url) => download_limited(state, &url, spec.max_bytes).await?,
+        InputImage::Upload(bytes) if bytes.len() > spec.max_bytes => {
+            return Err(format!("Input image is larger than {} bytes", spec.max_bytes));
+        }
+        InputImage::Upload(bytes) => bytes,
+    };
+    let (extension, content_type) =
+        sniff_image(&bytes).ok_or_else(|| "Input image must be a PNG, JPEG, GIF or WebP".to_string())?;
+
+    let key = format!("inputs/{}.{}", hex::encode(Sha256::digest(&bytes)), extension);
+    state.storage.put(&key, bytes, content_type).await.map_err(|e| {
+        tracing::error!("[{}] Failed to stage input image: {}", endpoint.path, e);
+        "Failed to store input image".to_string()
+    })?;
+    if let Err(e) = db::record_staged_input(&state.db_pool, &key, STAGED_INPUT_TTL).await {
+        tracing::error!("[{}] Failed to record staged input {}: {}", endpoint.path, key, e);
+    }
+
+    let url = state.storage.public_url(&key);
+    tracing::info!("[{}] Staged input image: {}", endpoint.path, url);
+    Ok(url)
+}
@@ -0,0 +1,509 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::credits::{self, Debit};
+use crate::db::{self, JobRecord};
+use crate::endpoints::{EndpointDef, ModelTarget};
+use crate::events::JobEventKind;
+use crate::handler::{GenerateResponse, with_receipt};
+use crate::pipeline::{self, GenerationRequest, Generated};
+use crate::providers::{QueueStatus, QueuedRequest};
+use crate::webhooks;
+use crate::x402::{self, SettleResponse, SettlementError, VerifiedPayment};
+
+/// How often a running job polls its provider's queue.
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// Consecutive queue poll errors tolerated before the job is failed.
+const MAX_POLL_ERRORS: u32 = 5;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SUCCEEDED: &str = "succeeded";
+pub const STATUS_FAILED: &str = "failed";
+
+#[derive(Serialize)]
+pub struct JobResponse {
+    pub id: Uuid,
+    pub status: String,
+    pub status_url: String,
+    pub endpoint: String,
+    pub quality: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<GenerateResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<JobRecord> for JobResponse {
+    fn from(job: JobRecord) -> Self {
+        Self {
+            id: job.id,
+            status: job.status,
+            status_url: format!("/jobs/{}", job.id),
+            endpoint: job.endpoint_path,
+            quality: job.quality,
+            result: job.result.and_then(|r| serde_json::from_value(r).ok()),
+            error: job.error,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+        }
+    }
+}
+
+/// Persist a new pending job and start it in the background.
+/// The verified payment is stored with the job and settled only if generation succeeds;
+/// credits debited instead are refunded if it fails.
+pub async fn start_job(
+    state: &web::Data<AppState>,
+    endpoint: &EndpointDef,
+    quality: &str,
+    request: &GenerationRequest,
+    payment: Option<&VerifiedPayment>,
+    debit: Option<&Debit>,
+    callback_url: Option<&str>,
+) -> Result<JobRecord, String> {
+    let payment_request = payment.map(|p| serde_json::to_value(p).unwrap_or_default());
+    let id = db::insert_job(
+        &state.db_pool,
+        &endpoint.path,
+        quality,
+        &request.prompt,
+        &request.prompt_hash,
+        &request.cache_key,
+        &request.body,
+        STATUS_PENDING,
+        None,
+        payment.and_then(|p| p.payer.as_deref()).or(debit.map(|d| d.payer.as_str())),
+        None,
+        payment_request.as_ref(),
+        callback_url,
+        debit.map(|d| d.id),
+        payment.map(|p| x402::payload_hash(&p.request.payment_payload)).as_deref(),
+    )
+    .await
+    .map_err(|e| format!("Failed to create job: {}", e))?;
+
+    let job = db::find_job(&state.db_pool, id)
+        .await
+        .map_err(|e| format!("Failed to load job {}: {}", id, e))?
+        .ok_or_else(|| format!("Job {} vanished after insert", id))?;
+
+    tracing::info!("[{}] Created job {} for: {}", endpoint.path, id, request.prompt);
+    state.events.open(id);
+    if payment.is_some() || debit.is_some() {
+        state.events.emit(id, JobEventKind::PaymentVerified);
+    }
+    tokio::spawn(run_job(state.clone(), job.clone(), endpoint.clone()));
+    Ok(job)
+}
+
+/// Resume jobs left pending/running by a previous process.
+pub async fn resume_unfinished_jobs(state: web::Data<AppState>) {
+    let jobs = match db::find_unfinished_jobs(&state.db_pool).await {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            tracing::error!("Failed to query unfinished jobs: {}", e);
+            return;
+        }
+    };
+
+    for job in jobs {
+        resume_job(&state, job).await;
+    }
+}
+
+/// Restart one unfinished job in the background. A job whose payment already settled is
+/// finished without generating or settling again.
+pub async fn resume_job(state: &web::Data<AppState>, job: JobRecord) {
+    let Some(endpoint) = state.endpoints.iter().find(|ep| ep.path == job.endpoint_path) else {
+        tracing::warn!("[job {}] Endpoint {} no longer exists", job.id, job.endpoint_path);
+        fail_job(state, job.id, "Endpoint no longer exists").await;
+        return;
+    };
+    // Jobs from before payloads were recorded on them, so their payment is never taken as stale
+    if let (None, Some(payment)) = (&job.payload_hash, job_payment(&job)) {
+        let hash = x402::payload_hash(&payment.request.payment_payload);
+        if let Err(e) = db::set_job_payload_hash(&state.db_pool, job.id, &hash).await {
+            tracing::error!("[job {}] Failed to record payment payload: {}", job.id, e);
+        }
+    }
+    tracing::info!("[job {}] Resuming ({})", job.id, job.status);
+    state.events.open(job.id);
+    tokio::spawn(run_job(state.clone(), job, endpoint.clone()));
+}
+
+/// The request a job was created for; jobs from before cache keys existed rebuild it.
+fn job_request(job: &JobRecord, endpoint: &EndpointDef) -> GenerationRequest {
+    match &job.request_body {
+        Some(body) => GenerationRequest::from_body(endpoint, &job.prompt, body.clone()),
+        None => GenerationRequest::new(endpoint, &job.prompt, &serde_json::Map::new()),
+    }
+}
+
+async fn run_job(state: web::Data<AppState>, job: JobRecord, endpoint: EndpointDef) {
+    let job_id = job.id;
+    let request = job_request(&job, &endpoint);
+
+    // A previous run may have settled before it died: never generate or settle it twice
+    let settled = match job_payment(&job) {
+        Some(payment) => match x402::find_settlement(&state.db_pool, &payment).await {
+            Ok(settled) => settled,
+            Err(e) => {
+                // It may have been charged, so the payment is neither released nor settled again
+                fail_job(&state, job_id, &e).await;
+                return;
+            }
+        },
+        None => None,
+    };
+    if let Some(settlement) = &settled {
+        let recorded = match settlement.payment_id {
+            Some(payment_id) => db::find_media_by_payment(&state.db_pool, payment_id)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::error!("[job {}] Failed to load paid media: {}", job_id, e);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        if !recorded.is_empty() {
+            tracing::info!("[job {}] Already paid and generated, finishing from recorded media", job_id);
+            let model = recorded[0].model.clone().or_else(|| job.model.clone()).unwrap_or_default();
+            let urls = recorded.into_iter().map(|m| m.s3_url).collect();
+            succeed_job(&state, &job, &endpoint, model, urls, Some(settlement)).await;
+            return;
+        }
+    }
+
+    let generated = match generate(&state, &job, &endpoint, &request).await {
+        Ok(generated) => generated,
+        Err(e) => {
+            // Generation failed: the verified payment is never settled, debited credits go back
+            if let Some(payment) = job_payment(&job) {
+                x402::release_payment(&state.db_pool, &payment).await;
+            }
+            if let Some(debit_id) = job.credit_debit_id {
+                credits::refund(&state.db_pool, debit_id).await;
+            }
+            pipeline::record_failure(
+                &state,
+                &endpoint,
+                &job.prompt,
+                Some(job_id),
+                job.payer_address.as_deref(),
+                &e,
+            )
+            .await;
+            fail_job(&state, job_id, &e).await;
+            return;
+        }
+    };
+
+    let settlement = match settled {
+        Some(settlement) => Some(settlement),
+        None => match settle_job_payment(&state, &job).await {
+            Ok(settlement) => settlement,
+            Err(e) => {
+                // Keep a row so cleanup removes the media, but never serve it from the cache
+                pipeline::record_unsettled_media(&state, &endpoint, &request, &generated).await;
+                fail_job(&state, job_id, &e.to_string()).await;
+                return;
+            }
+        },
+    };
+    let payer_address = settlement
+        .as_ref()
+        .and_then(|s| s.payer.clone())
+        .or_else(|| job.payer_address.clone());
+
+    pipeline::record_media(
+        &state,
+        &endpoint,
+        &request,
+        &generated,
+        settlement.as_ref(),
+        payer_address.as_deref(),
+    )
+    .await;
+    let urls = generated.outputs.iter().map(|m| m.cdn_url.clone()).collect();
+    succeed_job(&state, &job, &endpoint, generated.model, urls, settlement.as_ref()).await;
+}
+
+/// Record a job's settlement (if paid) and its outputs, then tell subscribers and its webhook.
+async fn succeed_job(
+    state: &web::Data<AppState>,
+    job: &JobRecord,
+    endpoint: &EndpointDef,
+    model: String,
+    urls: Vec<String>,
+    settlement: Option<&SettleResponse>,
+) {
+    let job_id = job.id;
+    if let Some(settlement) = settlement {
+        let payer_address = settlement.payer.as_deref().or(job.payer_address.as_deref());
+        let receipt = serde_json::to_value(settlement).unwrap_or_default();
+        if let Err(e) = db::set_job_payment(
+            &state.db_pool,
+            job_id,
+            payer_address,
+            settlement.transaction.as_deref(),
+            &receipt,
+        )
+        .await
+        {
+            tracing::error!("[job {}] Failed to record payment: {}", job_id, e);
+        }
+    }
+
+    let response = GenerateResponse {
+        url: urls[0].clone(),
+        urls,
+        model: Some(model),
+        prompt: job.prompt.clone(),
+        cached: false,
+        media_type: endpoint.media_type.clone(),
+        quality: job.quality.clone(),
+    };
+    let result = serde_json::to_value(&response).unwrap_or_default();
+    if let Err(e) = db::mark_job_succeeded(&state.db_pool, job_id, &result).await {
+        tracing::error!("[job {}] Failed to record success: {}", job_id, e);
+    }
+    tracing::info!("[job {}] Succeeded: {}", job_id, response.url);
+    state.events.emit(job_id, JobEventKind::Succeeded { result });
+    state.events.close(job_id);
+    notify(state, job_id).await;
+}
+
+async fn fail_job(state: &web::Data<AppState>, job_id: Uuid, error: &str) {
+    tracing::error!("[job {}] Failed: {}", job_id, error);
+    if let Err(e) = db::mark_job_failed(&state.db_pool, job_id, error).await {
+        tracing::error!("[job {}] Failed to record failure: {}", job_id, e);
+    }
+    state.events.emit(
+        job_id,
+        JobEventKind::Failed {
+            error: error.to_string(),
+        },
+    );
+    state.events.close(job_id);
+    notify(state, job_id).await;
+}
+
+/// Send a finished job's webhook, if it asked for one.
+pub async fn notify(state: &web::Data<AppState>, job_id: Uuid) {
+    match db::find_job(&state.db_pool, job_id).await {
+        Ok(Some(job)) => webhooks::notify_job(state, job).await,
+        Ok(None) => {}
+        Err(e) => tracing::error!("[job {}] Failed to load job for webhook: {}", job_id, e),
+    }
+}
+
+/// The payment verified when the job was created (none in TEST_MODE).
+fn job_payment(job: &JobRecord) -> Option<VerifiedPayment> {
+    serde_json::from_value(job.payment_request.clone()?).ok()
+}
+
+/// Settle the payment verified when the job was created (none in TEST_MODE).
+async fn settle_job_payment(
+    state: &AppState,
+    job: &JobRecord,
+) -> Result<Option<SettleResponse>, SettlementError> {
+    let Some(request) = &job.payment_request else {
+        return Ok(None);
+    };
+    let payment: VerifiedPayment = serde_json::from_value(request.clone())
+        .map_err(|e| SettlementError::Facilitator(format!("Corrupt payment on job: {}", e)))?;
+    x402::settle_x402_payment(&state.config, &state.http_client, &state.db_pool, &payment)
+        .await
+        .map(Some)
+}
+
+/// Run the job through the endpoint's models in order (resuming the one a previous run was
+/// waiting on) until one succeeds, then upload its outputs. All of it must fit in the endpoint's
+/// job time limit, counted from when the job was created, so the payment can still be settled.
+async fn generate(
+    state: &AppState,
+    job: &JobRecord,
+    endpoint: &EndpointDef,
+    request: &GenerationRequest,
+) -> Result<Generated, String> {
+    let elapsed = (Utc::now() - job.created_at).to_std().unwrap_or_default();
+    let deadline = Instant::now() + endpoint.job_time_limit().saturating_sub(elapsed);
+    let targets = endpoint.targets();
+    let first = job
+        .model
+        .as_deref()
+        .and_then(|model| targets.iter().position(|t| t.model == model))
+        .unwrap_or(0);
+
+    let mut errors = Vec::new();
+    for (i, target) in targets.iter().enumerate().skip(first) {
+        if Instant::now() >= deadline {
+            errors.push(format!(
+                "{}: not tried, the job ran out of its {}s limit",
+                target.model,
+                endpoint.job_time_limit().as_secs()
+            ));
+            break;
+        }
+        let resume = if i == first { job.provider_request.as_ref() } else { None };
+        // A request already at the provider is followed up whatever the circuit says
+        if resume.is_none() && !state.breakers.try_call(target.model) {
+            errors.push(format!("{}: circuit open", target.model));
+            continue;
+        }
+        let started = Instant::now();
+        let result = run_model(state, job, endpoint, target, request, resume, deadline).await;
+        state.breakers.record(target.model, result.is_ok(), started.elapsed());
+        match result {
+            Ok(result_urls) => {
+                let cache_key = request.cache_key_for(endpoint, target);
+                let outputs =
+                    pipeline::store_results(state, endpoint, &cache_key, &result_urls, Some(job.id)).await?;
+                return Ok(Generated {
+                    model: target.model.to_string(),
+                    cache_key,
+                    outputs,
+                });
+            }
+            Err(e) => {
+                tracing::error!("[job {}] {} failed: {}", job.id, target.model, e);
+                if let Some(next) = targets.get(i + 1) {
+                    state.events.emit(
+                        job.id,
+                        JobEventKind::FallingBack {
+                            from: target.model.to_string(),
+                            to: next.model.to_string(),
+                            error: e.clone(),
+                        },
+                    );
+                }
+                errors.push(format!("{}: {}", target.model, e));
+            }
+        }
+    }
+    Err(pipeline::all_models_failed(errors))
+}
+
+/// Submit to one model's queue (or resume `existing`), wait for it until `deadline` at the
+/// latest and return its result URLs.
+async fn run_model(
+    state: &AppState,
+    job: &JobRecord,
+    endpoint: &EndpointDef,
+    target: &ModelTarget<'_>,
+    request: &GenerationRequest,
+    existing: Option<&serde_json::Value>,
+    deadline: Instant,
+) -> Result<Vec<String>, String> {
+    let provider = state
+        .providers
+        .get(target.provider)
+        .ok_or_else(|| format!("Unknown provider '{}'", target.provider))?;
+
+    // Submit to the provider's queue unless a previous run already did
+    let queued: QueuedRequest = match existing {
+        Some(existing) => serde_json::from_value(existing.clone())
+            .map_err(|e| format!("Corrupt provider request on job: {}", e))?,
+        None => {
+            let body = request.body_for(endpoint, target);
+            let queued = provider
+                .enqueue(&state.http_client, target.base_url, target.model, &body)
+                .await?;
+            let as_json = serde_json::to_value(&queued).unwrap_or_default();
+            db::mark_job_running(&state.db_pool, job.id, &as_json, target.model)
+                .await
+                .map_err(|e| format!("Failed to record provider request: {}", e))?;
+            tracing::info!("[job {}] Queued {} at {} as {}", job.id, target.model, target.provider, queued.request_id);
+            state.events.emit(
+                job.id,
+                JobEventKind::Queued {
+                    provider: target.provider.to_string(),
+                    model: target.model.to_string(),
+                    request_id: queued.request_id.clone(),
+                },
+            );
+            queued
+        }
+    };
+
+    let started = Instant::now();
+    let remaining = deadline.saturating_duration_since(started);
+    let limit = endpoint.provider_timeout().map_or(remaining, |t| t.min(remaining));
+    let mut poll_errors = 0;
+    // What subscribers last heard, so each change is streamed once
+    let mut last_position = None;
+    let mut logs_seen = None;
+    loop {
+        if started.elapsed() > limit {
+            return Err(format!("Timed out after {}s waiting for provider", limit.as_secs()));
+        }
+
+        match provider.poll(&state.http_client, &queued).await {
+            Ok(QueueStatus::Completed) => break,
+            Ok(QueueStatus::InQueue { position }) => {
+                poll_errors = 0;
+                tracing::debug!("[job {}] In queue (position {:?})", job.id, position);
+                if last_position != Some(position) {
+                    last_position = Some(position);
+                    state.events.emit(job.id, JobEventKind::InQueue { position });
+                }
+            }
+            Ok(QueueStatus::InProgress { logs }) => {
+                poll_errors = 0;
+                if let Some(last) = logs.last() {
+                    tracing::debug!("[job {}] In progress: {}", job.id, last);
+                }
+                let seen = logs_seen.unwrap_or(0);
+                if logs_seen.is_none() || logs.len() > seen {
+                    logs_seen = Some(logs.len());
+                    let logs = logs.into_iter().skip(seen).collect();
+                    state.events.emit(job.id, JobEventKind::InProgress { logs });
+                }
+            }
+            Err(e) => {
+                poll_errors += 1;
+                tracing::warn!("[job {}] Poll error ({}/{}): {}", job.id, poll_errors, MAX_POLL_ERRORS, e);
+                if poll_errors >= MAX_POLL_ERRORS {
+                    return Err(e);
+                }
+            }
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let resp_json = provider.fetch_result(&state.http_client, &queued).await?;
+    provider
+        .extract_result_urls(&resp_json, target.response_url_path)
+        .map_err(|e| format!("No result URL in {} response: {}", target.provider, e))
+}
+
+/// GET /jobs/{id}
+pub async fn get_job(state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let Ok(id) = Uuid::parse_str(&path.into_inner()) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid job id"}));
+    };
+
+    match db::find_job(&state.db_pool, id).await {
+        Ok(Some(job)) => {
+            // Once settled, every poll carries the settlement receipt
+            let settlement: Option<SettleResponse> = job
+                .payment_response
+                .clone()
+                .and_then(|r| serde_json::from_value(r).ok());
+            with_receipt(HttpResponse::Ok(), settlement.as_ref()).json(JobResponse::from(job))
+        }
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({"error": "Job not found"})),
+        Err(e) => {
+            tracing::error!("[job {}] Lookup failed: {}", id, e);
+            HttpResponse::InternalServerError().json(serde_json::json!({"error": "Job lookup failed"}))
+        }
+    }
+}
@@ -1,37 +1,106 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use actix_cors::Cors;
-use actix_governor::{Governor, GovernorConfigBuilder};
+use actix_files::Files;
+use actix_governor::governor::middleware::NoOpMiddleware;
+use actix_governor::{Governor, GovernorConfig, GovernorConfigBuilder, PeerIpKeyExtractor};
 use actix_web::{web, App, HttpResponse, HttpServer, middleware};
 use serde::Serialize;
 
+mod breaker;
 mod cleanup;
 mod config;
+mod credits;
 mod db;
 mod domain_types;
 mod endpoints;
+mod events;
 mod handler;
+mod idempotency;
+mod inputs;
+mod jobs;
+mod moderation;
+mod openapi;
+mod outbound;
+mod params;
+mod pipeline;
+mod pricing;
+mod providers;
 mod s3;
+mod storage;
+mod webhooks;
 mod x402;
 
-use config::Config;
-use endpoints::EndpointDef;
+#[cfg(test)]
+mod tests;
+
+use config::{Config, StorageConfig};
+use endpoints::{EndpointDef, QualityMap};
+use providers::ProviderRegistry;
+
+/// actix's default request body limit, kept for routes without input images.
+const DEFAULT_PAYLOAD_LIMIT: usize = 256 * 1024;
 
 pub struct AppState {
     pub config: Config,
     pub http_client: reqwest::Client,
+    /// For caller-supplied URLs (webhooks, input images); refuses non-public addresses.
+    pub outbound_client: reqwest::Client,
+    pub providers: ProviderRegistry,
     pub endpoints: Arc<Vec<EndpointDef>>,
     pub db_pool: sqlx::PgPool,
-    pub s3_client: aws_sdk_s3::Client,
+    pub storage: Arc<dyn storage::Storage>,
+    pub events: events::JobEvents,
+    pub breakers: breaker::Breakers,
+    pub moderation: moderation::Moderation,
+}
+
+#[derive(Serialize)]
+struct PriceInfo {
+    asset: String,
+    symbol: String,
+    network: String,
+    amount: String,
+    amount_raw: String,
 }
 
 #[derive(Serialize)]
 struct QualityInfo {
     quality: String,
     model: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fallbacks: Vec<String>,
     cost: String,
     cost_raw: String,
+    prices: Vec<PriceInfo>,
+    /// What a request costs when its result is already cached, if the endpoint discounts hits.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cached_prices: Vec<PriceInfo>,
     description: String,
+    mode: String,
+    allowed_params: HashMap<String, endpoints::ParamSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pricing: Option<endpoints::PricingRule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_image: Option<endpoints::InputImageSpec>,
+    /// False while every model of this quality has an open circuit; requests get a 503.
+    available: bool,
+    /// Circuit breaker state of the model and its fallbacks, in the order they are tried.
+    health: Vec<breaker::ModelStatus>,
+}
+
+fn price_infos(quotes: &[pricing::Quote]) -> Vec<PriceInfo> {
+    quotes
+        .iter()
+        .map(|quote| PriceInfo {
+            asset: quote.asset.id.clone(),
+            symbol: quote.asset.symbol.clone(),
+            network: quote.asset.network.clone(),
+            amount: quote.human.clone(),
+            amount_raw: quote.amount.to_string(),
+        })
+        .collect()
 }
 
 #[derive(Serialize)]
@@ -43,6 +112,16 @@ struct RouteInfo {
     qualities: Vec<QualityInfo>,
 }
 
+#[derive(Serialize)]
+struct AssetInfo {
+    id: String,
+    symbol: String,
+    network: String,
+    address: String,
+    decimals: u8,
+    schemes: Vec<x402::PaymentScheme>,
+}
+
 #[derive(Serialize)]
 struct InfoResponse {
     service: &'static str,
@@ -50,6 +129,7 @@ struct InfoResponse {
     routes: Vec<RouteInfo>,
     token: String,
     network: String,
+    assets: Vec<AssetInfo>,
 }
 
 async fn info(state: web::Data<AppState>) -> HttpResponse {
@@ -69,17 +149,29 @@ async fn info(state: web::Data<AppState>) -> HttpResponse {
 
         for q in &quality_keys {
             let ep = &quality_map[q];
-            let raw_cost = domain_types::DomainU256::from_human_amount(
-                &ep.cost,
-                state.config.payment_token_decimals,
-            )
-            .expect("cost validated at startup");
+            let defaults = params::defaults(ep);
+            let quotes = pricing::quotes(&state.config, ep, &defaults).expect("prices validated at startup");
+            let prices = price_infos(&quotes);
+            let cached_prices = if ep.discounts_cache_hits() {
+                price_infos(&pricing::cached_quotes(&state.config, ep, &defaults).expect("prices validated at startup"))
+            } else {
+                Vec::new()
+            };
             qualities.push(QualityInfo {
                 quality: q.clone(),
-                model: ep.fal_model.clone(),
-                cost: ep.cost.clone(),
-                cost_raw: raw_cost.to_string(),
+                model: ep.model.clone(),
+                fallbacks: ep.fallbacks.iter().map(|f| f.model.clone()).collect(),
+                cost: prices[0].amount.clone(),
+                cost_raw: prices[0].amount_raw.clone(),
+                prices,
+                cached_prices,
                 description: ep.description.clone(),
+                mode: format!("{:?}", ep.mode).to_lowercase(),
+                allowed_params: ep.allowed_params.clone(),
+                pricing: ep.pricing.clone(),
+                input_image: ep.input_image.clone(),
+                available: state.breakers.endpoint_retry_after(ep).is_none(),
+                health: ep.targets().iter().map(|t| state.breakers.status(t.model)).collect(),
             });
         }
 
@@ -96,8 +188,21 @@ async fn info(state: web::Data<AppState>) -> HttpResponse {
         service: "x402-super-router",
         version: env!("CARGO_PKG_VERSION"),
         routes,
-        token: state.config.payment_token_address.clone(),
-        network: state.config.payment_network.clone(),
+        token: state.config.primary_asset().address.clone(),
+        network: state.config.primary_asset().network.clone(),
+        assets: state
+            .config
+            .payment_assets
+            .iter()
+            .map(|a| AssetInfo {
+                id: a.id.clone(),
+                symbol: a.symbol.clone(),
+                network: a.network.clone(),
+                address: a.address.clone(),
+                decimals: a.decimals,
+                schemes: a.schemes.clone(),
+            })
+            .collect(),
     })
 }
 
@@ -105,9 +210,19 @@ async fn info_text(state: web::Data<AppState>) -> HttpResponse {
     let mut out = String::new();
     out.push_str("x402-super-router\n");
     out.push_str(&format!("version: {}\n", env!("CARGO_PKG_VERSION")));
-    out.push_str(&format!("network: {}\n", state.config.payment_network));
-    out.push_str(&format!("token: {} ({})\n", state.config.payment_token_symbol, state.config.payment_token_address));
     out.push_str(&format!("wallet: {}\n", state.config.wallet_address));
+    out.push_str("\n--- payment assets ---\n\n");
+    for asset in &state.config.payment_assets {
+        let schemes: Vec<&str> = asset.schemes.iter().map(|s| s.as_str()).collect();
+        out.push_str(&format!(
+            "  {} : {} on {} ({}) schemes: {}\n",
+            asset.id,
+            asset.symbol,
+            asset.network,
+            asset.address,
+            schemes.join(", ")
+        ));
+    }
     out.push_str("\n--- routes ---\n");
 
     let grouped = endpoints::group_by_route(&state.endpoints);
@@ -123,22 +238,66 @@ async fn info_text(state: web::Data<AppState>) -> HttpResponse {
 
         for q in &quality_keys {
             let ep = &quality_map[q];
-            let raw_cost = domain_types::DomainU256::from_human_amount(
-                &ep.cost,
-                state.config.payment_token_decimals,
-            )
-            .expect("cost validated at startup");
+            let quotes = pricing::quotes(&state.config, ep, &params::defaults(ep)).expect("prices validated at startup");
+            let prices: Vec<String> = quotes
+                .iter()
+                .map(|quote| format!("{} {} on {} (raw: {})", quote.human, quote.asset.symbol, quote.asset.network, quote.amount))
+                .collect();
             out.push_str(&format!(
-                "    {} : {} {} (model: {}, raw: {})\n",
-                q, ep.cost, state.config.payment_token_symbol, ep.fal_model, raw_cost
+                "    {} : {} (model: {}, mode: {:?})\n",
+                q, prices.join(" | "), ep.model, ep.mode
             ));
+            if let Some(retry_after) = state.breakers.endpoint_retry_after(ep) {
+                out.push_str(&format!("      UNAVAILABLE: provider failing, retry in {}s\n", retry_after));
+            }
+            if !ep.fallbacks.is_empty() {
+                let models: Vec<&str> = ep.fallbacks.iter().map(|f| f.model.as_str()).collect();
+                out.push_str(&format!("      fallbacks: {}\n", models.join(" -> ")));
+            }
+            if ep.discounts_cache_hits() {
+                let cached = pricing::cached_quotes(&state.config, ep, &params::defaults(ep))
+                    .expect("prices validated at startup");
+                let prices: Vec<String> = cached
+                    .iter()
+                    .map(|quote| format!("{} {}", quote.human, quote.asset.symbol))
+                    .collect();
+                out.push_str(&format!("      cache hits: {}\n", prices.join(" | ")));
+            }
+            if let Some(rule) = &ep.pricing {
+                let mut terms: Vec<String> = rule.per_unit.iter().map(|(p, u)| format!("{} × {}", p, u)).collect();
+                terms.sort();
+                out.push_str(&format!(
+                    "      price = unit price × ({} + {}), shown for the defaults\n",
+                    rule.base,
+                    terms.join(" + ")
+                ));
+            }
+            if !ep.allowed_params.is_empty() {
+                let mut names: Vec<&String> = ep.allowed_params.keys().collect();
+                names.sort();
+                out.push_str(&format!("      params: {:?}\n", names));
+            }
+            if let Some(input) = &ep.input_image {
+                out.push_str(&format!(
+                    "      input image: multipart '{}' or '{}' ({}, up to {} bytes)\n",
+                    inputs::IMAGE_FILE_FIELD,
+                    inputs::IMAGE_URL_FIELD,
+                    if input.required { "required" } else { "optional" },
+                    input.max_bytes
+                ));
+            }
         }
     }
 
     out.push_str("\n--- payment ---\n\n");
     out.push_str("  Send a POST request with JSON body to any route above.\n");
     out.push_str("  Without an X-PAYMENT header, you'll receive a 402 with payment requirements.\n");
-    out.push_str("  Include a valid X-PAYMENT header (base64-encoded permit) to generate content.\n");
+    out.push_str("  Include a valid X-PAYMENT header (base64-encoded x402 payment payload) to generate content.\n");
+    out.push_str("  Prompts are checked against the content policy first; rejected ones get a 400 and are never charged.\n");
+    out.push_str("  Routes with params accept them as extra JSON fields, e.g. {\"prompt\": \"...\", \"seed\": 42}.\n");
+    out.push_str("  Image-to-image/video routes take the input image as a multipart upload or an image_url field.\n");
+    out.push_str("  Async routes answer 202 with a job id; poll GET /jobs/{id} for the result.\n");
+    out.push_str("  OpenAPI 3 spec (with x402 payment requirements): GET /openapi.json\n");
     HttpResponse::Ok()
         .content_type("text/plain")
         .body(out)
@@ -148,6 +307,52 @@ async fn health() -> HttpResponse {
     HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
 }
 
+/// Register every route the router serves. Shared by the server and the test harness.
+fn configure_routes(
+    cfg: &mut web::ServiceConfig,
+    grouped: &HashMap<String, QualityMap>,
+    governor_conf: &GovernorConfig<PeerIpKeyExtractor, NoOpMiddleware>,
+    storage_config: &StorageConfig,
+) {
+    cfg.route("/", web::get().to(info_text))
+        .route("/api", web::get().to(info))
+        .route("/api/health", web::get().to(health))
+        .route("/openapi.json", web::get().to(openapi::openapi_json))
+        .route("/jobs/{id}", web::get().to(jobs::get_job))
+        .route("/jobs/{id}/events", web::get().to(events::job_events))
+        .route("/credits", web::get().to(credits::get_credits))
+        .route("/credits/token", web::delete().to(credits::revoke_token));
+    cfg.service(
+        web::resource(credits::TOPUP_ROUTE)
+            .wrap(Governor::new(governor_conf))
+            .route(web::post().to(credits::topup)),
+    );
+
+    // The local storage backend is served by the router itself
+    if let StorageConfig::Local { dir } = storage_config {
+        cfg.service(Files::new(storage::LOCAL_MEDIA_ROUTE, dir));
+    }
+
+    // Register one route per group, injecting the QualityMap as app_data
+    for (route, quality_map) in grouped {
+        let qm_data = web::Data::new(quality_map.clone());
+        // Routes taking input images need room for the upload (plus form fields)
+        let payload_limit = quality_map
+            .values()
+            .filter_map(|ep| ep.input_image.as_ref())
+            .map(|input| input.max_bytes + 1024 * 1024)
+            .max()
+            .unwrap_or(DEFAULT_PAYLOAD_LIMIT);
+        cfg.service(
+            web::resource(route)
+                .app_data(qm_data)
+                .app_data(web::PayloadConfig::new(payload_limit))
+                .wrap(Governor::new(governor_conf))
+                .route(web::post().to(handler::handle_generate)),
+        );
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().ok();
@@ -162,20 +367,44 @@ async fn main() -> std::io::Result<()> {
     let config = Config::from_env();
     let port = config.port;
 
+    if config.payment_assets.is_empty() {
+        panic!("At least one payment asset must be configured");
+    }
+    for asset in &config.payment_assets {
+        if asset.schemes.is_empty() {
+            panic!("Payment asset '{}' must accept at least one scheme (permit, exact)", asset.id);
+        }
+    }
+
     let endpoints_config = endpoints::load_endpoints(&config.endpoints_config_path);
 
-    // Validate all costs parse at startup
+    // Validate all prices parse and reference known assets at startup
     for ep in &endpoints_config.endpoints {
-        let raw = domain_types::DomainU256::from_human_amount(&ep.cost, config.payment_token_decimals)
-            .unwrap_or_else(|e| panic!("Bad cost '{}' for endpoint {}: {}", ep.cost, ep.path, e));
-        tracing::info!(
-            "  {} [{}] cost: {} {} (raw: {})",
-            ep.route,
-            ep.quality,
-            ep.cost,
-            config.payment_token_symbol,
-            raw
-        );
+        params::check_specs(ep).unwrap_or_else(|e| panic!("{}", e));
+        pricing::check_rule(ep).unwrap_or_else(|e| panic!("{}", e));
+        for asset_id in ep.prices.keys().chain(ep.cached_prices.keys()) {
+            if config.find_asset(asset_id).is_none() {
+                panic!("Endpoint {} has a price for unknown asset '{}'", ep.path, asset_id);
+            }
+        }
+        let cached = pricing::cached_quotes(&config, ep, &params::defaults(ep)).unwrap_or_else(|e| panic!("{}", e));
+        // A hit is free only when every asset's cached price is zero
+        let free = cached.iter().filter(|q| q.amount.0.is_zero()).count();
+        if free > 0 && free < cached.len() {
+            panic!("Endpoint {} must price cache hits zero in every asset or in none", ep.path);
+        }
+        let quotes = pricing::quotes(&config, ep, &params::defaults(ep)).unwrap_or_else(|e| panic!("{}", e));
+        for quote in &quotes {
+            tracing::info!(
+                "  {} [{}] cost: {} {} on {} (raw: {})",
+                ep.route,
+                ep.quality,
+                quote.human,
+                quote.asset.symbol,
+                quote.asset.network,
+                quote.amount
+            );
+        }
     }
 
     // Group endpoints by route and validate each route has a "low" variant (the default)
@@ -186,6 +415,19 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    // Validate every endpoint (and fallback) model references a registered provider
+    let providers = ProviderRegistry::from_config(&config);
+    for ep in &endpoints_config.endpoints {
+        for target in ep.targets() {
+            let Some(provider) = providers.get(target.provider) else {
+                panic!("Unknown provider '{}' for endpoint {}", target.provider, ep.path);
+            };
+            if ep.mode == endpoints::GenerationMode::Async && !provider.supports_queue() {
+                panic!("Endpoint {} is Async but provider '{}' has no queue API", ep.path, target.provider);
+            }
+        }
+    }
+
     let endpoint_defs = Arc::new(endpoints_config.endpoints);
 
     // Initialize DB pool
@@ -193,9 +435,17 @@ async fn main() -> std::io::Result<()> {
     let db_pool = db::create_pool(&config.database_url).await;
     tracing::info!("Database connected");
 
-    // Initialize S3 client
-    let s3_client = s3::create_s3_client(&config);
-    tracing::info!("S3 client initialized (endpoint: {})", config.s3_endpoint);
+    // Media recorded before cache keys existed gets one derived from its endpoint's request body
+    pipeline::backfill_cache_keys(&db_pool, &endpoint_defs).await;
+
+    // Load the blocklist now so a bad pattern fails at startup
+    let moderation = moderation::Moderation::from_config(&config.moderation);
+
+    // Initialize media storage
+    let storage = storage::from_config(&config);
+    if let StorageConfig::Local { dir } = &config.storage {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Failed to create storage dir '{}': {}", dir, e));
+    }
 
     // Create tmp dir for ffmpeg temp files
     std::fs::create_dir_all("tmp").expect("Failed to create tmp/ directory");
@@ -204,17 +454,29 @@ async fn main() -> std::io::Result<()> {
     if config.test_mode {
         tracing::warn!("  *** TEST_MODE ENABLED — all x402 payments are bypassed ***");
     }
-    tracing::info!("  Network: {}", config.payment_network);
+    for asset in &config.payment_assets {
+        tracing::info!(
+            "  Asset {}: {} on {} ({}) schemes {:?}",
+            asset.id,
+            asset.symbol,
+            asset.network,
+            asset.address,
+            asset.schemes
+        );
+    }
     tracing::info!("  Wallet: {}", config.wallet_address);
     tracing::info!("  Facilitator: {}", config.facilitator_url);
-    tracing::info!("  S3 Bucket: {}", config.s3_bucket);
-    tracing::info!("  S3 CDN: {}", config.s3_cdn_url);
+    tracing::info!("  Storage: {}", storage.describe());
+    tracing::info!("  Moderation: {}", moderation.describe());
     tracing::info!("  Routes: {}", grouped.len());
     for (route, quality_map) in &grouped {
         let qualities: Vec<&String> = quality_map.keys().collect();
         tracing::info!("    {} -> qualities: {:?}", route, qualities);
         for (q, ep) in quality_map {
-            tracing::info!("      {} : {} ({})", q, ep.fal_model, ep.description);
+            tracing::info!("      {} : {}/{} {:?} ({})", q, ep.provider, ep.model, ep.mode, ep.description);
+            for fallback in ep.targets().iter().skip(1) {
+                tracing::info!("        fallback: {}/{}", fallback.provider, fallback.model);
+            }
         }
     }
 
@@ -222,8 +484,7 @@ async fn main() -> std::io::Result<()> {
     let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel::<()>(1);
     tokio::spawn(cleanup::run_cleanup_worker(
         db_pool.clone(),
-        s3_client.clone(),
-        config.s3_bucket.clone(),
+        storage.clone(),
         shutdown_rx,
     ));
     tracing::info!("Cleanup worker spawned");
@@ -236,13 +497,24 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
 
     let state = web::Data::new(AppState {
-        config,
         http_client: reqwest::Client::new(),
+        outbound_client: outbound::client(config.allow_private_urls),
+        providers,
         endpoints: Arc::clone(&endpoint_defs),
         db_pool,
-        s3_client,
+        storage,
+        events: events::JobEvents::default(),
+        breakers: breaker::Breakers::new(config.breaker.clone()),
+        moderation,
+        config,
     });
 
+    // Pick up async jobs interrupted by a previous shutdown
+    tokio::spawn(jobs::resume_unfinished_jobs(state.clone()));
+
+    // Retry failed job webhooks
+    tokio::spawn(webhooks::run_webhook_worker(state.clone(), shutdown_tx.subscribe()));
+
     // Build the grouped quality maps for route registration
     let grouped_for_factory = Arc::new(grouped);
 
@@ -255,32 +527,17 @@ async fn main() -> std::io::Result<()> {
             .allow_any_header()
             .expose_any_header();
 
-        let mut app = App::new()
+        App::new()
             .app_data(state.clone())
             .wrap(cors)
             .wrap(middleware::Logger::default())
-            .route("/", web::get().to(info_text))
-            .route("/api", web::get().to(info))
-            .route("/api/health", web::get().to(health));
-
-        // Register one route per group, injecting the QualityMap as app_data
-        for (route, quality_map) in grouped_for_factory.as_ref() {
-            let qm_data = web::Data::new(quality_map.clone());
-            app = app.service(
-                web::resource(route)
-                    .app_data(qm_data)
-                    .wrap(Governor::new(&governor_conf))
-                    .route(web::post().to(handler::handle_generate)),
-            );
-        }
-
-        app
+            .configure(|cfg| configure_routes(cfg, &grouped_for_factory, &governor_conf, &state.config.storage))
     })
     .bind(format!("0.0.0.0:{}", port))?
     .run()
     .await?;
 
-    // Server stopped — signal cleanup worker
+    // Server stopped — signal background workers
     tracing::info!("Shutting down");
     let _ = shutdown_tx.send(());
 
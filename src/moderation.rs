@@ -0,0 +1,204 @@
+//! Prompt moderation, run before payment is requested: a keyword/regex blocklist loaded from
+//! a RON file and, optionally, an external moderation API.
+
+use async_trait::async_trait;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::config::ModerationConfig;
+
+#[derive(Debug, Default, Deserialize)]
+struct BlocklistFile {
+    /// Words or phrases, matched case-insensitively on word boundaries with any whitespace between words.
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Regular expressions, matched case-insensitively anywhere in the text.
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+pub struct Blocklist {
+    rules: Vec<Regex>,
+}
+
+impl Blocklist {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let file: BlocklistFile = ron::from_str(content).map_err(|e| e.to_string())?;
+        let mut sources: Vec<String> = file.patterns;
+        let keywords: Vec<String> = file
+            .keywords
+            .iter()
+            .filter(|k| !k.trim().is_empty())
+            .map(|k| k.split_whitespace().map(regex::escape).collect::<Vec<_>>().join(r"\s+"))
+            .collect();
+        if !keywords.is_empty() {
+            sources.push(format!(r"\b(?:{})\b", keywords.join("|")));
+        }
+        let rules = sources
+            .iter()
+            .map(|source| {
+                RegexBuilder::new(source)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("Invalid pattern '{}': {}", source, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn load(path: &str) -> Self {
+        let content = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read moderation blocklist '{}': {}", path, e));
+        Self::parse(&content).unwrap_or_else(|e| panic!("Failed to parse moderation blocklist '{}': {}", path, e))
+    }
+
+    /// The first blocked fragment of `text`, if any.
+    fn find<'t>(&self, text: &'t str) -> Option<&'t str> {
+        self.rules.iter().find_map(|rule| rule.find(text)).map(|m| m.as_str())
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// What an external moderation service thinks of a text.
+pub enum Verdict {
+    Allowed,
+    Flagged { categories: Vec<String> },
+}
+
+/// An external moderation API (OpenAI, a self-hosted classifier, ...).
+#[async_trait]
+pub trait ModerationProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn check(&self, http_client: &reqwest::Client, text: &str) -> Result<Verdict, String>;
+}
+
+/// OpenAI's moderation endpoint (`/v1/moderations`).
+pub struct OpenAiModeration {
+    url: String,
+    api_key: String,
+}
+
+#[async_trait]
+impl ModerationProvider for OpenAiModeration {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn check(&self, http_client: &reqwest::Client, text: &str) -> Result<Verdict, String> {
+        let resp = http_client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&serde_json::json!({ "input": text }))
+            .send()
+            .await
+            .map_err(|e| format!("Moderation request failed: {}", e))?;
+        let status = resp.status();
+        let body: serde_json::Value = resp
+            .json()
+            .await
+            .map_err(|e| format!("Invalid moderation response: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("Moderation API returned {}: {}", status, body));
+        }
+
+        let result = &body["results"][0];
+        if !result["flagged"].as_bool().ok_or("Moderation response has no 'flagged'")? {
+            return Ok(Verdict::Allowed);
+        }
+        let mut categories: Vec<String> = result["categories"]
+            .as_object()
+            .map(|c| c.iter().filter(|(_, v)| v.as_bool() == Some(true)).map(|(k, _)| k.clone()).collect())
+            .unwrap_or_default();
+        categories.sort();
+        Ok(Verdict::Flagged { categories })
+    }
+}
+
+/// Why a request was refused by moderation.
+pub enum ModerationError {
+    Blocked(String),
+    /// The external provider could not be asked; requests are refused rather than served unchecked.
+    Unavailable(String),
+}
+
+impl ModerationError {
+    pub fn to_response(&self) -> actix_web::HttpResponse {
+        match self {
+            ModerationError::Blocked(reason) => actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Prompt rejected by content policy",
+                "reason": reason,
+            })),
+            ModerationError::Unavailable(e) => actix_web::HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "error": format!("Content moderation is unavailable: {}", e),
+            })),
+        }
+    }
+}
+
+pub struct Moderation {
+    blocklist: Option<Blocklist>,
+    provider: Option<Box<dyn ModerationProvider>>,
+}
+
+impl Moderation {
+    pub fn from_config(config: &ModerationConfig) -> Self {
+        let provider: Option<Box<dyn ModerationProvider>> = match config.provider.as_deref() {
+            None => None,
+            Some("openai") => Some(Box::new(OpenAiModeration {
+                url: config.url.clone(),
+                api_key: config
+                    .api_key
+                    .clone()
+                    .expect("MODERATION_API_KEY must be set for MODERATION_PROVIDER=openai"),
+            })),
+            Some(other) => panic!("Unknown MODERATION_PROVIDER '{}' (supported: openai)", other),
+        };
+        Self {
+            blocklist: config.blocklist_path.as_deref().map(Blocklist::load),
+            provider,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let blocklist = match &self.blocklist {
+            Some(list) => format!("blocklist ({} rules)", list.rule_count()),
+            None => "no blocklist".to_string(),
+        };
+        match &self.provider {
+            Some(provider) => format!("{} + {}", blocklist, provider.name()),
+            None => blocklist,
+        }
+    }
+
+    /// Check the caller's texts (prompt and string params), blocklist first.
+    pub async fn check(&self, http_client: &reqwest::Client, texts: &[&str]) -> Result<(), ModerationError> {
+        let texts: Vec<&str> = texts.iter().copied().filter(|t| !t.trim().is_empty()).collect();
+        if let Some(blocklist) = &self.blocklist {
+            for text in &texts {
+                if let Some(term) = blocklist.find(text) {
+                    return Err(ModerationError::Blocked(format!("contains blocked term '{}'", term)));
+                }
+            }
+        }
+        let Some(provider) = &self.provider else { return Ok(()) };
+        if texts.is_empty() {
+            return Ok(());
+        }
+        match provider.check(http_client, &texts.join("\n")).await {
+            Ok(Verdict::Allowed) => Ok(()),
+            Ok(Verdict::Flagged { categories }) if categories.is_empty() => {
+                Err(ModerationError::Blocked(format!("flagged by {}", provider.name())))
+            }
+            Ok(Verdict::Flagged { categories }) => Err(ModerationError::Blocked(format!(
+                "flagged by {} for {}",
+                provider.name(),
+                categories.join(", ")
+            ))),
+            Err(e) => Err(ModerationError::Unavailable(e)),
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::BTreeMap;
+
+use actix_web::{web, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::endpoints::{EndpointDef, GenerationMode, ParamSpec};
+use crate::inputs;
+use crate::params;
+use crate::pricing;
+use crate::x402;
+
+/// OpenAPI 3 document for the generation routes in endpoints.ron, the jobs API and the x402 flow.
+/// Each generation operation carries an `x-x402` extension with the exact payment requirements
+/// (the `accepts` list of the 402 response) for every quality.
+pub fn build_document(config: &Config, endpoints: &[EndpointDef]) -> Value {
+    // route -> quality -> endpoint, sorted so the document is stable
+    let mut routes: BTreeMap<&str, BTreeMap<&str, &EndpointDef>> = BTreeMap::new();
+    for ep in endpoints {
+        routes.entry(&ep.route).or_default().insert(&ep.quality, ep);
+    }
+
+    let mut paths = serde_json::Map::new();
+    for (route, qualities) in &routes {
+        paths.insert(route.to_string(), json!({ "post": generate_operation(config, route, qualities) }));
+    }
+
+    paths.insert(
+        "/jobs/{id}".to_string(),
+        json!({
+            "get": {
+                "operationId": "get_job",
+                "summary": "Status of an async generation job",
+                "parameters": [{
+                    "name": "id",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string", "format": "uuid" }
+                }],
+                "responses": {
+                    "200": {
+                        "description": "The job. Once settled, carries the payment receipt.",
+                        "headers": { "X-PAYMENT-RESPONSE": { "$ref": "#/components/headers/X-PAYMENT-RESPONSE" } },
+                        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/JobResponse" } } }
+                    },
+                    "400": error_response("Invalid job id"),
+                    "404": error_response("Job not found")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/jobs/{id}/events".to_string(),
+        json!({
+            "get": {
+                "operationId": "job_events",
+                "summary": "Server-Sent Events stream of a job's progress",
+                "description": "Events: payment_verified, queued, falling_back, in_queue, in_progress, downloading, post_processing, \
+                    uploaded, then succeeded or failed, after which the stream ends. Each `data` is JSON with `type`, \
+                    `seq`, `progress` (0-1, by stage) and `at`. Past events are replayed; send `Last-Event-ID` to resume.",
+                "parameters": [
+                    {
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string", "format": "uuid" }
+                    },
+                    {
+                        "name": "Last-Event-ID",
+                        "in": "header",
+                        "required": false,
+                        "schema": { "type": "integer" }
+                    }
+                ],
+                "responses": {
+                    "200": { "description": "Event stream", "content": { "text/event-stream": { "schema": { "type": "string" } } } },
+                    "400": error_response("Invalid job id"),
+                    "404": error_response("Job not found")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/credits/topup".to_string(),
+        json!({
+            "post": {
+                "operationId": "topup_credits",
+                "summary": "Buy prepaid credits with one x402 payment",
+                "description": "Without X-PAYMENT, answers 402 with the requirements for `amount` of `asset`. Once \
+                    settled, the amount is added to the payer's balance and a bearer token is returned (unless the \
+                    request already carries one of the same payer).",
+                "security": [{ "x402": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "required": ["amount"],
+                        "properties": {
+                            "amount": { "type": "string", "description": "Human-readable amount, e.g. \"100\"" },
+                            "asset": { "type": "string", "description": "Asset id; defaults to the primary asset" }
+                        }
+                    } } }
+                },
+                "responses": {
+                    "200": {
+                        "description": "Credited: `payer`, `asset`, `credited`, `balances` and, when issued, `token`",
+                        "headers": { "X-PAYMENT-RESPONSE": { "$ref": "#/components/headers/X-PAYMENT-RESPONSE" } },
+                        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Credits" } } }
+                    },
+                    "400": error_response("Invalid amount or asset, or below the asset's minimum top-up"),
+                    "402": {
+                        "description": "Payment required or invalid",
+                        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/PaymentRequiredResponse" } } }
+                    },
+                    "409": text_error("This X-PAYMENT payload was already settled or is in use by another request"),
+                    "502": text_error("Facilitator unreachable or settlement failed")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/credits".to_string(),
+        json!({
+            "get": {
+                "operationId": "get_credits",
+                "summary": "Prepaid balances of the bearer token's payer",
+                "security": [{ "credits": [] }],
+                "responses": {
+                    "200": {
+                        "description": "`payer` and `balances`",
+                        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Credits" } } }
+                    },
+                    "401": error_response("Missing, unknown or revoked credit token")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/credits/token".to_string(),
+        json!({
+            "delete": {
+                "operationId": "revoke_credit_token",
+                "summary": "Revoke the bearer token; the balance stays with the payer",
+                "security": [{ "credits": [] }],
+                "responses": {
+                    "204": { "description": "Revoked" },
+                    "401": error_response("Missing, unknown or already revoked credit token")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/api/health".to_string(),
+        json!({
+            "get": {
+                "operationId": "health",
+                "summary": "Health check",
+                "responses": { "200": { "description": "Service is up" } }
+            }
+        }),
+    );
+
+    let assets: Vec<Value> = config
+        .payment_assets
+        .iter()
+        .map(|a| {
+            json!({
+                "id": a.id,
+                "symbol": a.symbol,
+                "network": a.network,
+                "address": a.address,
+                "decimals": a.decimals,
+                "schemes": a.schemes,
+            })
+        })
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "x402-super-router",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Payment-gated AI media generation. Requests without an X-PAYMENT header are answered \
+                with 402 and the accepted payment requirements; retry with a base64-encoded x402 payment payload \
+                in X-PAYMENT. Successful paid responses carry an X-PAYMENT-RESPONSE settlement receipt."
+        },
+        "servers": [{ "url": config.public_url }],
+        "x-x402-assets": assets,
+        "paths": paths,
+        "components": components(),
+    })
+}
+
+fn generate_operation(config: &Config, route: &str, qualities: &BTreeMap<&str, &EndpointDef>) -> Value {
+    let quality_names: Vec<&str> = qualities.keys().copied().collect();
+    let low = qualities.get("low").copied().unwrap_or_else(|| qualities.values().next().unwrap());
+    let has_sync = qualities.values().any(|ep| ep.mode == GenerationMode::Sync);
+    let has_async = qualities.values().any(|ep| ep.mode == GenerationMode::Async);
+
+    let mut x402_qualities = serde_json::Map::new();
+    for (quality, ep) in qualities {
+        let quotes = pricing::quotes(config, ep, &params::defaults(ep)).expect("prices validated at startup");
+        let accepts = x402::build_accepts(config, &quotes, &ep.path, &ep.description, ep.payment_timeout_seconds());
+        let mut info = json!({
+            "model": ep.model,
+            "fallbacks": ep.fallbacks.iter().map(|f| &f.model).collect::<Vec<_>>(),
+            "mode": format!("{:?}", ep.mode).to_lowercase(),
+            "description": ep.description,
+            "resource": ep.path,
+            "allowed_params": ep.allowed_params.keys().collect::<std::collections::BTreeSet<_>>(),
+            "pricing": ep.pricing,
+            "input_image": ep.input_image,
+            "accepts": accepts,
+        });
+        // What the 402 offers instead when the request's result is already cached
+        if ep.discounts_cache_hits() {
+            let mut cached = pricing::cached_quotes(config, ep, &params::defaults(ep)).expect("prices validated at startup");
+            for quote in &mut cached {
+                quote.cache_hit = Some(true);
+            }
+            info["cached_accepts"] =
+                json!(x402::build_accepts(config, &cached, &ep.path, &ep.description, ep.payment_timeout_seconds()));
+        }
+        x402_qualities.insert(quality.to_string(), info);
+    }
+
+    // Params of every quality; which quality accepts which is under x-x402
+    let (shared, by_quality) = param_schemas(qualities);
+    let mut properties = serde_json::Map::new();
+    properties.insert(
+        "prompt".to_string(),
+        json!({ "type": "string", "description": "Text prompt", "example": low.default_prompt }),
+    );
+    properties.insert(
+        "quality".to_string(),
+        json!({ "type": "string", "enum": quality_names, "default": "low" }),
+    );
+    properties.extend(shared);
+    if has_async {
+        properties.insert(
+            "callback_url".to_string(),
+            json!({
+                "type": "string",
+                "format": "uri",
+                "description": "Async qualities only: the finished job is POSTed here, signed with X-Webhook-Signature"
+            }),
+        );
+    }
+    let takes_image = qualities.values().any(|ep| ep.input_image.is_some());
+    if takes_image {
+        properties.insert(
+            inputs::IMAGE_URL_FIELD.to_string(),
+            json!({ "type": "string", "format": "uri", "description": "Input image URL (PNG, JPEG, GIF or WebP)" }),
+        );
+    }
+    let object_schema = |properties: serde_json::Map<String, Value>| {
+        let mut schema = json!({ "type": "object", "properties": properties });
+        if !by_quality.is_empty() {
+            schema["oneOf"] = json!(by_quality);
+        }
+        schema
+    };
+    let request_schema = object_schema(properties.clone());
+
+    // Uploads go as multipart form fields, with the image file under `image`
+    let mut content = serde_json::Map::new();
+    content.insert("application/json".to_string(), json!({ "schema": request_schema }));
+    if takes_image {
+        let mut form = properties;
+        form.insert(
+            inputs::IMAGE_FILE_FIELD.to_string(),
+            json!({ "type": "string", "format": "binary", "description": "Input image file" }),
+        );
+        content.insert("multipart/form-data".to_string(), json!({ "schema": object_schema(form) }));
+    }
+
+    let mut responses = serde_json::Map::new();
+    if has_sync {
+        responses.insert(
+            "200".to_string(),
+            json!({
+                "description": "Generated (or cached) media",
+                "headers": credit_headers(),
+                "content": { "application/json": { "schema": { "$ref": "#/components/schemas/GenerateResponse" } } }
+            }),
+        );
+    }
+    if has_async {
+        responses.insert(
+            "202".to_string(),
+            json!({
+                "description": "Job accepted; poll its status_url. Cache hits are returned as already succeeded jobs.",
+                "headers": credit_headers(),
+                "content": { "application/json": { "schema": { "$ref": "#/components/schemas/JobResponse" } } }
+            }),
+        );
+    }
+    responses.insert(
+        "400".to_string(),
+        json!({
+            "description": "Invalid body, quality or params (JSON; params errors list each one under `details`), \
+                prompt rejected by the content policy (JSON, with a `reason`), or malformed X-PAYMENT header (text)",
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+                "text/plain": { "schema": { "type": "string" } }
+            }
+        }),
+    );
+    responses.insert(
+        "402".to_string(),
+        json!({
+            "description": "Payment required or invalid. `accepts` lists every asset and scheme the route takes. \
+                With a credit token: the balance doesn't cover the price (`error`, `prices`, `balances`, `topup`).",
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/PaymentRequiredResponse" } } }
+        }),
+    );
+    responses.insert("401".to_string(), error_response("Unknown or revoked credit token"));
+    responses.insert(
+        "409".to_string(),
+        json!({
+            "description": "This X-PAYMENT payload was already settled (the transaction is named) or is in use by \
+                another request (text), or a request with this Idempotency-Key is still in progress (JSON, with \
+                `Retry-After`)",
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+                "text/plain": { "schema": { "type": "string" } }
+            }
+        }),
+    );
+    responses.insert(
+        "422".to_string(),
+        json!({
+            "description": "This Idempotency-Key was already used for a different request",
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+        }),
+    );
+    responses.insert("429".to_string(), json!({ "description": "Rate limited" }));
+    responses.insert(
+        "503".to_string(),
+        json!({
+            "description": "Every model of the quality is failing (see `Retry-After` and `available_qualities`), \
+                or content moderation is unreachable. Sent before payment.",
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+        }),
+    );
+    responses.insert("500".to_string(), text_error("Generation failed; the payment was not settled"));
+    responses.insert("502".to_string(), text_error("Facilitator unreachable or settlement failed"));
+
+    json!({
+        "operationId": route.trim_start_matches('/').replace('/', "_"),
+        "summary": low.description,
+        "parameters": [
+            {
+                "name": "X-PAYMENT",
+                "in": "header",
+                "required": false,
+                "description": "Base64-encoded x402 payment payload",
+                "schema": { "type": "string" }
+            },
+            {
+                "name": "Idempotency-Key",
+                "in": "header",
+                "required": false,
+                "description": "Paid requests only. A retry with the same key from the same payer gets the first \
+                    request's response (status, body, X-PAYMENT-RESPONSE or X-Credit-* headers) with \
+                    `Idempotent-Replayed: true`, and is not charged or debited again.",
+                "schema": { "type": "string", "maxLength": 255 }
+            },
+            {
+                "name": "prompt",
+                "in": "query",
+                "required": false,
+                "description": "Used when the request has no body",
+                "schema": { "type": "string" }
+            },
+            {
+                "name": "quality",
+                "in": "query",
+                "required": false,
+                "description": "Used when the request has no body",
+                "schema": { "type": "string", "enum": quality_names, "default": "low" }
+            }
+        ],
+        "requestBody": {
+            "required": false,
+            "content": content
+        },
+        "responses": responses,
+        "security": [{}, { "x402": [] }, { "credits": [] }],
+        "x-x402": { "qualities": x402_qualities },
+    })
+}
+
+/// Schemas of the route's params: those every quality declaring them declares alike, and one
+/// `oneOf` variant per group of qualities for params whose schema depends on the quality.
+/// A variant only matches its own `quality` values; the one holding the default "low" also
+/// matches requests without a quality.
+fn param_schemas(qualities: &BTreeMap<&str, &EndpointDef>) -> (serde_json::Map<String, Value>, Vec<Value>) {
+    let mut declared: BTreeMap<&str, BTreeMap<&str, Value>> = BTreeMap::new();
+    for (quality, ep) in qualities {
+        for (name, spec) in &ep.allowed_params {
+            declared.entry(name).or_default().insert(quality, param_schema(spec));
+        }
+    }
+
+    let mut shared = serde_json::Map::new();
+    let mut varying = Vec::new();
+    for (name, schemas) in declared {
+        let mut distinct = schemas.values();
+        let first = distinct.next().expect("declared by some quality");
+        if distinct.all(|schema| schema == first) {
+            shared.insert(name.to_string(), first.clone());
+        } else {
+            varying.push((name, schemas));
+        }
+    }
+    if varying.is_empty() {
+        return (shared, Vec::new());
+    }
+
+    // Qualities that declare every varying param the same way share a variant
+    let mut groups: Vec<(Vec<&str>, serde_json::Map<String, Value>)> = Vec::new();
+    for quality in qualities.keys() {
+        let mut properties = serde_json::Map::new();
+        for (name, schemas) in &varying {
+            if let Some(schema) = schemas.get(quality) {
+                properties.insert(name.to_string(), schema.clone());
+            }
+        }
+        match groups.iter_mut().find(|(_, p)| *p == properties) {
+            Some((names, _)) => names.push(quality),
+            None => groups.push((vec![quality], properties)),
+        }
+    }
+    let variants = groups
+        .into_iter()
+        .map(|(names, mut properties)| {
+            let is_default = names.contains(&"low");
+            properties.insert("quality".to_string(), json!({ "type": "string", "enum": names }));
+            let mut variant = json!({ "type": "object", "properties": properties });
+            if !is_default {
+                variant["required"] = json!(["quality"]);
+            }
+            variant
+        })
+        .collect();
+    (shared, variants)
+}
+
+fn param_schema(spec: &ParamSpec) -> Value {
+    let mut schema = json!({ "type": spec.kind.json_type() });
+    if let Some(description) = &spec.description {
+        schema["description"] = json!(description);
+    }
+    if !spec.one_of.is_empty() {
+        schema["enum"] = json!(spec.one_of);
+    }
+    if let Some(min) = spec.min {
+        schema["minimum"] = json!(min);
+    }
+    if let Some(max) = spec.max {
+        schema["maximum"] = json!(max);
+    }
+    if let Some(default) = &spec.default {
+        schema["default"] = default.clone();
+    }
+    if !spec.price_multipliers.is_empty() {
+        schema["x-price-multipliers"] = json!(spec.price_multipliers);
+    }
+    schema
+}
+
+/// Headers of a paid response: the x402 receipt, or the balance left when paid with credits.
+fn credit_headers() -> Value {
+    json!({
+        "X-PAYMENT-RESPONSE": { "$ref": "#/components/headers/X-PAYMENT-RESPONSE" },
+        "X-Credit-Balance": { "$ref": "#/components/headers/X-Credit-Balance" },
+        "X-Credit-Asset": { "$ref": "#/components/headers/X-Credit-Asset" }
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+    })
+}
+
+fn text_error(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } }
+    })
+}
+
+fn components() -> Value {
+    json!({
+        "securitySchemes": {
+            "x402": { "type": "apiKey", "in": "header", "name": "X-PAYMENT" },
+            "credits": {
+                "type": "http",
+                "scheme": "bearer",
+                "description": "Token from POST /credits/topup; requests are paid from the prepaid balance"
+            }
+        },
+        "headers": {
+            "X-PAYMENT-RESPONSE": {
+                "description": "Base64-encoded JSON SettleResponse for the settled payment",
+                "schema": { "type": "string" }
+            },
+            "X-Credit-Balance": {
+                "description": "Remaining prepaid balance (human-readable) after a request paid with credits",
+                "schema": { "type": "string" }
+            },
+            "X-Credit-Asset": {
+                "description": "Asset the credits were debited in",
+                "schema": { "type": "string" }
+            }
+        },
+        "schemas": {
+            "GenerateResponse": {
+                "type": "object",
+                "required": ["url", "urls", "prompt", "cached", "type", "quality"],
+                "properties": {
+                    "url": { "type": "string", "format": "uri", "description": "The first output" },
+                    "urls": {
+                        "type": "array",
+                        "items": { "type": "string", "format": "uri" },
+                        "description": "Every output, e.g. one per image when num_images > 1"
+                    },
+                    "model": {
+                        "type": "string",
+                        "description": "The model that produced the result; a fallback if the primary one failed"
+                    },
+                    "prompt": { "type": "string" },
+                    "cached": { "type": "boolean" },
+                    "type": { "type": "string", "description": "Media type, e.g. image, gif, video" },
+                    "quality": { "type": "string" }
+                }
+            },
+            "JobResponse": {
+                "type": "object",
+                "required": ["id", "status", "status_url", "endpoint", "quality", "created_at", "updated_at"],
+                "properties": {
+                    "id": { "type": "string", "format": "uuid" },
+                    "status": { "type": "string", "enum": ["pending", "running", "succeeded", "failed"] },
+                    "status_url": { "type": "string" },
+                    "endpoint": { "type": "string" },
+                    "quality": { "type": "string" },
+                    "result": { "$ref": "#/components/schemas/GenerateResponse" },
+                    "error": { "type": "string" },
+                    "created_at": { "type": "string", "format": "date-time" },
+                    "updated_at": { "type": "string", "format": "date-time" }
+                }
+            },
+            "PaymentRequiredResponse": {
+                "type": "object",
+                "required": ["x402Version", "accepts"],
+                "properties": {
+                    "x402Version": { "type": "integer" },
+                    "accepts": { "type": "array", "items": { "$ref": "#/components/schemas/PaymentRequirements" } },
+                    "error": { "type": "string", "nullable": true }
+                }
+            },
+            "PaymentRequirements": {
+                "type": "object",
+                "required": ["scheme", "network", "maxAmountRequired", "resource", "description", "mimeType", "payTo", "maxTimeoutSeconds", "asset"],
+                "properties": {
+                    "scheme": { "type": "string", "enum": ["permit", "exact"] },
+                    "network": { "type": "string" },
+                    "maxAmountRequired": { "type": "string", "description": "Amount in raw token units" },
+                    "resource": { "type": "string" },
+                    "description": { "type": "string" },
+                    "mimeType": { "type": "string" },
+                    "payTo": { "type": "string" },
+                    "maxTimeoutSeconds": { "type": "integer" },
+                    "asset": { "type": "string", "description": "Token contract address" },
+                    "extra": { "type": "object", "additionalProperties": true }
+                }
+            },
+            "SettleResponse": {
+                "type": "object",
+                "required": ["success", "network"],
+                "properties": {
+                    "success": { "type": "boolean" },
+                    "network": { "type": "string" },
+                    "transaction": { "type": "string", "nullable": true },
+                    "errorReason": { "type": "string", "nullable": true },
+                    "payer": { "type": "string", "nullable": true }
+                }
+            },
+            "Credits": {
+                "type": "object",
+                "required": ["payer", "balances"],
+                "properties": {
+                    "payer": { "type": "string" },
+                    "asset": { "type": "string", "description": "Top-ups only: the asset credited" },
+                    "credited": { "type": "string", "description": "Top-ups only: human-readable amount added" },
+                    "token": { "type": "string", "description": "Top-ups only: a new bearer token, shown once" },
+                    "balances": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "asset": { "type": "string" },
+                                "symbol": { "type": "string" },
+                                "balance": { "type": "string", "description": "Human-readable amount" },
+                                "balance_raw": { "type": "string", "description": "Raw token units" }
+                            }
+                        }
+                    }
+                }
+            },
+            "Error": {
+                "type": "object",
+                "properties": { "error": { "type": "string" } }
+            }
+        }
+    })
+}
+
+/// GET /openapi.json
+pub async fn openapi_json(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(build_document(&state.config, &state.endpoints))
+}
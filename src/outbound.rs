@@ -0,0 +1,156 @@
+//! Requests to URLs supplied by callers: webhook callbacks and input image URLs. They must not
+//! reach into the router's own network, so hosts resolving to loopback, private, link-local
+//! (cloud metadata) or other non-global addresses are refused — when the URL is accepted, and
+//! again by the client's resolver when connecting, in case the DNS answer changed in between.
+//! Redirects go through the same checks.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Url, redirect};
+
+const MAX_REDIRECTS: usize = 10;
+/// Hosts that take longer than this to accept a connection are given up on.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound on a whole request, body included; callers may set a shorter one per request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT)
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved
+        || a >= 240)
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_global_v4(v4);
+    }
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, which can reach any IPv4 address
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+/// Whether an address is on the public internet.
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => is_global_v6(ip),
+    }
+}
+
+fn refused(host: &str) -> String {
+    format!("{} is not a public address", host)
+}
+
+/// Resolve `host` and check every address it resolves to is public.
+async fn check_host(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Could not resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("Could not resolve {}", host));
+    }
+    if addrs.iter().any(|addr| !is_global(addr.ip())) {
+        return Err(refused(host));
+    }
+    Ok(addrs)
+}
+
+/// Check a caller-supplied URL is absolute http(s) and, unless `allow_private` is set, that its
+/// host only resolves to public addresses. `what` names the field in errors.
+pub async fn check_url(url: &str, what: &str, allow_private: bool) -> Result<Url, String> {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.host().is_some() => parsed,
+        _ => return Err(format!("{} must be an absolute http(s) URL", what)),
+    };
+    if allow_private {
+        return Ok(parsed);
+    }
+    let host = parsed.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) if !is_global(ip) => return Err(format!("{}: {}", what, refused(host))),
+        Ok(_) => {}
+        Err(_) => {
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            check_host(host, port).await.map_err(|e| format!("{}: {}", what, e))?;
+        }
+    }
+    Ok(parsed)
+}
+
+/// Resolver that fails for hosts with any non-public address, checked at connect time.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = check_host(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Redirects to non-http(s) URLs or to literal non-public addresses are not followed; redirects
+/// to hostnames are checked by the resolver.
+fn redirect_policy() -> redirect::Policy {
+    redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        let url = attempt.url();
+        if !matches!(url.scheme(), "http" | "https") {
+            return attempt.error("redirect to a non-http(s) URL");
+        }
+        let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(ip) if !is_global(ip) => {
+                let error = refused(host);
+                attempt.error(error)
+            }
+            _ => attempt.follow(),
+        }
+    })
+}
+
+/// HTTP client for caller-supplied URLs. With `allow_private` (local development and tests)
+/// it only has the timeouts. It ignores proxy settings, which would resolve hosts out of our sight.
+pub fn client(allow_private: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT);
+    if allow_private {
+        return builder.build().expect("Failed to build outbound HTTP client");
+    }
+    builder
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirect_policy())
+        .build()
+        .expect("Failed to build outbound HTTP client")
+}
@@ -0,0 +1,175 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::endpoints::{EndpointDef, ParamKind, ParamSpec};
+use crate::inputs;
+use crate::pricing;
+
+/// Request fields handled by the router itself, never passed through as provider params.
+pub const RESERVED: &[&str] = &["prompt", "quality", "callback_url", inputs::IMAGE_URL_FIELD, inputs::IMAGE_FILE_FIELD];
+
+/// One rejected param, returned in the 400 body.
+#[derive(Debug, Clone, Serialize)]
+pub struct ParamError {
+    pub param: String,
+    pub message: String,
+}
+
+/// Check caller params against the endpoint's `allowed_params`, coercing query-string values
+/// to the declared kind and filling in defaults. Returns the params to merge into the provider body.
+pub fn validate(endpoint: &EndpointDef, supplied: &Map<String, Value>) -> Result<Map<String, Value>, Vec<ParamError>> {
+    let mut errors = Vec::new();
+    let mut resolved = Map::new();
+
+    for (name, value) in supplied {
+        let Some(spec) = endpoint.allowed_params.get(name) else {
+            let mut accepted: Vec<&String> = endpoint.allowed_params.keys().collect();
+            accepted.sort();
+            errors.push(ParamError {
+                param: name.clone(),
+                message: format!("Not accepted by this endpoint. Accepted params: {:?}", accepted),
+            });
+            continue;
+        };
+        match check(spec, value) {
+            Ok(value) => {
+                resolved.insert(name.clone(), value);
+            }
+            Err(message) => errors.push(ParamError {
+                param: name.clone(),
+                message,
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by(|a, b| a.param.cmp(&b.param));
+        return Err(errors);
+    }
+
+    for (name, value) in defaults(endpoint) {
+        resolved.entry(name).or_insert(value);
+    }
+    Ok(resolved)
+}
+
+/// The declared defaults, i.e. the params of a request that sets none.
+pub fn defaults(endpoint: &EndpointDef) -> Map<String, Value> {
+    endpoint
+        .allowed_params
+        .iter()
+        .filter_map(|(name, spec)| spec.default.clone().map(|d| (name.clone(), d)))
+        .collect()
+}
+
+/// Startup check of an endpoint's `allowed_params`: no reserved names, valid defaults and
+/// `one_of` values, parseable price multipliers.
+pub fn check_specs(endpoint: &EndpointDef) -> Result<(), String> {
+    if let Some(input) = &endpoint.input_image
+        && endpoint.allowed_params.contains_key(&input.param)
+    {
+        return Err(format!(
+            "Endpoint {}: '{}' is set from the input image and cannot be an allowed param",
+            endpoint.path, input.param
+        ));
+    }
+    for (name, spec) in &endpoint.allowed_params {
+        if RESERVED.contains(&name.as_str()) {
+            return Err(format!("Endpoint {}: '{}' cannot be an allowed param", endpoint.path, name));
+        }
+        for value in &spec.one_of {
+            let unrestricted = ParamSpec {
+                one_of: Vec::new(),
+                ..spec.clone()
+            };
+            check(&unrestricted, value)
+                .map_err(|e| format!("Endpoint {} param '{}': {} is invalid: {}", endpoint.path, name, value, e))?;
+        }
+        // A request leaving the param out gets its default, else the value in request_params
+        if let Some(default) = spec.default.as_ref().or_else(|| endpoint.request_params.get(name)) {
+            check(spec, default)
+                .map_err(|e| format!("Endpoint {} param '{}': default {}", endpoint.path, name, e))?;
+        }
+        for (value, multiplier) in &spec.price_multipliers {
+            pricing::parse_multiplier(multiplier).map_err(|e| {
+                format!("Endpoint {} param '{}': price multiplier for {}: {}", endpoint.path, name, value, e)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Key a chosen value is looked up by in `price_multipliers`.
+pub fn value_key(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn check(spec: &ParamSpec, value: &Value) -> Result<Value, String> {
+    let value = coerce(spec.kind, value)?;
+
+    if !spec.one_of.is_empty() && !spec.one_of.iter().any(|allowed| same_value(allowed, &value)) {
+        let options: Vec<String> = spec.one_of.iter().map(value_key).collect();
+        return Err(format!("Must be one of: {}", options.join(", ")));
+    }
+
+    if let Some(n) = value.as_f64() {
+        if let Some(min) = spec.min
+            && n < min
+        {
+            return Err(format!("Must be at least {}", min));
+        }
+        if let Some(max) = spec.max
+            && n > max
+        {
+            return Err(format!("Must be at most {}", max));
+        }
+    }
+    Ok(value)
+}
+
+/// Accept the declared kind, or a string that parses as it (query-string params are all strings).
+fn coerce(kind: ParamKind, value: &Value) -> Result<Value, String> {
+    let text = value.as_str().map(str::trim);
+    match kind {
+        ParamKind::String => match value {
+            Value::String(_) => Ok(value.clone()),
+            _ => Err("Must be a string".to_string()),
+        },
+        ParamKind::Integer => {
+            if let Some(i) = value.as_i64() {
+                return Ok(Value::from(i));
+            }
+            text.and_then(|t| t.parse::<i64>().ok())
+                .map(Value::from)
+                .ok_or_else(|| "Must be an integer".to_string())
+        }
+        ParamKind::Number => {
+            if value.is_number() {
+                return Ok(value.clone());
+            }
+            let parsed = text.and_then(|t| {
+                t.parse::<i64>()
+                    .map(Value::from)
+                    .ok()
+                    .or_else(|| t.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number))
+            });
+            parsed.ok_or_else(|| "Must be a number".to_string())
+        }
+        ParamKind::Boolean => match (value, text) {
+            (Value::Bool(_), _) => Ok(value.clone()),
+            (_, Some("true")) => Ok(Value::Bool(true)),
+            (_, Some("false")) => Ok(Value::Bool(false)),
+            _ => Err("Must be true or false".to_string()),
+        },
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x == y,
+        _ => a == b,
+    }
+}
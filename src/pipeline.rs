@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::AppState;
+use crate::db;
+use crate::endpoints::{EndpointDef, ModelTarget, PostProcess};
+use crate::events::JobEventKind;
+use crate::x402::SettleResponse;
+
+/// Cache hash for a prompt: sha256 of the trimmed, lowercased text.
+pub fn prompt_hash(prompt: &str) -> String {
+    let mut h = Sha256::new();
+    h.update(prompt.trim().to_lowercase().as_bytes());
+    hex::encode(h.finalize())
+}
+
+/// Build the provider request body: merge request_params + validated user params + prompt.
+pub fn build_request_body(endpoint: &EndpointDef, prompt: &str, params: &Map<String, Value>) -> serde_json::Value {
+    merge_body(&endpoint.request_params, prompt, params)
+}
+
+fn merge_body(request_params: &HashMap<String, Value>, prompt: &str, params: &Map<String, Value>) -> Value {
+    let mut body_map = serde_json::Map::new();
+    for (k, v) in request_params {
+        body_map.insert(k.clone(), v.clone());
+    }
+    for (k, v) in params {
+        body_map.insert(k.clone(), v.clone());
+    }
+    body_map.insert(
+        "prompt".to_string(),
+        serde_json::Value::String(prompt.to_string()),
+    );
+    serde_json::Value::Object(body_map)
+}
+
+/// Serialize JSON with object keys sorted at every level, so equal bodies give equal strings.
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|k| format!("{}:{}", Value::String(k.clone()), canonical_json(&map[k])))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Cache key for a provider request: sha256 of the provider, model and canonical merged body.
+/// The prompt is trimmed and lowercased first so lookups stay case-insensitive, as with `prompt_hash`.
+pub fn cache_key(endpoint: &EndpointDef, body: &Value) -> String {
+    model_cache_key(&endpoint.provider, &endpoint.model, body)
+}
+
+fn model_cache_key(provider: &str, model: &str, body: &Value) -> String {
+    let mut normalized = body.clone();
+    if let Some(Value::String(prompt)) = normalized.get_mut("prompt") {
+        *prompt = prompt.trim().to_lowercase();
+    }
+
+    let mut h = Sha256::new();
+    h.update(provider.as_bytes());
+    h.update(b"\n");
+    h.update(model.as_bytes());
+    h.update(b"\n");
+    h.update(canonical_json(&normalized).as_bytes());
+    hex::encode(h.finalize())
+}
+
+/// A provider request body and the keys it is cached under.
+#[derive(Debug, Clone)]
+pub struct GenerationRequest {
+    pub prompt: String,
+    pub body: Value,
+    /// The caller's params, re-merged over a fallback model's `request_params`.
+    pub params: Map<String, Value>,
+    pub prompt_hash: String,
+    pub cache_key: String,
+}
+
+impl GenerationRequest {
+    pub fn new(endpoint: &EndpointDef, prompt: &str, params: &Map<String, Value>) -> Self {
+        Self {
+            prompt: prompt.to_string(),
+            body: build_request_body(endpoint, prompt, params),
+            params: params.clone(),
+            prompt_hash: prompt_hash(prompt),
+            cache_key: String::new(),
+        }
+        .keyed(endpoint)
+    }
+
+    /// Wrap an already merged body (e.g. one persisted with a job). The caller's params are
+    /// taken to be whatever differs from the endpoint's `request_params`.
+    pub fn from_body(endpoint: &EndpointDef, prompt: &str, body: Value) -> Self {
+        let params = body
+            .as_object()
+            .map(|fields| {
+                fields
+                    .iter()
+                    .filter(|(k, v)| k.as_str() != "prompt" && endpoint.request_params.get(*k) != Some(v))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            prompt: prompt.to_string(),
+            body,
+            params,
+            prompt_hash: prompt_hash(prompt),
+            cache_key: String::new(),
+        }
+        .keyed(endpoint)
+    }
+
+    fn keyed(mut self, endpoint: &EndpointDef) -> Self {
+        self.cache_key = cache_key(endpoint, &self.body);
+        self
+    }
+
+    /// The body to send to `target`: the request itself for the primary model, re-merged over
+    /// a fallback's own `request_params` otherwise.
+    pub fn body_for(&self, endpoint: &EndpointDef, target: &ModelTarget) -> Value {
+        if is_primary(endpoint, target) {
+            self.body.clone()
+        } else {
+            merge_body(target.request_params, &self.prompt, &self.params)
+        }
+    }
+
+    /// The key `target`'s results are recorded under: `cache_key` for the primary model. A
+    /// fallback's results get a key of their own, which lookups (by `cache_key`) never hit, so
+    /// a cheaper fallback's output is never served as the primary model's.
+    pub fn cache_key_for(&self, endpoint: &EndpointDef, target: &ModelTarget) -> String {
+        if is_primary(endpoint, target) {
+            self.cache_key.clone()
+        } else {
+            model_cache_key(target.provider, target.model, &self.body_for(endpoint, target))
+        }
+    }
+}
+
+/// The primary target borrows the endpoint's own request_params.
+fn is_primary(endpoint: &EndpointDef, target: &ModelTarget) -> bool {
+    std::ptr::eq(target.request_params, &endpoint.request_params)
+}
+
+/// Fill in `cache_key` for media recorded before cache keys existed (and before user params),
+/// rebuilding each row's request body from its endpoint. Rows of endpoints no longer configured are left alone.
+///
+/// This can't be done in the migration adding the column: the key hashes the endpoint's
+/// provider, model and `request_params` from endpoints.ron, which the database never sees, and
+/// must match `canonical_json` byte for byte. It runs at every startup instead and is a no-op
+/// once no row lacks a key.
+pub async fn backfill_cache_keys(pool: &PgPool, endpoints: &[EndpointDef]) {
+    let rows = match db::find_media_without_cache_key(pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::error!("Failed to query media without cache keys: {}", e);
+            return;
+        }
+    };
+    if rows.is_empty() {
+        return;
+    }
+
+    let mut updated = 0;
+    for (id, endpoint_path, prompt) in &rows {
+        let Some(endpoint) = endpoints.iter().find(|ep| &ep.path == endpoint_path) else {
+            continue;
+        };
+        let key = GenerationRequest::new(endpoint, prompt, &Map::new()).cache_key;
+        match db::set_media_cache_key(pool, *id, &key).await {
+            Ok(()) => updated += 1,
+            Err(e) => tracing::error!("Failed to backfill cache key for {}: {}", id, e),
+        }
+    }
+    tracing::info!("Backfilled cache keys for {}/{} media records", updated, rows.len());
+}
+
+async fn download_url(http_client: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
+    let resp = http_client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+    if !resp.status().is_success() {
+        return Err(format!("Download failed with status {}", resp.status()));
+    }
+    resp.bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| format!("Failed to read download bytes: {}", e))
+}
+
+/// Post-process if needed (ffmpeg uses tmp/ dir for temp files)
+async fn post_process(endpoint: &EndpointDef, hash: &str, result_bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    match &endpoint.post_process {
+        PostProcess::None => Ok(result_bytes),
+        PostProcess::FfmpegToGif {
+            input_extension,
+            ffmpeg_args,
+        } => {
+            std::fs::create_dir_all("tmp").map_err(|e| format!("Failed to create tmp dir: {}", e))?;
+
+            let tmp_input = Path::new("tmp").join(format!("{}.{}", hash, input_extension));
+            let tmp_output = Path::new("tmp").join(format!("{}.{}", hash, endpoint.output_extension));
+
+            std::fs::write(&tmp_input, &result_bytes).map_err(|e| {
+                tracing::error!("[{}] Failed to save temp file: {}", endpoint.path, e);
+                format!("Failed to save temp file: {}", e)
+            })?;
+
+            let mut cmd_args = vec![
+                "-i".to_string(),
+                tmp_input.to_string_lossy().to_string(),
+            ];
+            cmd_args.extend(ffmpeg_args.iter().cloned());
+            cmd_args.push("-y".to_string());
+            cmd_args.push(tmp_output.to_string_lossy().to_string());
+
+            let output = tokio::process::Command::new("ffmpeg")
+                .args(&cmd_args)
+                .output()
+                .await
+                .map_err(|e| {
+                    tracing::error!("[{}] ffmpeg failed: {}", endpoint.path, e);
+                    format!("ffmpeg failed to execute (is it installed?): {}", e)
+                })?;
+
+            let _ = std::fs::remove_file(&tmp_input);
+
+            if !output.status.success() {
+                let _ = std::fs::remove_file(&tmp_output);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                tracing::error!("[{}] ffmpeg conversion failed: {}", endpoint.path, stderr);
+                return Err(format!("ffmpeg conversion failed: {}", stderr));
+            }
+
+            let converted = std::fs::read(&tmp_output)
+                .map_err(|e| format!("Failed to read ffmpeg output: {}", e))?;
+            let _ = std::fs::remove_file(&tmp_output);
+            Ok(converted)
+        }
+    }
+}
+
+/// Error for a generation where every model failed: the error itself when there was only one.
+pub fn all_models_failed(errors: Vec<String>) -> String {
+    match errors.len() {
+        1 => errors.into_iter().next().unwrap_or_default(),
+        _ => format!("All models failed: {}", errors.join("; ")),
+    }
+}
+
+/// The uploaded outputs of a finished generation, the model that produced them and the cache
+/// key they are recorded under.
+pub struct Generated {
+    pub model: String,
+    pub cache_key: String,
+    pub outputs: Vec<StoredMedia>,
+}
+
+/// One output uploaded to storage but not yet recorded in the DB.
+pub struct StoredMedia {
+    pub s3_key: String,
+    pub cdn_url: String,
+    pub file_size: i64,
+}
+
+/// Download, post-process and upload every output of a generation, in order. The first output
+/// is stored under the cache key, later ones under `{key}_{index}`. If any output fails, the
+/// ones already uploaded are removed again. Progress is streamed to `job_id`'s subscribers, if any.
+pub async fn store_results(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    hash: &str,
+    result_urls: &[String],
+    job_id: Option<uuid::Uuid>,
+) -> Result<Vec<StoredMedia>, String> {
+    let outputs = result_urls.len();
+    let mut stored = Vec::with_capacity(outputs);
+    for (output, url) in result_urls.iter().enumerate() {
+        let name = match output {
+            0 => hash.to_string(),
+            n => format!("{}_{}", hash, n),
+        };
+        state.events.emit_for(job_id, JobEventKind::Downloading { output, outputs });
+        let result = match download_url(&state.http_client, url).await {
+            Ok(bytes) => {
+                if !matches!(endpoint.post_process, PostProcess::None) {
+                    state.events.emit_for(job_id, JobEventKind::PostProcessing { output, outputs });
+                }
+                store_result(state, endpoint, &name, bytes).await
+            }
+            Err(e) => {
+                tracing::error!("[{}] Download failed: {}", endpoint.path, e);
+                Err(e)
+            }
+        };
+        match result {
+            Ok(media) => {
+                state.events.emit_for(
+                    job_id,
+                    JobEventKind::Uploaded {
+                        output,
+                        outputs,
+                        url: media.cdn_url.clone(),
+                    },
+                );
+                stored.push(media)
+            }
+            Err(e) => {
+                for media in &stored {
+                    if let Err(e) = state.storage.delete(&media.s3_key).await {
+                        tracing::warn!("[{}] Failed to remove partial output {}: {}", endpoint.path, media.s3_key, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    if stored.len() > 1 {
+        tracing::info!("[{}] Stored {} outputs", endpoint.path, stored.len());
+    }
+    Ok(stored)
+}
+
+/// Post-process a downloaded provider result and upload it to storage under `hash`.
+async fn store_result(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    hash: &str,
+    result_bytes: Vec<u8>,
+) -> Result<StoredMedia, String> {
+    let final_bytes = post_process(endpoint, hash, result_bytes).await?;
+
+    // Storage key: endpoint_path_without_leading_slash/hash.ext
+    let path_segment = endpoint.path.trim_start_matches('/');
+    let s3_key = format!("{}/{}.{}", path_segment, hash, endpoint.output_extension);
+
+    let content_type = match endpoint.output_extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "mp4" => "video/mp4",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    };
+
+    let file_size = final_bytes.len() as i64;
+
+    state
+        .storage
+        .put(&s3_key, final_bytes, content_type)
+        .await
+        .map_err(|e| {
+            tracing::error!("[{}] Upload failed: {}", endpoint.path, e);
+            e
+        })?;
+
+    let cdn_url = state.storage.public_url(&s3_key);
+
+    tracing::info!("[{}] Uploaded: {}", endpoint.path, cdn_url);
+
+    Ok(StoredMedia {
+        s3_key,
+        cdn_url,
+        file_size,
+    })
+}
+
+/// Insert one DB row per uploaded output, as siblings of a new generation, so later requests hit the cache.
+/// Paid rows are linked to their settlement in the payments ledger.
+pub async fn record_media(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    request: &GenerationRequest,
+    generated: &Generated,
+    settlement: Option<&SettleResponse>,
+    payer_address: Option<&str>,
+) {
+    insert_outputs(state, endpoint, request, generated, settlement, payer_address, false).await;
+}
+
+/// Record the outputs of a generation whose payment failed to settle. The rows only let the
+/// cleanup worker remove the objects; they never serve cache hits, which may be priced lower.
+pub async fn record_unsettled_media(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    request: &GenerationRequest,
+    generated: &Generated,
+) {
+    insert_outputs(state, endpoint, request, generated, None, None, true).await;
+}
+
+async fn insert_outputs(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    request: &GenerationRequest,
+    generated: &Generated,
+    settlement: Option<&SettleResponse>,
+    payer_address: Option<&str>,
+    unsettled: bool,
+) {
+    let payment_tx = settlement.and_then(|s| s.transaction.as_deref());
+    let payment_id = settlement.and_then(|s| s.payment_id);
+    let generation_id = uuid::Uuid::new_v4();
+    for (index, media) in generated.outputs.iter().enumerate() {
+        if let Err(e) = db::insert_media(
+            &state.db_pool,
+            &endpoint.path,
+            &request.prompt,
+            &request.prompt_hash,
+            &generated.cache_key,
+            &media.s3_key,
+            &media.cdn_url,
+            &endpoint.media_type,
+            media.file_size,
+            payer_address,
+            payment_tx,
+            generation_id,
+            index as i32,
+            &generated.model,
+            payment_id,
+            unsettled,
+        )
+        .await
+        {
+            tracing::error!("[{}] Failed to insert DB record: {}", endpoint.path, e);
+        }
+    }
+}
+
+/// Record a generation that failed after payment was verified. Its payment is never settled.
+pub async fn record_failure(
+    state: &AppState,
+    endpoint: &EndpointDef,
+    prompt: &str,
+    job_id: Option<uuid::Uuid>,
+    payer_address: Option<&str>,
+    error: &str,
+) {
+    if let Err(e) =
+        db::insert_generation_failure(&state.db_pool, &endpoint.path, prompt, job_id, payer_address, error)
+            .await
+    {
+        tracing::error!("[{}] Failed to record generation failure: {}", endpoint.path, e);
+    }
+}
@@ -0,0 +1,224 @@
+use primitive_types::U256;
+use serde_json::{Map, Value};
+
+use crate::config::{Config, PaymentAsset};
+use crate::domain_types::DomainU256;
+use crate::endpoints::{EndpointDef, ParamKind};
+use crate::params;
+
+/// Multipliers and formula terms are fixed-point with this many decimals ("1.5" = 1_500_000).
+const FIXED_DECIMALS: u8 = 6;
+
+/// What an endpoint costs in one accepted asset.
+#[derive(Debug, Clone)]
+pub struct Quote<'a> {
+    pub asset: &'a PaymentAsset,
+    /// Human-readable amount, e.g. "1000" or "0.05".
+    pub human: String,
+    /// Raw token units (human amount × 10^decimals).
+    pub amount: DomainU256,
+    /// For generation requests: whether the quote is for a cached result. Sent to the payer
+    /// as `extra.cacheHit`.
+    pub cache_hit: Option<bool>,
+}
+
+/// Parse a non-negative decimal such as "2" or "1.25" into fixed-point.
+pub fn parse_multiplier(multiplier: &str) -> Result<U256, String> {
+    DomainU256::from_human_amount(multiplier, FIXED_DECIMALS).map(|m| m.0)
+}
+
+fn fixed_unit() -> U256 {
+    U256::exp10(FIXED_DECIMALS as usize)
+}
+
+/// Multiply a raw amount by a fixed-point multiplier, rounding up so a request is never undercharged.
+fn apply_multiplier(amount: U256, multiplier: U256) -> U256 {
+    let unit = fixed_unit();
+    (amount * multiplier + unit - 1) / unit
+}
+
+/// Parse a non-negative decimal into fixed-point, rounding digits past the last fixed-point
+/// one up, so a value is never priced below what is sent to the provider.
+fn parse_multiplier_ceil(text: &str) -> Result<U256, String> {
+    let Some((whole, fraction)) = text.trim().split_once('.') else {
+        return parse_multiplier(text);
+    };
+    if fraction.len() <= FIXED_DECIMALS as usize {
+        return parse_multiplier(text);
+    }
+    let (kept, rest) = fraction.split_at(FIXED_DECIMALS as usize);
+    if !rest.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("'{}' is not a decimal", text));
+    }
+    let value = parse_multiplier(&format!("{}.{}", whole, kept))?;
+    Ok(if rest.bytes().any(|b| b != b'0') { value + 1 } else { value })
+}
+
+/// Numeric value of a formula param as fixed-point: the request's value, else `request_params`.
+fn formula_value(endpoint: &EndpointDef, params: &Map<String, Value>, name: &str) -> Result<U256, String> {
+    let value = params
+        .get(name)
+        .or_else(|| endpoint.request_params.get(name))
+        .ok_or_else(|| format!("no value for '{}'", name))?;
+    let text = match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => match n.as_u64() {
+            Some(n) => n.to_string(),
+            // f64's Display is the shortest exact decimal in fixed notation; serde_json's would
+            // print large or tiny floats as e.g. "1e21"
+            None => n.as_f64().unwrap_or(-1.0).to_string(),
+        },
+        other => return Err(format!("'{}' is not numeric: {}", name, other)),
+    };
+    parse_multiplier_ceil(&text).map_err(|e| format!("'{}' = {}: {}", name, text, e))
+}
+
+/// Evaluate the endpoint's `pricing` formula to a fixed-point multiplier, if it has one.
+/// Terms are summed at double precision and the total rounded up once, so no term is lost.
+fn formula_multiplier(endpoint: &EndpointDef, params: &Map<String, Value>) -> Result<Option<U256>, String> {
+    let Some(rule) = &endpoint.pricing else {
+        return Ok(None);
+    };
+    let unit = fixed_unit();
+    let base = parse_multiplier(&rule.base).map_err(|e| format!("base '{}': {}", rule.base, e))?;
+    let mut total = base * unit;
+    for (name, per_unit) in &rule.per_unit {
+        let per_unit = parse_multiplier(per_unit).map_err(|e| format!("per_unit '{}': {}", per_unit, e))?;
+        let value = formula_value(endpoint, params, name)?;
+        total += value * per_unit;
+    }
+    Ok(Some((total + unit - 1) / unit))
+}
+
+/// Startup check of an endpoint's `pricing` formula: every term parses, and every param it
+/// references has a numeric value either fixed in `request_params` or constrained by `allowed_params`.
+pub fn check_rule(endpoint: &EndpointDef) -> Result<(), String> {
+    let Some(rule) = &endpoint.pricing else {
+        return Ok(());
+    };
+    for name in rule.per_unit.keys() {
+        match endpoint.allowed_params.get(name) {
+            Some(spec) => {
+                let numeric_options = !spec.one_of.is_empty()
+                    && spec.one_of.iter().all(|v| parse_multiplier(&params::value_key(v)).is_ok());
+                let numeric = matches!(spec.kind, ParamKind::Integer | ParamKind::Number) || numeric_options;
+                if !numeric {
+                    return Err(format!(
+                        "Endpoint {} prices by '{}', which must be an Integer/Number param or a String with numeric one_of values",
+                        endpoint.path, name
+                    ));
+                }
+                // Bounded both ways, so a caller can neither go negative nor run the price up
+                if spec.one_of.is_empty() && (spec.min.is_none_or(|min| min < 0.0) || spec.max.is_none()) {
+                    return Err(format!(
+                        "Endpoint {} prices by '{}', which needs `min: 0` (or higher) and a `max`, or `one_of`",
+                        endpoint.path, name
+                    ));
+                }
+                if spec.default.is_none() && !endpoint.request_params.contains_key(name) {
+                    return Err(format!(
+                        "Endpoint {} prices by '{}', which needs a default or a value in request_params",
+                        endpoint.path, name
+                    ));
+                }
+            }
+            None => {
+                if !endpoint.request_params.contains_key(name) {
+                    return Err(format!("Endpoint {} prices by unknown param '{}'", endpoint.path, name));
+                }
+            }
+        }
+    }
+    formula_multiplier(endpoint, &params::defaults(endpoint))
+        .map(|_| ())
+        .map_err(|e| format!("Endpoint {} pricing: {}", endpoint.path, e))
+}
+
+/// Every asset the endpoint can be paid in, in configured order, for a request with `params`
+/// (validated, defaults applied). `cost` prices the primary asset; `prices` adds (or overrides)
+/// prices per asset id. Assets without a price are not offered for the endpoint.
+/// The `pricing` formula and chosen values with a `price_multipliers` entry scale every price.
+pub fn quotes<'a>(
+    config: &'a Config,
+    endpoint: &EndpointDef,
+    params: &Map<String, Value>,
+) -> Result<Vec<Quote<'a>>, String> {
+    price_quotes(config, endpoint, params, |i, asset| match endpoint.prices.get(&asset.id) {
+        Some(price) => Some(price),
+        None if i == 0 => Some(&endpoint.cost),
+        None => None,
+    })
+}
+
+/// Like `quotes`, for a request whose result is already cached: `cached_cost` and
+/// `cached_prices` replace the full price where set.
+pub fn cached_quotes<'a>(
+    config: &'a Config,
+    endpoint: &EndpointDef,
+    params: &Map<String, Value>,
+) -> Result<Vec<Quote<'a>>, String> {
+    price_quotes(config, endpoint, params, |i, asset| {
+        let full = match endpoint.prices.get(&asset.id) {
+            Some(price) => Some(price),
+            None if i == 0 => Some(&endpoint.cost),
+            None => None,
+        }?;
+        match endpoint.cached_prices.get(&asset.id) {
+            Some(price) => Some(price),
+            None if i == 0 => Some(endpoint.cached_cost.as_ref().unwrap_or(full)),
+            None => Some(full),
+        }
+    })
+}
+
+/// Quotes from `price_of(index, asset)`, the human price of each asset (None: not offered).
+fn price_quotes<'a, 'e>(
+    config: &'a Config,
+    endpoint: &'e EndpointDef,
+    params: &Map<String, Value>,
+    price_of: impl Fn(usize, &PaymentAsset) -> Option<&'e String>,
+) -> Result<Vec<Quote<'a>>, String> {
+    let mut multipliers = Vec::new();
+    if let Some(multiplier) = formula_multiplier(endpoint, params)
+        .map_err(|e| format!("Bad pricing formula on endpoint {}: {}", endpoint.path, e))?
+    {
+        multipliers.push(multiplier);
+    }
+    for (name, spec) in &endpoint.allowed_params {
+        if let Some(value) = params.get(name)
+            && let Some(multiplier) = spec.price_multipliers.get(&params::value_key(value))
+        {
+            multipliers.push(parse_multiplier(multiplier).map_err(|e| {
+                format!("Bad price multiplier '{}' for {} on endpoint {}: {}", multiplier, name, endpoint.path, e)
+            })?);
+        }
+    }
+
+    let mut quotes = Vec::new();
+    for (i, asset) in config.payment_assets.iter().enumerate() {
+        let Some(human) = price_of(i, asset) else {
+            continue;
+        };
+        let amount = DomainU256::from_human_amount(human, asset.decimals).map_err(|e| {
+            format!("Bad price '{}' ({}) for endpoint {}: {}", human, asset.id, endpoint.path, e)
+        })?;
+
+        if multipliers.is_empty() {
+            quotes.push(Quote {
+                asset,
+                human: human.clone(),
+                amount,
+                cache_hit: None,
+            });
+        } else {
+            let amount = DomainU256(multipliers.iter().fold(amount.0, |a, m| apply_multiplier(a, *m)));
+            quotes.push(Quote {
+                asset,
+                human: amount.to_human_amount(asset.decimals),
+                amount,
+                cache_hit: None,
+            });
+        }
+    }
+    Ok(quotes)
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+
+use super::{GenerationProvider, QueueStatus, QueuedRequest};
+
+/// fal.ai inference: synchronous via `{base_url}/{model}` (https://fal.run),
+/// queued via `{queue_url}/{model}` (https://queue.fal.run). Either can be overridden per endpoint.
+pub struct FalProvider {
+    api_key: String,
+    base_url: String,
+    queue_url: String,
+}
+
+impl FalProvider {
+    pub fn new(api_key: String, base_url: String, queue_url: String) -> Self {
+        Self {
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            queue_url: queue_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(base_url: Option<&str>, default: &str, model: &str) -> String {
+        let base = base_url.map(|u| u.trim_end_matches('/')).unwrap_or(default);
+        format!("{}/{}", base, model)
+    }
+
+    async fn get_json(&self, http_client: &reqwest::Client, url: &str) -> Result<serde_json::Value, String> {
+        let response = self
+            .authorize(http_client.get(url))
+            .send()
+            .await
+            .map_err(|e| format!("FAL queue request failed: {}", e))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("FAL queue error {}: {}", status, body));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse FAL queue response: {}", e))
+    }
+}
+
+#[async_trait]
+impl GenerationProvider for FalProvider {
+    fn name(&self) -> &str {
+        "fal"
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.header("Authorization", format!("Key {}", self.api_key))
+    }
+
+    async fn submit(
+        &self,
+        http_client: &reqwest::Client,
+        base_url: Option<&str>,
+        model: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let url = Self::url(base_url, &self.base_url, model);
+        let response = self
+            .authorize(http_client.post(&url))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| format!("FAL request failed: {}", e))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("FAL error {}: {}", status, body));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse FAL response: {}", e))
+    }
+
+    fn supports_queue(&self) -> bool {
+        true
+    }
+
+    async fn enqueue(
+        &self,
+        http_client: &reqwest::Client,
+        base_url: Option<&str>,
+        model: &str,
+        body: &serde_json::Value,
+    ) -> Result<QueuedRequest, String> {
+        let url = Self::url(base_url, &self.queue_url, model);
+        let response = self
+            .authorize(http_client.post(&url))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| format!("FAL queue submit failed: {}", e))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("FAL queue error {}: {}", status, body));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse FAL queue submit response: {}", e))
+    }
+
+    async fn poll(
+        &self,
+        http_client: &reqwest::Client,
+        request: &QueuedRequest,
+    ) -> Result<QueueStatus, String> {
+        let url = format!("{}?logs=1", request.status_url);
+        let json = self.get_json(http_client, &url).await?;
+
+        match json.get("status").and_then(|s| s.as_str()) {
+            Some("IN_QUEUE") => Ok(QueueStatus::InQueue {
+                position: json.get("queue_position").and_then(|p| p.as_u64()),
+            }),
+            Some("IN_PROGRESS") => Ok(QueueStatus::InProgress {
+                logs: json
+                    .get("logs")
+                    .and_then(|l| l.as_array())
+                    .map(|logs| {
+                        logs.iter()
+                            .filter_map(|l| l.get("message").and_then(|m| m.as_str()))
+                            .map(|m| m.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            }),
+            Some("COMPLETED") => Ok(QueueStatus::Completed),
+            other => Err(format!("Unexpected FAL queue status: {:?}", other)),
+        }
+    }
+
+    async fn fetch_result(
+        &self,
+        http_client: &reqwest::Client,
+        request: &QueuedRequest,
+    ) -> Result<serde_json::Value, String> {
+        self.get_json(http_client, &request.response_url).await
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::endpoints::extract_urls;
+
+pub mod fal;
+
+/// Handle for a request submitted to a provider's queue, persisted with the job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedRequest {
+    pub request_id: String,
+    pub status_url: String,
+    pub response_url: String,
+}
+
+/// Provider-side state of a queued request.
+#[derive(Debug, Clone)]
+pub enum QueueStatus {
+    InQueue { position: Option<u64> },
+    InProgress { logs: Vec<String> },
+    Completed,
+}
+
+/// A backend that turns a JSON request body into generated media (fal.ai, Replicate, ComfyUI, ...).
+#[async_trait]
+pub trait GenerationProvider: Send + Sync {
+    /// Identifier referenced by `provider` in endpoints.ron.
+    fn name(&self) -> &str;
+
+    /// Attach the provider's credentials to an outgoing request.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder;
+
+    /// Run a generation for `model` and return the provider's raw JSON response.
+    /// `base_url` overrides the provider's configured base URL.
+    async fn submit(
+        &self,
+        http_client: &reqwest::Client,
+        base_url: Option<&str>,
+        model: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value, String>;
+
+    /// Whether this provider implements `enqueue`/`poll`/`fetch_result` for async jobs.
+    fn supports_queue(&self) -> bool {
+        false
+    }
+
+    /// Submit a generation to the provider's queue without waiting for it to finish.
+    /// `base_url` overrides the provider's configured queue URL.
+    async fn enqueue(
+        &self,
+        _http_client: &reqwest::Client,
+        _base_url: Option<&str>,
+        _model: &str,
+        _body: &serde_json::Value,
+    ) -> Result<QueuedRequest, String> {
+        Err(format!("Provider '{}' does not support queued jobs", self.name()))
+    }
+
+    /// Check the status of a queued request.
+    async fn poll(
+        &self,
+        _http_client: &reqwest::Client,
+        _request: &QueuedRequest,
+    ) -> Result<QueueStatus, String> {
+        Err(format!("Provider '{}' does not support queued jobs", self.name()))
+    }
+
+    /// Fetch the raw JSON response of a completed queued request.
+    async fn fetch_result(
+        &self,
+        _http_client: &reqwest::Client,
+        _request: &QueuedRequest,
+    ) -> Result<serde_json::Value, String> {
+        Err(format!("Provider '{}' does not support queued jobs", self.name()))
+    }
+
+    /// Pull the result media URLs out of a provider response using the endpoint's `response_url_path`.
+    fn extract_result_urls(&self, response: &serde_json::Value, url_path: &str) -> Result<Vec<String>, String> {
+        extract_urls(response, url_path)
+    }
+}
+
+/// Providers available to endpoints, keyed by name.
+pub struct ProviderRegistry {
+    providers: HashMap<String, Arc<dyn GenerationProvider>>,
+}
+
+impl ProviderRegistry {
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = Self {
+            providers: HashMap::new(),
+        };
+        registry.register(Arc::new(fal::FalProvider::new(
+            config.fal_key.clone(),
+            config.fal_base_url.clone(),
+            config.fal_queue_url.clone(),
+        )));
+        registry
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn GenerationProvider>) {
+        self.providers.insert(provider.name().to_string(), provider);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn GenerationProvider>> {
+        self.providers.get(name).cloned()
+    }
+}
@@ -1,14 +1,16 @@
+use async_trait::async_trait;
 use aws_credential_types::Credentials;
 use aws_sdk_s3::config::{BehaviorVersion, Region};
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::Client as S3Client;
 
-use crate::config::Config;
+use crate::config::S3Config;
+use crate::storage::Storage;
 
-pub fn create_s3_client(config: &Config) -> S3Client {
+pub fn create_s3_client(config: &S3Config) -> S3Client {
     let creds = Credentials::new(
-        &config.s3_access_key,
-        &config.s3_secret_key,
+        &config.access_key,
+        &config.secret_key,
         None,
         None,
         "env",
@@ -16,8 +18,8 @@ pub fn create_s3_client(config: &Config) -> S3Client {
 
     let s3_config = aws_sdk_s3::Config::builder()
         .behavior_version(BehaviorVersion::latest())
-        .region(Region::new(config.s3_region.clone()))
-        .endpoint_url(&config.s3_endpoint)
+        .region(Region::new(config.region.clone()))
+        .endpoint_url(&config.endpoint)
         .credentials_provider(creds)
         .force_path_style(true)
         .build();
@@ -56,6 +58,40 @@ pub async fn delete_file(client: &S3Client, bucket: &str, key: &str) -> Result<(
     Ok(())
 }
 
-pub fn cdn_url(config: &Config, key: &str) -> String {
-    format!("{}/{}", config.s3_cdn_url.trim_end_matches('/'), key)
+pub fn cdn_url(config: &S3Config, key: &str) -> String {
+    format!("{}/{}", config.cdn_url.trim_end_matches('/'), key)
+}
+
+/// Media in an S3 bucket, served from its CDN URL.
+pub struct S3Storage {
+    client: S3Client,
+    config: S3Config,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            client: create_s3_client(&config),
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn describe(&self) -> String {
+        format!("S3 bucket {} at {} (CDN: {})", self.config.bucket, self.config.endpoint, self.config.cdn_url)
+    }
+
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), String> {
+        upload_file(&self.client, &self.config.bucket, key, bytes, content_type).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        delete_file(&self.client, &self.config.bucket, key).await
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        cdn_url(&self.config, key)
+    }
 }
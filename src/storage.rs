@@ -0,0 +1,89 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::config::{Config, StorageConfig};
+use crate::s3::S3Storage;
+
+/// Route under which the local backend's files are served (`{PUBLIC_URL}/media/{key}`).
+pub const LOCAL_MEDIA_ROUTE: &str = "/media";
+
+/// Where generated media lives. Keys look like `generate_image/low/{hash}.png`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Human-readable description for startup logs.
+    fn describe(&self) -> String;
+
+    /// Store `bytes` under `key`, replacing any existing object.
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), String>;
+
+    /// Remove the object at `key`. Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<(), String>;
+
+    /// URL clients download the object at `key` from.
+    fn public_url(&self, key: &str) -> String;
+}
+
+pub fn from_config(config: &Config) -> Arc<dyn Storage> {
+    match &config.storage {
+        StorageConfig::S3(s3) => Arc::new(S3Storage::new(s3.clone())),
+        StorageConfig::Local { dir } => Arc::new(LocalStorage::new(dir, &config.public_url)),
+    }
+}
+
+/// Media on the local filesystem, served by the router at `LOCAL_MEDIA_ROUTE`.
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(dir: &str, public_url: &str) -> Self {
+        Self {
+            root: PathBuf::from(dir),
+            base_url: format!("{}{}", public_url.trim_end_matches('/'), LOCAL_MEDIA_ROUTE),
+        }
+    }
+
+    /// Resolve a key under the root, refusing anything that could escape it.
+    fn path_for(&self, key: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(key);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("Invalid storage key: {}", key));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn describe(&self) -> String {
+        format!("local directory {} (served at {})", self.root.display(), self.base_url)
+    }
+
+    async fn put(&self, key: &str, bytes: Vec<u8>, _content_type: &str) -> Result<(), String> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = self.path_for(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to delete {}: {}", path.display(), e)),
+        }
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::test;
+
+use crate::breaker::{Breakers, CircuitState};
+use crate::config::BreakerConfig;
+
+use super::{harness, payment_header, post, unique_prompt};
+
+#[actix_web::test]
+async fn failing_model_opens_its_circuit_before_payment() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    h.fal.fail.store(true, Ordering::SeqCst);
+
+    for _ in 0..2 {
+        let resp = test::call_service(
+            &app,
+            post(
+                "/generate_image",
+                serde_json::json!({"prompt": unique_prompt("a failing cat")}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 500);
+    }
+    assert_eq!(h.fal.request_count(), 2);
+
+    // The low quality now answers 503 without asking for payment or calling fal
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat"}), None).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 503);
+    assert!(resp.headers().get("Retry-After").is_some());
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["available_qualities"], serde_json::json!(["high", "medium"]));
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat"}), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(h.fal.request_count(), 2);
+
+    // Other qualities still ask for payment
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat", "quality": "medium"}), None).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 402);
+
+    // /api shows which quality to avoid
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/api").to_request()).await;
+    let info: serde_json::Value = test::read_body_json(resp).await;
+    let route = info["routes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|r| r["route"] == "/generate_image")
+        .unwrap();
+    let low = route["qualities"].as_array().unwrap().iter().find(|q| q["quality"] == "low").unwrap();
+    assert_eq!(low["available"], false);
+    assert_eq!(low["health"][0]["state"], "open");
+    assert_eq!(low["health"][0]["error_rate"], 1.0);
+    let medium = route["qualities"].as_array().unwrap().iter().find(|q| q["quality"] == "medium").unwrap();
+    assert_eq!(medium["available"], true);
+}
+
+#[actix_web::test]
+async fn open_circuit_lets_one_trial_call_through_after_cooldown() {
+    let breakers = Breakers::new(BreakerConfig {
+        error_rate: 0.5,
+        min_calls: 3,
+        cooldown: Duration::from_millis(50),
+    });
+    let model = "fal-ai/flaky";
+
+    breakers.record(model, true, Duration::from_millis(200));
+    breakers.record(model, false, Duration::from_millis(10));
+    assert_eq!(breakers.status(model).state, CircuitState::Closed);
+    breakers.record(model, false, Duration::from_millis(10));
+    let status = breakers.status(model);
+    assert_eq!(status.state, CircuitState::Open);
+    assert_eq!(status.avg_latency_ms, Some(200));
+    assert_eq!(status.retry_after_seconds, Some(1));
+    assert!(!breakers.try_call(model));
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    // Half-open: routes no longer refuse requests for it, and one trial call gets through
+    let status = breakers.status(model);
+    assert_eq!(status.state, CircuitState::HalfOpen);
+    assert_eq!(status.retry_after_seconds, None);
+    assert!(breakers.try_call(model));
+    assert!(!breakers.try_call(model), "only one trial call at a time");
+
+    breakers.record(model, true, Duration::from_millis(100));
+    assert_eq!(breakers.status(model).state, CircuitState::Closed);
+    assert!(breakers.try_call(model));
+}
@@ -0,0 +1,70 @@
+use actix_web::test;
+
+use crate::db;
+use crate::pipeline::{self, GenerationRequest};
+
+use super::{harness, payment_header, post, unique_prompt};
+
+#[actix_web::test]
+async fn cache_key_covers_the_whole_request_body() {
+    let Some(h) = harness().await else { return };
+    let endpoint = h.endpoint("/generate_image/low");
+
+    let base = GenerationRequest::new(endpoint, "A Cat", &serde_json::Map::new());
+    assert_eq!(base.cache_key, GenerationRequest::new(endpoint, "  a cat ", &serde_json::Map::new()).cache_key);
+
+    // Key order doesn't matter, values do
+    let mut reordered = serde_json::Map::new();
+    let fields = base.body.as_object().unwrap();
+    let mut keys: Vec<&String> = fields.keys().collect();
+    keys.reverse();
+    for k in keys {
+        reordered.insert(k.clone(), fields[k].clone());
+    }
+    let reordered = GenerationRequest::from_body(endpoint, "A Cat", serde_json::Value::Object(reordered));
+    assert_eq!(base.cache_key, reordered.cache_key);
+
+    let mut seeded = base.body.clone();
+    seeded["seed"] = serde_json::json!(42);
+    assert_ne!(base.cache_key, GenerationRequest::from_body(endpoint, "A Cat", seeded).cache_key);
+
+    // Same prompt, different quality-specific params
+    let medium = GenerationRequest::new(h.endpoint("/generate_image/medium"), "A Cat", &serde_json::Map::new());
+    assert_ne!(base.cache_key, medium.cache_key);
+}
+
+#[actix_web::test]
+async fn legacy_rows_are_backfilled_and_hit() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("a legacy cat");
+
+    // A row written before cache keys existed
+    sqlx::query(
+        "INSERT INTO generated_media (endpoint_path, prompt, prompt_hash, s3_key, s3_url, media_type, file_size_bytes)
+         VALUES ('/generate_image/low', $1, $2, 'generate_image/low/legacy.png', 'http://cdn/legacy.png', 'image', 1)",
+    )
+    .bind(&prompt)
+    .bind(pipeline::prompt_hash(&prompt))
+    .execute(&h.state.db_pool)
+    .await
+    .unwrap();
+
+    pipeline::backfill_cache_keys(&h.state.db_pool, &h.state.endpoints).await;
+    let request = GenerationRequest::new(h.endpoint("/generate_image/low"), &prompt, &serde_json::Map::new());
+    let records = db::find_by_cache_key(&h.state.db_pool, &request.cache_key, "/generate_image/low")
+        .await
+        .unwrap();
+    assert_eq!(records.len(), 1);
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": prompt}), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["cached"], true);
+    assert_eq!(body["url"], "http://cdn/legacy.png");
+    assert_eq!(h.fal.request_count(), 0);
+}
@@ -0,0 +1,184 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+
+use crate::cleanup;
+use crate::x402::PAYMENT_RESPONSE_HEADER;
+
+use super::{TEST_BUCKET, harness, harness_with_endpoints, payment_header, post, unique_prompt};
+
+/// The primary asset's requirement from a 402 body.
+async fn primary_requirement(resp: actix_web::dev::ServiceResponse) -> serde_json::Value {
+    assert_eq!(resp.status(), 402);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    body["accepts"][0].clone()
+}
+
+#[actix_web::test]
+async fn cache_hit_is_quoted_at_the_cached_cost() {
+    let Some(h) = harness_with_endpoints(|endpoints| {
+        let ep = endpoints.iter_mut().find(|ep| ep.path == "/generate_image/low").unwrap();
+        ep.cached_cost = Some("100".to_string());
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+    let body = serde_json::json!({"prompt": unique_prompt("a discounted cat")});
+
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), None).to_request()).await;
+    let miss = primary_requirement(resp).await;
+    assert_eq!(miss["maxAmountRequired"], "1000000000000000000000");
+    assert_eq!(miss["extra"]["cacheHit"], false);
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    // Same request again: a hit, quoted at the discount, and paying it serves the cached media
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), None).to_request()).await;
+    let hit = primary_requirement(resp).await;
+    assert_eq!(hit["maxAmountRequired"], "100000000000000000000");
+    assert_eq!(hit["extra"]["cacheHit"], true);
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+    let result: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(result["cached"], true);
+
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn free_cache_hit_needs_no_payment() {
+    let Some(h) = harness_with_endpoints(|endpoints| {
+        let ep = endpoints.iter_mut().find(|ep| ep.path == "/generate_image/low").unwrap();
+        ep.cached_cost = Some("0".to_string());
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+    let body = serde_json::json!({"prompt": unique_prompt("a free cat")});
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(&app, post("/generate_image", body, None).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+    let result: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(result["cached"], true);
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn media_from_an_unsettled_payment_is_not_a_cache_hit() {
+    let Some(h) = harness_with_endpoints(|endpoints| {
+        let ep = endpoints.iter_mut().find(|ep| ep.path == "/generate_image/low").unwrap();
+        ep.cached_cost = Some("0".to_string());
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+    let body = serde_json::json!({"prompt": unique_prompt("an unsettled cat")});
+
+    h.facilitator.settle_down.store(true, Ordering::SeqCst);
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert!(!resp.status().is_success());
+    h.facilitator.settle_down.store(false, Ordering::SeqCst);
+
+    // The media was generated but never paid for: quoted and generated as a miss
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), None).to_request()).await;
+    let miss = primary_requirement(resp).await;
+    assert_eq!(miss["maxAmountRequired"], "1000000000000000000000");
+    assert_eq!(miss["extra"]["cacheHit"], false);
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let result: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(result["cached"], false);
+    assert_eq!(h.fal.request_count(), 2);
+}
+
+#[actix_web::test]
+async fn expiring_unsettled_media_keeps_the_paid_copy_of_its_object() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("a twice generated cat");
+    let body = serde_json::json!({"prompt": prompt});
+
+    h.facilitator.settle_down.store(true, Ordering::SeqCst);
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert!(!resp.status().is_success());
+    h.facilitator.settle_down.store(false, Ordering::SeqCst);
+
+    // The paid retry stores its output under the same key as the unsettled one
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let keys: Vec<String> = sqlx::query_scalar("SELECT DISTINCT s3_key FROM generated_media WHERE prompt = $1")
+        .bind(&prompt)
+        .fetch_all(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 1);
+
+    // Only the unsettled row expires: the object it shares with the paid row stays
+    sqlx::query("UPDATE generated_media SET expires_at = NOW() - INTERVAL '1 day' WHERE prompt = $1 AND unsettled")
+        .bind(&prompt)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    cleanup::cleanup_expired(&h.state.db_pool, h.state.storage.as_ref()).await;
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM generated_media WHERE prompt = $1")
+        .bind(&prompt)
+        .fetch_one(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 1);
+    assert!(h.s3.objects.lock().unwrap().contains_key(&format!("{}/{}", TEST_BUCKET, keys[0])));
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    let result: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(result["cached"], true);
+}
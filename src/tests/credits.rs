@@ -0,0 +1,156 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+
+use crate::credits::{ASSET_HEADER, BALANCE_HEADER};
+use crate::idempotency::{IDEMPOTENCY_KEY_HEADER, REPLAYED_HEADER};
+use crate::x402::PAYMENT_RESPONSE_HEADER;
+
+use super::{harness, payment_header_from, post, unique_prompt};
+
+/// A payer of its own, so balances of tests sharing a database don't mix.
+fn unique_payer() -> String {
+    format!("0x{}00000000", uuid::Uuid::new_v4().simple())
+}
+
+fn with_token(req: test::TestRequest, token: &str) -> test::TestRequest {
+    req.insert_header(("Authorization", format!("Bearer {}", token)))
+}
+
+/// A paid top-up of `amount` of the primary asset by `payer`.
+fn top_up(payer: &str, amount: &str) -> test::TestRequest {
+    let payment = payment_header_from("permit", payer);
+    post("/credits/topup", serde_json::json!({"amount": amount}), Some(&payment))
+}
+
+/// Check a successful top-up response and return its token.
+async fn issued_token(resp: actix_web::dev::ServiceResponse, payer: &str, amount: &str) -> String {
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["payer"], payer);
+    assert_eq!(body["balances"][0]["balance"], amount);
+    body["token"].as_str().expect("token").to_string()
+}
+
+fn get_credits(token: &str) -> test::TestRequest {
+    with_token(test::TestRequest::get().uri("/credits"), token)
+}
+
+async fn balance_of(resp: actix_web::dev::ServiceResponse) -> serde_json::Value {
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    body["balances"][0]["balance"].clone()
+}
+
+#[actix_web::test]
+async fn topup_pays_for_generations_without_facilitator_calls() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payer = unique_payer();
+    let cost: u64 = h.endpoint("/generate_image/low").cost.parse().unwrap();
+
+    // Unpaid top-up: 402 for the requested amount
+    let resp = test::call_service(
+        &app,
+        post("/credits/topup", serde_json::json!({"amount": "1"}), None).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 402);
+
+    let amount = (cost * 2 + cost / 2).to_string();
+    let resp = test::call_service(&app, top_up(&payer, &amount).to_request()).await;
+    let token = issued_token(resp, &payer, &amount).await;
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+
+    for remaining in [cost + cost / 2, cost / 2] {
+        let req = post("/generate_image", serde_json::json!({"prompt": unique_prompt("a prepaid cat")}), None);
+        let resp = test::call_service(&app, with_token(req, &token).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(BALANCE_HEADER).unwrap(), remaining.to_string().as_str());
+        assert_eq!(resp.headers().get(ASSET_HEADER).unwrap(), "starkbot-base");
+        assert!(!resp.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+    }
+
+    // Not enough left: 402 pointing at the top-up route, nothing debited
+    let req = post("/generate_image", serde_json::json!({"prompt": unique_prompt("a broke cat")}), None);
+    let resp = test::call_service(&app, with_token(req, &token).to_request()).await;
+    assert_eq!(resp.status(), 402);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["topup"], "/credits/topup");
+    let resp = test::call_service(&app, get_credits(&token).to_request()).await;
+    assert_eq!(balance_of(resp).await, (cost / 2).to_string());
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 2);
+}
+
+#[actix_web::test]
+async fn failed_generation_refunds_and_revoked_token_is_refused() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payer = unique_payer();
+    let cost = h.endpoint("/generate_image/low").cost.clone();
+
+    let resp = test::call_service(&app, top_up(&payer, &cost).to_request()).await;
+    let token = issued_token(resp, &payer, &cost).await;
+
+    h.fal.fail.store(true, Ordering::SeqCst);
+    let req = post("/generate_image", serde_json::json!({"prompt": unique_prompt("a refunded cat")}), None);
+    let resp = test::call_service(&app, with_token(req, &token).to_request()).await;
+    assert_eq!(resp.status(), 500);
+    let body = test::read_body(resp).await;
+    assert!(String::from_utf8_lossy(&body).contains("credits were refunded"));
+    let resp = test::call_service(&app, get_credits(&token).to_request()).await;
+    assert_eq!(balance_of(resp).await, cost);
+
+    let resp = test::call_service(&app, get_credits("x402c_unknown").to_request()).await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = test::call_service(
+        &app,
+        with_token(test::TestRequest::delete().uri("/credits/token"), &token).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 204);
+    h.fal.fail.store(false, Ordering::SeqCst);
+    let req = post("/generate_image", serde_json::json!({"prompt": unique_prompt("a revoked cat")}), None);
+    let resp = test::call_service(&app, with_token(req, &token).to_request()).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_web::test]
+async fn retried_credit_request_is_debited_once() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payer = unique_payer();
+    let cost: u64 = h.endpoint("/generate_image/low").cost.parse().unwrap();
+
+    let amount = (cost * 2).to_string();
+    let resp = test::call_service(&app, top_up(&payer, &amount).to_request()).await;
+    let token = issued_token(resp, &payer, &amount).await;
+
+    let key = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::json!({"prompt": unique_prompt("a retried prepaid cat")});
+    let keyed = || {
+        with_token(post("/generate_image", body.clone(), None), &token)
+            .insert_header((IDEMPOTENCY_KEY_HEADER, key.clone()))
+            .to_request()
+    };
+
+    let first = test::call_service(&app, keyed()).await;
+    assert_eq!(first.status(), 200);
+    assert!(!first.headers().contains_key(REPLAYED_HEADER));
+    let first_body = test::read_body(first).await;
+
+    let second = test::call_service(&app, keyed()).await;
+    assert_eq!(second.status(), 200);
+    assert_eq!(second.headers().get(REPLAYED_HEADER).unwrap(), "true");
+    assert_eq!(second.headers().get(BALANCE_HEADER).unwrap(), cost.to_string().as_str());
+    assert_eq!(test::read_body(second).await, first_body);
+
+    let resp = test::call_service(&app, get_credits(&token).to_request()).await;
+    assert_eq!(balance_of(resp).await, cost.to_string());
+    assert_eq!(h.fal.request_count(), 1);
+}
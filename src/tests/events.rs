@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use actix_web::test;
+
+use crate::db;
+use crate::events::JobEventKind;
+use crate::jobs;
+
+use super::{harness, payment_header, post, unique_prompt};
+
+/// (event name, data) of every event in an SSE body, skipping comments.
+fn parse_events(body: &[u8]) -> Vec<(String, serde_json::Value)> {
+    String::from_utf8_lossy(body)
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut name = None;
+            let mut data = None;
+            for line in block.lines() {
+                if let Some(v) = line.strip_prefix("event: ") {
+                    name = Some(v.to_string());
+                } else if let Some(v) = line.strip_prefix("data: ") {
+                    data = serde_json::from_str(v).ok();
+                }
+            }
+            Some((name?, data?))
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn stream_replays_history_then_follows_to_the_end() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let prompt = unique_prompt("a streamed cat");
+    let id = db::insert_job(
+        &h.state.db_pool,
+        "/generate_video/low",
+        "low",
+        &prompt,
+        "hash",
+        "key",
+        &serde_json::json!({}),
+        jobs::STATUS_PENDING,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    // As if this process were running it
+    h.state.events.open(id);
+    h.state.events.emit(id, JobEventKind::PaymentVerified);
+    h.state.events.emit(id, JobEventKind::InQueue { position: Some(2) });
+
+    // Finish the job while the stream is open
+    let state = h.state.clone();
+    actix_web::rt::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        state.events.emit(id, JobEventKind::InProgress { logs: vec!["step 1".to_string()] });
+        let result = serde_json::json!({"url": "https://cdn/x.mp4"});
+        db::mark_job_succeeded(&state.db_pool, id, &result).await.unwrap();
+        state.events.emit(id, JobEventKind::Succeeded { result });
+        state.events.close(id);
+    });
+
+    let uri = format!("/jobs/{}/events", id);
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "text/event-stream");
+    let events = parse_events(&test::read_body(resp).await);
+
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["payment_verified", "in_queue", "in_progress", "succeeded"]);
+    assert_eq!(events[1].1["position"], 2);
+    assert_eq!(events[2].1["logs"][0], "step 1");
+    assert_eq!(events[3].1["progress"], 1.0);
+    assert_eq!(events[3].1["result"]["url"], "https://cdn/x.mp4");
+
+    // Reconnecting after the job finished yields just the outcome
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&uri).insert_header(("Last-Event-ID", "2")).to_request(),
+    )
+    .await;
+    let names: Vec<String> = parse_events(&test::read_body(resp).await).into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["succeeded"]);
+}
+
+#[actix_web::test]
+async fn job_run_elsewhere_is_followed_through_its_row() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let id = db::insert_job(
+        &h.state.db_pool,
+        "/generate_video/low",
+        "low",
+        &unique_prompt("a distant cat"),
+        "hash",
+        "key",
+        &serde_json::json!({}),
+        jobs::STATUS_PENDING,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    // Another process finishes it; events for a job this one doesn't run go nowhere
+    let state = h.state.clone();
+    actix_web::rt::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        state.events.emit(id, JobEventKind::InQueue { position: Some(1) });
+        let result = serde_json::json!({"url": "https://cdn/y.mp4"});
+        db::mark_job_succeeded(&state.db_pool, id, &result).await.unwrap();
+    });
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("/jobs/{}/events", id)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let events = parse_events(&test::read_body(resp).await);
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["succeeded"]);
+    assert_eq!(events[0].1["result"]["url"], "https://cdn/y.mp4");
+}
+
+#[actix_web::test]
+async fn paid_job_streams_to_completion() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a racing cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+
+    let uri = format!("{}/events", job["status_url"].as_str().unwrap());
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    let events = parse_events(&test::read_body(resp).await);
+    let (name, data) = events.last().expect("at least the outcome");
+    assert_eq!(name, "succeeded");
+    assert_eq!(data["result"]["type"], "video");
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("/jobs/{}/events", uuid::Uuid::new_v4())).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 404);
+}
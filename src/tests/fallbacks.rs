@@ -0,0 +1,149 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::test;
+
+use crate::endpoints::FallbackModel;
+
+use super::{harness, harness_with_endpoints, payment_header, post, unique_prompt};
+
+const MEDIUM_MODEL: &str = "fal-ai/kling-image/v3/text-to-image";
+const MEDIUM_FALLBACK: &str = "fal-ai/flux/dev";
+
+#[actix_web::test]
+async fn failing_model_falls_back_and_records_the_one_used() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    h.fal.failing_models.lock().unwrap().insert(MEDIUM_MODEL.to_string());
+    let prompt = unique_prompt("a resilient cat");
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": prompt, "quality": "medium", "negative_prompt": "dogs"}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["model"], MEDIUM_FALLBACK);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+
+    // The fallback gets its own request_params with the caller's params merged over them
+    let requests = h.fal.requests.lock().unwrap().clone();
+    let models: Vec<&str> = requests.iter().map(|(m, _)| m.as_str()).collect();
+    assert_eq!(models, [MEDIUM_MODEL, MEDIUM_FALLBACK]);
+    let fallback_body = &requests[1].1;
+    assert_eq!(fallback_body["image_size"], "square_hd");
+    assert_eq!(fallback_body["negative_prompt"], "dogs");
+    assert_eq!(fallback_body["prompt"], requests[0].1["prompt"]);
+    assert!(fallback_body.get("aspect_ratio").is_none());
+
+    let model: Option<String> = sqlx::query_scalar("SELECT model FROM generated_media WHERE prompt = $1")
+        .bind(&prompt)
+        .fetch_one(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(model.as_deref(), Some(MEDIUM_FALLBACK));
+
+    // The fallback's output isn't a cache hit for the primary model: it runs again
+    h.fal.failing_models.lock().unwrap().clear();
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": prompt, "quality": "medium", "negative_prompt": "dogs"}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["cached"], false);
+    assert_eq!(body["model"], MEDIUM_MODEL);
+    assert_eq!(h.fal.request_count(), 3);
+}
+
+#[actix_web::test]
+async fn payment_is_not_settled_when_every_model_fails() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    h.fal.fail.store(true, Ordering::SeqCst);
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": unique_prompt("a doomed cat"), "quality": "medium"}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 500);
+    let body = test::read_body(resp).await;
+    let error = String::from_utf8_lossy(&body);
+    assert!(error.contains(MEDIUM_MODEL) && error.contains(MEDIUM_FALLBACK), "{}", error);
+    assert_eq!(h.fal.request_count(), 2);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn async_job_falls_back_to_the_next_model() {
+    let Some(h) = harness_with_endpoints(|endpoints| {
+        let ep = endpoints.iter_mut().find(|ep| ep.path == "/generate_video/low").unwrap();
+        ep.fallbacks.push(FallbackModel {
+            provider: None,
+            model: "fal-ai/backup-video".to_string(),
+            base_url: None,
+            request_params: Default::default(),
+            response_url_path: "video.url".to_string(),
+        });
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+    let primary = h.endpoint("/generate_video/low").model.clone();
+    h.fal.failing_models.lock().unwrap().insert(primary.clone());
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a stubborn cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let status_url = job["status_url"].as_str().unwrap().to_string();
+
+    let mut last = serde_json::Value::Null;
+    for _ in 0..50 {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&status_url).to_request()).await;
+        last = test::read_body_json(resp).await;
+        if last["status"] == "succeeded" || last["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(last["status"], "succeeded", "{}", last);
+    assert_eq!(last["result"]["model"], "fal-ai/backup-video");
+
+    let job_id = uuid::Uuid::parse_str(job["id"].as_str().unwrap()).unwrap();
+    let model: Option<String> = sqlx::query_scalar("SELECT model FROM generation_jobs WHERE id = $1")
+        .bind(job_id)
+        .fetch_one(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(model.as_deref(), Some("fal-ai/backup-video"));
+    assert_eq!(h.fal.request_count(), 2);
+}
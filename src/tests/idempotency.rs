@@ -0,0 +1,123 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+
+use crate::idempotency::{IDEMPOTENCY_KEY_HEADER, REPLAYED_HEADER};
+use crate::x402::PAYMENT_RESPONSE_HEADER;
+
+use super::{harness, payment_header, post, unique_prompt};
+
+fn keyed(route: &str, body: &serde_json::Value, payment: &str, key: &str) -> test::TestRequest {
+    post(route, body.clone(), Some(payment)).insert_header((IDEMPOTENCY_KEY_HEADER, key.to_string()))
+}
+
+fn header(resp: &actix_web::dev::ServiceResponse, name: &str) -> Option<String> {
+    resp.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+#[actix_web::test]
+async fn retry_with_same_payment_replays_response() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let key = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::json!({"prompt": unique_prompt("an idempotent cat")});
+
+    let first = test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()).await;
+    assert_eq!(first.status(), 200);
+    assert!(header(&first, REPLAYED_HEADER).is_none());
+    let receipt = header(&first, PAYMENT_RESPONSE_HEADER).expect("receipt");
+    let first_body = test::read_body(first).await;
+
+    let second = test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()).await;
+    assert_eq!(second.status(), 200);
+    assert_eq!(header(&second, REPLAYED_HEADER).as_deref(), Some("true"));
+    assert_eq!(header(&second, PAYMENT_RESPONSE_HEADER), Some(receipt));
+    assert_eq!(test::read_body(second).await, first_body);
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn retry_with_new_payment_from_same_payer_is_not_charged() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let key = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::json!({"prompt": unique_prompt("a re-signed cat")});
+
+    let first = test::call_service(
+        &app,
+        keyed("/generate_image", &body, &payment_header("permit"), &key).to_request(),
+    )
+    .await;
+    assert_eq!(first.status(), 200);
+    let first_body = test::read_body(first).await;
+
+    // A freshly signed payment from the same payer: verified, then released unsettled
+    let second = test::call_service(
+        &app,
+        keyed("/generate_image", &body, &payment_header("permit"), &key).to_request(),
+    )
+    .await;
+    assert_eq!(second.status(), 200);
+    assert_eq!(header(&second, REPLAYED_HEADER).as_deref(), Some("true"));
+    assert_eq!(test::read_body(second).await, first_body);
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn retry_while_in_flight_waits_for_first() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let key = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::json!({"prompt": unique_prompt("a slow cat")});
+
+    h.fal.delay_ms.store(500, Ordering::SeqCst);
+    let (first, second) = futures_util::future::join(
+        test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()).await
+        },
+    )
+    .await;
+    assert_eq!(first.status(), 200);
+    assert_eq!(second.status(), 200);
+    assert!(header(&first, REPLAYED_HEADER).is_none());
+    assert_eq!(header(&second, REPLAYED_HEADER).as_deref(), Some("true"));
+    assert_eq!(test::read_body(first).await, test::read_body(second).await);
+
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn uncharged_outcome_is_not_kept() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let key = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::json!({"prompt": unique_prompt("a flaky cat")});
+
+    // Generation fails and the payment is released: the retry runs again
+    h.fal.fail.store(true, Ordering::SeqCst);
+    let resp = test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()).await;
+    assert_eq!(resp.status(), 500);
+
+    h.fal.fail.store(false, Ordering::SeqCst);
+    let resp = test::call_service(&app, keyed("/generate_image", &body, &payment, &key).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert!(header(&resp, REPLAYED_HEADER).is_none());
+
+    // The same key can't be reused for another request
+    let other = serde_json::json!({"prompt": unique_prompt("a different cat")});
+    let resp = test::call_service(&app, keyed("/generate_image", &other, &payment, &key).to_request()).await;
+    assert_eq!(resp.status(), 422);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+}
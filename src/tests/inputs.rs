@@ -0,0 +1,252 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::test;
+
+use crate::cleanup;
+
+use super::mocks::MEDIA_BYTES;
+use super::{TEST_BUCKET, harness, harness_with_config, payment_header, post, unique_prompt};
+
+const BOUNDARY: &str = "x402-test-boundary";
+
+/// A multipart/form-data request with text fields and an `image` file part.
+fn multipart(route: &str, fields: &[(&str, &str)], image: &[u8], payment: Option<&str>) -> test::TestRequest {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value)
+                .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"input.png\"\r\n\
+             Content-Type: image/png\r\n\r\n",
+            BOUNDARY
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(image);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+    let mut req = test::TestRequest::post()
+        .uri(route)
+        .peer_addr("127.0.0.1:40000".parse().unwrap())
+        .insert_header(("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+        .set_payload(body);
+    if let Some(payment) = payment {
+        req = req.insert_header(("X-PAYMENT", payment.to_string()));
+    }
+    req
+}
+
+#[actix_web::test]
+async fn uploaded_image_is_staged_and_passed_to_the_provider() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("make it a watercolor");
+
+    let resp = test::call_service(
+        &app,
+        multipart(
+            "/edit_image",
+            &[("prompt", &prompt), ("strength", "0.5")],
+            MEDIA_BYTES,
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let (model, request) = h.fal.requests.lock().unwrap()[0].clone();
+    assert_eq!(model, "fal-ai/flux/dev/image-to-image");
+    assert_eq!(request["prompt"], prompt);
+    assert_eq!(request["strength"], 0.5);
+
+    // The provider fetches the input from our storage, under a content-addressed key
+    let image_url = request["image_url"].as_str().unwrap();
+    let key = image_url.split(&format!("/{}/", TEST_BUCKET)).nth(1).unwrap();
+    assert!(key.starts_with("inputs/") && key.ends_with(".png"), "{}", key);
+    let staged = h.s3.objects.lock().unwrap()[&format!("{}/{}", TEST_BUCKET, key)].clone();
+    assert_eq!(staged, MEDIA_BYTES);
+}
+
+#[actix_web::test]
+async fn image_url_is_downloaded_and_staged() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/animate_image",
+            serde_json::json!({
+                "prompt": unique_prompt("slow zoom"),
+                "image_url": format!("{}/files/source.png", h.fal_url),
+            }),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+
+    // The job is submitted to the queue in the background
+    for _ in 0..50 {
+        if h.fal.request_count() > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let (model, request) = h.fal.requests.lock().unwrap()[0].clone();
+    assert_eq!(model, "fal-ai/minimax/hailuo-02/standard/image-to-video");
+    let image_url = request["image_url"].as_str().unwrap();
+    assert!(image_url.contains(&format!("/{}/inputs/", TEST_BUCKET)), "{}", image_url);
+}
+
+#[actix_web::test]
+async fn missing_or_invalid_images_are_never_charged() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    // No image at all: rejected before payment
+    let resp = test::call_service(
+        &app,
+        post(
+            "/edit_image",
+            serde_json::json!({"prompt": "a cat"}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+
+    // Text-only routes don't take one
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": "a cat", "image_url": "https://example.com/cat.png"}),
+            None,
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    // Not an image: rejected after verification, without generating or settling
+    let resp = test::call_service(
+        &app,
+        multipart("/edit_image", &[("prompt", "a cat")], b"not an image", Some(&payment_header("permit")))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(h.fal.request_count(), 0);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+    assert_eq!(h.stored_objects(), 0);
+}
+
+#[actix_web::test]
+async fn image_url_to_a_private_address_is_refused() {
+    let Some(h) = harness_with_config(|config| config.allow_private_urls = false).await else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+
+    let local = format!("{}/files/source.png", h.fal_url);
+    for url in [local.as_str(), "http://169.254.169.254/latest/meta-data/", "http://localhost/cat.png"] {
+        let resp = test::call_service(
+            &app,
+            post(
+                "/edit_image",
+                serde_json::json!({"prompt": "a cat", "image_url": url}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 400, "{}", url);
+    }
+    assert_eq!(h.fal.request_count(), 0);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+    assert_eq!(h.stored_objects(), 0);
+}
+
+#[actix_web::test]
+async fn image_url_that_never_answers_times_out_uncharged() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    // Accepts connections and holds them open without ever responding
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+
+    let resp = tokio::time::timeout(
+        Duration::from_secs(30),
+        test::call_service(
+            &app,
+            post(
+                "/edit_image",
+                serde_json::json!({"prompt": "a cat", "image_url": format!("http://{}/cat.png", addr)}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        ),
+    )
+    .await
+    .expect("input download was not bounded");
+    assert_eq!(resp.status(), 400);
+    let body = test::read_body(resp).await;
+    assert!(String::from_utf8_lossy(&body).contains("Timed out"));
+    assert_eq!(h.fal.request_count(), 0);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn expired_staged_inputs_are_cleaned_up() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    // Content of its own, so the staged key isn't shared with other tests
+    let image = [MEDIA_BYTES, uuid::Uuid::new_v4().as_bytes()].concat();
+
+    let resp = test::call_service(
+        &app,
+        multipart("/edit_image", &[("prompt", "a cat")], &image, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let (_, request) = h.fal.requests.lock().unwrap()[0].clone();
+    let image_url = request["image_url"].as_str().unwrap();
+    let key = image_url.split(&format!("/{}/", TEST_BUCKET)).nth(1).unwrap().to_string();
+    let object = format!("{}/{}", TEST_BUCKET, key);
+    assert!(h.s3.objects.lock().unwrap().contains_key(&object));
+
+    // Not expired yet: kept
+    cleanup::cleanup_staged_inputs(&h.state.db_pool, h.state.storage.as_ref()).await;
+    assert!(h.s3.objects.lock().unwrap().contains_key(&object));
+
+    sqlx::query("UPDATE staged_inputs SET expires_at = NOW() - INTERVAL '1 second' WHERE s3_key = $1")
+        .bind(&key)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    cleanup::cleanup_staged_inputs(&h.state.db_pool, h.state.storage.as_ref()).await;
+    assert!(!h.s3.objects.lock().unwrap().contains_key(&object));
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM staged_inputs WHERE s3_key = $1")
+        .bind(&key)
+        .fetch_one(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 0);
+}
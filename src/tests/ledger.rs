@@ -0,0 +1,141 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use uuid::Uuid;
+
+use crate::{cleanup, x402};
+
+use super::mocks::{MOCK_PAYER, MOCK_TX};
+use super::{harness, payment_header, post, unique_prompt};
+
+#[derive(Debug, sqlx::FromRow)]
+struct PaymentRow {
+    id: Uuid,
+    operation: String,
+    outcome: String,
+    resource: String,
+    scheme: String,
+    amount: String,
+    payer_address: Option<String>,
+    tx_hash: Option<String>,
+    error: Option<String>,
+    verification_id: Option<Uuid>,
+    latency_ms: i64,
+}
+
+fn payload_hash(header: &str) -> String {
+    let payload: serde_json::Value = serde_json::from_slice(&BASE64.decode(header).unwrap()).unwrap();
+    x402::payload_hash(&payload)
+}
+
+async fn ledger_rows(pool: &sqlx::PgPool, header: &str) -> Vec<PaymentRow> {
+    sqlx::query_as(
+        "SELECT id, operation, outcome, resource, scheme, amount::TEXT AS amount, payer_address, tx_hash, error,
+                verification_id, latency_ms
+         FROM payments WHERE payload_hash = $1 ORDER BY created_at",
+    )
+    .bind(payload_hash(header))
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn verify_and_settle_are_recorded_and_outlive_their_media() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let prompt = unique_prompt("a bookkept cat");
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": prompt}), Some(&payment)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let rows = ledger_rows(&h.state.db_pool, &payment).await;
+    assert_eq!(rows.len(), 2, "{:?}", rows);
+    let (verify, settle) = (&rows[0], &rows[1]);
+    assert_eq!((verify.operation.as_str(), verify.outcome.as_str()), ("verify", "verified"));
+    assert_eq!((settle.operation.as_str(), settle.outcome.as_str()), ("settle", "settled"));
+    assert_eq!(settle.verification_id, Some(verify.id));
+    assert_eq!(settle.tx_hash.as_deref(), Some(MOCK_TX));
+    assert_eq!(verify.payer_address.as_deref(), Some(MOCK_PAYER));
+    let quoted = crate::pricing::quotes(&h.state.config, h.endpoint("/generate_image/low"), &Default::default())
+        .unwrap()[0]
+        .amount
+        .to_string();
+    for row in &rows {
+        assert_eq!(row.resource, "/generate_image/low");
+        assert_eq!(row.scheme, "permit");
+        assert_eq!(row.amount, quoted);
+        assert!(row.latency_ms >= 0);
+    }
+
+    // The media row points at its settlement
+    let (media_id, payment_id): (Uuid, Option<Uuid>) =
+        sqlx::query_as("SELECT id, payment_id FROM generated_media WHERE prompt = $1")
+            .bind(&prompt)
+            .fetch_one(&h.state.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(payment_id, Some(settle.id));
+
+    // Expiring and cleaning up the media leaves the ledger alone
+    sqlx::query("UPDATE generated_media SET expires_at = NOW() - INTERVAL '1 day' WHERE id = $1")
+        .bind(media_id)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    cleanup::cleanup_expired(&h.state.db_pool, h.state.storage.as_ref()).await;
+    let media_left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM generated_media WHERE id = $1")
+        .bind(media_id)
+        .fetch_one(&h.state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(media_left, 0);
+    assert_eq!(ledger_rows(&h.state.db_pool, &payment).await.len(), 2);
+}
+
+#[actix_web::test]
+async fn rejected_and_unsettled_payments_are_recorded() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    // Invalid: one verify row per requirement the payload was tried against
+    h.facilitator.valid.store(false, Ordering::SeqCst);
+    let invalid = payment_header("permit");
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat"}), Some(&invalid)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 402);
+    let rows = ledger_rows(&h.state.db_pool, &invalid).await;
+    assert!(!rows.is_empty());
+    for row in &rows {
+        assert_eq!((row.operation.as_str(), row.outcome.as_str()), ("verify", "invalid"));
+        assert_eq!(row.error.as_deref(), Some("mock_invalid"));
+    }
+
+    // Verified but the generation failed: nothing to settle
+    h.facilitator.valid.store(true, Ordering::SeqCst);
+    h.fal.fail.store(true, Ordering::SeqCst);
+    let unsettled = payment_header("permit");
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": unique_prompt("a doomed cat")}),
+            Some(&unsettled),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 500);
+    let rows = ledger_rows(&h.state.db_pool, &unsettled).await;
+    let outcomes: Vec<&str> = rows.iter().map(|r| r.outcome.as_str()).collect();
+    assert_eq!(outcomes, ["verified"]);
+}
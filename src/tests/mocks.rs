@@ -0,0 +1,394 @@
+//! In-process stand-ins for fal.ai, the x402 facilitator, an S3-compatible store, a webhook
+//! receiver and a moderation API.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+/// Bytes served for every generated "media" file. They start with the PNG signature,
+/// so mock results double as valid input images.
+pub const MEDIA_BYTES: &[u8] = b"\x89PNG\r\n\x1a\nmock-media-bytes";
+
+pub const MOCK_PAYER: &str = "0x00000000000000000000000000000000000000aa";
+pub const MOCK_TX: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+/// Run an actix app on an ephemeral local port and return its base URL.
+fn bind<F, T>(factory: F) -> String
+where
+    F: Fn() -> App<T> + Send + Clone + 'static,
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+{
+    let server = HttpServer::new(factory)
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("bind mock server");
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}", addr)
+}
+
+// ── fal.ai ──
+
+#[derive(Default)]
+pub struct FalState {
+    /// When set, every generation request fails with a 500.
+    pub fail: AtomicBool,
+    /// Milliseconds each sync generation takes.
+    pub delay_ms: AtomicU64,
+    /// Models whose generation requests fail with a 500.
+    pub failing_models: Mutex<HashSet<String>>,
+    /// (model, request body) of every sync or queued submission.
+    pub requests: Mutex<Vec<(String, serde_json::Value)>>,
+    pub auth_headers: Mutex<Vec<String>>,
+}
+
+impl FalState {
+    pub fn request_count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+/// A fal-style result with `num_images` images (one unless the request asks for more).
+fn fal_result(base: &str, num_images: u64) -> serde_json::Value {
+    let images: Vec<serde_json::Value> = (0..num_images)
+        .map(|i| serde_json::json!({ "url": format!("{}/files/image-{}.png", base, i) }))
+        .collect();
+    serde_json::json!({
+        "images": images,
+        "video": { "url": format!("{}/files/video.mp4", base) },
+    })
+}
+
+fn record_fal_request(
+    state: &FalState,
+    req: &HttpRequest,
+    model: String,
+    body: &web::Bytes,
+) -> Option<HttpResponse> {
+    let auth = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    state.auth_headers.lock().unwrap().push(auth);
+    let body = serde_json::from_slice(body).unwrap_or(serde_json::Value::Null);
+    let failing = state.failing_models.lock().unwrap().contains(&model);
+    state.requests.lock().unwrap().push((model, body));
+
+    if failing || state.fail.load(Ordering::SeqCst) {
+        return Some(HttpResponse::InternalServerError().body("mock fal failure"));
+    }
+    None
+}
+
+async fn fal_run(
+    req: HttpRequest,
+    state: web::Data<FalState>,
+    model: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Some(err) = record_fal_request(&state, &req, model.into_inner(), &body) {
+        return err;
+    }
+    let delay = state.delay_ms.load(Ordering::SeqCst);
+    if delay > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+    }
+    let num_images = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|b| b["num_images"].as_u64())
+        .unwrap_or(1);
+    HttpResponse::Ok().json(fal_result(&base_url(&req), num_images))
+}
+
+async fn fal_enqueue(
+    req: HttpRequest,
+    state: web::Data<FalState>,
+    model: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Some(err) = record_fal_request(&state, &req, model.into_inner(), &body) {
+        return err;
+    }
+    let base = base_url(&req);
+    let id = uuid::Uuid::new_v4();
+    HttpResponse::Ok().json(serde_json::json!({
+        "request_id": id.to_string(),
+        "status_url": format!("{}/queue/requests/{}/status", base, id),
+        "response_url": format!("{}/queue/requests/{}", base, id),
+    }))
+}
+
+async fn fal_status() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "COMPLETED", "logs": [] }))
+}
+
+async fn fal_response(req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(fal_result(&base_url(&req), 1))
+}
+
+async fn fal_file() -> HttpResponse {
+    HttpResponse::Ok().body(MEDIA_BYTES)
+}
+
+/// Serves `/run/{model}` (sync), `/queue/...` (queue API) and `/files/{name}` (results).
+pub fn start_fal() -> (String, Arc<FalState>) {
+    let state = web::Data::new(FalState::default());
+    let shared = state.clone().into_inner();
+    let url = bind(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/run/{model:.*}", web::post().to(fal_run))
+            .route("/queue/requests/{id}/status", web::get().to(fal_status))
+            .route("/queue/requests/{id}", web::get().to(fal_response))
+            .route("/queue/{model:.*}", web::post().to(fal_enqueue))
+            .route("/files/{name}", web::get().to(fal_file))
+    });
+    (url, shared)
+}
+
+// ── x402 facilitator ──
+
+pub struct FacilitatorState {
+    /// What `/verify` answers for `isValid`.
+    pub valid: AtomicBool,
+    pub verify_calls: AtomicUsize,
+    pub settle_calls: AtomicUsize,
+    pub last_verify: Mutex<Option<serde_json::Value>>,
+    /// When set, `/settle` answers 500, as if it timed out after going on-chain.
+    pub settle_down: AtomicBool,
+}
+
+impl Default for FacilitatorState {
+    fn default() -> Self {
+        Self {
+            valid: AtomicBool::new(true),
+            verify_calls: AtomicUsize::new(0),
+            settle_calls: AtomicUsize::new(0),
+            last_verify: Mutex::new(None),
+            settle_down: AtomicBool::new(false),
+        }
+    }
+}
+
+/// The payload's `authorization.from` (as in `exact` payloads), else `MOCK_PAYER`.
+fn payer_of(body: &serde_json::Value) -> String {
+    body["paymentPayload"]["payload"]["authorization"]["from"]
+        .as_str()
+        .unwrap_or(MOCK_PAYER)
+        .to_string()
+}
+
+async fn facilitator_verify(
+    state: web::Data<FacilitatorState>,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    state.verify_calls.fetch_add(1, Ordering::SeqCst);
+    let payer = payer_of(&body);
+    *state.last_verify.lock().unwrap() = Some(body.into_inner());
+    if state.valid.load(Ordering::SeqCst) {
+        HttpResponse::Ok().json(serde_json::json!({ "isValid": true, "payer": payer }))
+    } else {
+        HttpResponse::Ok().json(serde_json::json!({ "isValid": false, "invalidReason": "mock_invalid" }))
+    }
+}
+
+async fn facilitator_settle(
+    state: web::Data<FacilitatorState>,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    state.settle_calls.fetch_add(1, Ordering::SeqCst);
+    if state.settle_down.load(Ordering::SeqCst) {
+        return HttpResponse::InternalServerError().body("mock settle outage");
+    }
+    let network = body["paymentRequirements"]["network"].clone();
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "network": network,
+        "transaction": MOCK_TX,
+        "payer": payer_of(&body),
+    }))
+}
+
+/// Serves `/verify` and `/settle`.
+pub fn start_facilitator() -> (String, Arc<FacilitatorState>) {
+    let state = web::Data::new(FacilitatorState::default());
+    let shared = state.clone().into_inner();
+    let url = bind(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/verify", web::post().to(facilitator_verify))
+            .route("/settle", web::post().to(facilitator_settle))
+    });
+    (url, shared)
+}
+
+// ── S3-compatible store ──
+
+#[derive(Default)]
+pub struct S3State {
+    /// Objects keyed by "bucket/key".
+    pub objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+/// Strip `aws-chunked` framing (`<hex size>[;ext]\r\n<data>\r\n ... 0\r\n<trailers>`).
+fn decode_aws_chunked(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = body;
+    while let Some(line_end) = rest.windows(2).position(|w| w == b"\r\n") {
+        let header = String::from_utf8_lossy(&rest[..line_end]);
+        let size_hex = header.split(';').next().unwrap_or("0");
+        let size = usize::from_str_radix(size_hex.trim(), 16).unwrap_or(0);
+        if size == 0 {
+            break;
+        }
+        let start = line_end + 2;
+        out.extend_from_slice(&rest[start..start + size]);
+        rest = &rest[start + size + 2..];
+    }
+    out
+}
+
+async fn s3_put(
+    req: HttpRequest,
+    state: web::Data<S3State>,
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let chunked = req
+        .headers()
+        .get("content-encoding")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("aws-chunked"));
+    let bytes = if chunked {
+        decode_aws_chunked(&body)
+    } else {
+        body.to_vec()
+    };
+    state.objects.lock().unwrap().insert(path.into_inner(), bytes);
+    HttpResponse::Ok().insert_header(("ETag", "\"mock\"")).finish()
+}
+
+async fn s3_get(state: web::Data<S3State>, path: web::Path<String>) -> HttpResponse {
+    match state.objects.lock().unwrap().get(&path.into_inner()) {
+        Some(bytes) => HttpResponse::Ok().body(bytes.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn s3_delete(state: web::Data<S3State>, path: web::Path<String>) -> HttpResponse {
+    state.objects.lock().unwrap().remove(&path.into_inner());
+    HttpResponse::NoContent().finish()
+}
+
+/// Path-style object store: `PUT/GET/DELETE /{bucket}/{key}`.
+pub fn start_s3() -> (String, Arc<S3State>) {
+    let state = web::Data::new(S3State::default());
+    let shared = state.clone().into_inner();
+    let url = bind(move || {
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::PayloadConfig::new(64 * 1024 * 1024))
+            .route("/{path:.*}", web::put().to(s3_put))
+            .route("/{path:.*}", web::get().to(s3_get))
+            .route("/{path:.*}", web::delete().to(s3_delete))
+    });
+    (url, shared)
+}
+
+// ── Webhook receiver ──
+
+#[derive(Default)]
+pub struct WebhookState {
+    /// Deliveries to answer with a 500 before accepting.
+    pub failures_left: AtomicUsize,
+    /// (signature header, raw body) of every delivery, failed ones included.
+    pub received: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+async fn webhook_receive(req: HttpRequest, state: web::Data<WebhookState>, body: web::Bytes) -> HttpResponse {
+    let signature = req
+        .headers()
+        .get("X-Webhook-Signature")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    state.received.lock().unwrap().push((signature, body.to_vec()));
+
+    let fail = state
+        .failures_left
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if fail {
+        HttpResponse::InternalServerError().finish()
+    } else {
+        HttpResponse::NoContent().finish()
+    }
+}
+
+/// Accepts webhooks at `POST /hook`.
+pub fn start_webhook_receiver() -> (String, Arc<WebhookState>) {
+    let state = web::Data::new(WebhookState::default());
+    let shared = state.clone().into_inner();
+    let url = bind(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/hook", web::post().to(webhook_receive))
+    });
+    (format!("{}/hook", url), shared)
+}
+
+// ── Moderation API ──
+
+#[derive(Default)]
+pub struct ModerationState {
+    /// Texts containing this are flagged for "violence".
+    pub flag_word: Mutex<String>,
+    /// When set, the API answers 500.
+    pub down: AtomicBool,
+    pub inputs: Mutex<Vec<String>>,
+}
+
+async fn moderation_check(state: web::Data<ModerationState>, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let input = body["input"].as_str().unwrap_or_default().to_string();
+    state.inputs.lock().unwrap().push(input.clone());
+    if state.down.load(Ordering::SeqCst) {
+        return HttpResponse::InternalServerError().json(serde_json::json!({ "error": "mock outage" }));
+    }
+    let flagged = input.contains(state.flag_word.lock().unwrap().as_str());
+    HttpResponse::Ok().json(serde_json::json!({
+        "results": [{
+            "flagged": flagged,
+            "categories": { "violence": flagged, "hate": false },
+        }]
+    }))
+}
+
+/// OpenAI-style `/v1/moderations`.
+pub fn start_moderation(flag_word: &str) -> (String, Arc<ModerationState>) {
+    let state = web::Data::new(ModerationState::default());
+    *state.flag_word.lock().unwrap() = flag_word.to_string();
+    let shared = state.clone().into_inner();
+    let url = bind(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/v1/moderations", web::post().to(moderation_check))
+    });
+    (format!("{}/v1/moderations", url), shared)
+}
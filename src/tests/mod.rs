@@ -0,0 +1,254 @@
+//! End-to-end tests of the payment → generate → upload → DB flow against in-process mocks.
+//!
+//! Postgres is the only real dependency: set `TEST_DATABASE_URL` to a scratch database
+//! (migrations are applied automatically). Without it the tests are skipped.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_governor::GovernorConfigBuilder;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{test, web, App};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::config::{BreakerConfig, Config, ModerationConfig, PaymentAsset, S3Config, StorageConfig};
+use crate::endpoints::{self, EndpointDef, QualityMap};
+use crate::providers::ProviderRegistry;
+use crate::x402::PaymentScheme;
+use crate::{AppState, configure_routes, db, storage};
+
+mod breaker;
+mod cache;
+mod cached_pricing;
+mod credits;
+mod events;
+mod fallbacks;
+mod idempotency;
+mod inputs;
+mod ledger;
+mod mocks;
+mod moderation;
+mod openapi;
+mod params;
+mod payment_flow;
+mod replay;
+mod webhooks;
+
+pub use mocks::{FacilitatorState, FalState, S3State};
+
+pub const TEST_BUCKET: &str = "test-bucket";
+pub const TEST_WEBHOOK_SECRET: &str = "test-webhook-secret";
+
+pub struct Harness {
+    pub state: web::Data<AppState>,
+    pub grouped: Arc<HashMap<String, QualityMap>>,
+    pub fal: Arc<FalState>,
+    pub fal_url: String,
+    pub facilitator: Arc<FacilitatorState>,
+    pub s3: Arc<S3State>,
+}
+
+fn test_config(database_url: String, fal_url: &str, facilitator_url: &str, storage: StorageConfig) -> Config {
+    Config {
+        test_mode: false,
+        port: 0,
+        facilitator_url: facilitator_url.to_string(),
+        facilitator_verify_path: "/verify".to_string(),
+        facilitator_settle_path: "/settle".to_string(),
+        facilitator_signer: "0x00000000000000000000000000000000000000f5".to_string(),
+        wallet_address: "0x00000000000000000000000000000000000000be".to_string(),
+        payment_assets: vec![PaymentAsset {
+            id: "starkbot-base".to_string(),
+            network: "base".to_string(),
+            address: "0x587Cd533F418825521f3A1daa7CCd1E7339A1B07".to_string(),
+            symbol: "STARKBOT".to_string(),
+            decimals: 18,
+            name: "StarkBot".to_string(),
+            version: "1".to_string(),
+            schemes: vec![PaymentScheme::Permit, PaymentScheme::Exact],
+            pay_to: None,
+            min_topup: None,
+        }],
+        fal_key: "test-fal-key".to_string(),
+        fal_base_url: format!("{}/run", fal_url),
+        fal_queue_url: format!("{}/queue", fal_url),
+        public_url: "http://localhost:3402".to_string(),
+        endpoints_config_path: "endpoints.ron".to_string(),
+        storage,
+        database_url,
+        webhook_secret: Some(TEST_WEBHOOK_SECRET.to_string()),
+        // Mock receivers and image hosts listen on loopback
+        allow_private_urls: true,
+        breaker: BreakerConfig {
+            error_rate: 0.5,
+            min_calls: 2,
+            cooldown: Duration::from_secs(60),
+        },
+        moderation: ModerationConfig {
+            blocklist_path: None,
+            provider: None,
+            api_key: None,
+            url: String::new(),
+        },
+    }
+}
+
+/// Start the mocks and build router state against them, or None when no test database is set.
+pub async fn harness() -> Option<Harness> {
+    harness_with_storage(None).await
+}
+
+/// Like `harness`, but storing media in `local_dir` instead of the mock S3 when given.
+pub async fn harness_with_storage(local_dir: Option<&Path>) -> Option<Harness> {
+    build_harness(local_dir, |_| {}, |_| {}).await
+}
+
+/// Like `harness`, with `endpoints.ron` adjusted by `edit` before routes are built.
+pub async fn harness_with_endpoints(edit: impl FnOnce(&mut Vec<EndpointDef>)) -> Option<Harness> {
+    build_harness(None, |_| {}, edit).await
+}
+
+/// Like `harness`, with the router config adjusted by `edit`.
+pub async fn harness_with_config(edit: impl FnOnce(&mut Config)) -> Option<Harness> {
+    build_harness(None, edit, |_| {}).await
+}
+
+async fn build_harness(
+    local_dir: Option<&Path>,
+    edit_config: impl FnOnce(&mut Config),
+    edit_endpoints: impl FnOnce(&mut Vec<EndpointDef>),
+) -> Option<Harness> {
+    let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL not set; skipping end-to-end test");
+        return None;
+    };
+
+    let (fal_url, fal) = mocks::start_fal();
+    let (facilitator_url, facilitator) = mocks::start_facilitator();
+    let (s3_url, s3) = mocks::start_s3();
+
+    let storage = match local_dir {
+        Some(dir) => StorageConfig::Local {
+            dir: dir.to_string_lossy().to_string(),
+        },
+        None => StorageConfig::S3(S3Config {
+            endpoint: s3_url.clone(),
+            bucket: TEST_BUCKET.to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+            cdn_url: format!("{}/{}", s3_url, TEST_BUCKET),
+        }),
+    };
+    let mut config = test_config(database_url, &fal_url, &facilitator_url, storage);
+    edit_config(&mut config);
+
+    let db_pool = db::create_pool(&config.database_url).await;
+    let migrator = sqlx::migrate::Migrator::new(Path::new("./migrations"))
+        .await
+        .expect("load migrations");
+    migrator.run(&db_pool).await.expect("run migrations");
+
+    let mut endpoint_defs = endpoints::load_endpoints(&config.endpoints_config_path).endpoints;
+    edit_endpoints(&mut endpoint_defs);
+    let grouped = Arc::new(endpoints::group_by_route(&endpoint_defs));
+
+    let state = web::Data::new(AppState {
+        providers: ProviderRegistry::from_config(&config),
+        http_client: reqwest::Client::new(),
+        outbound_client: crate::outbound::client(config.allow_private_urls),
+        endpoints: Arc::new(endpoint_defs),
+        storage: storage::from_config(&config),
+        events: crate::events::JobEvents::default(),
+        breakers: crate::breaker::Breakers::new(config.breaker.clone()),
+        moderation: crate::moderation::Moderation::from_config(&config.moderation),
+        db_pool,
+        config,
+    });
+
+    Some(Harness {
+        state,
+        grouped,
+        fal,
+        fal_url,
+        facilitator,
+        s3,
+    })
+}
+
+impl Harness {
+    /// The router app with the same routes as production; pass to `test::init_service`.
+    pub fn app(
+        &self,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        > + use<>,
+    > {
+        let governor_conf = GovernorConfigBuilder::default()
+            .burst_size(1000)
+            .finish()
+            .unwrap();
+        let grouped = self.grouped.clone();
+        let storage = self.state.config.storage.clone();
+        App::new()
+            .app_data(self.state.clone())
+            .configure(move |cfg| configure_routes(cfg, &grouped, &governor_conf, &storage))
+    }
+
+    pub fn endpoint(&self, path: &str) -> &EndpointDef {
+        self.state.endpoints.iter().find(|ep| ep.path == path).unwrap()
+    }
+
+    pub fn stored_objects(&self) -> usize {
+        self.s3.objects.lock().unwrap().len()
+    }
+}
+
+/// A unique prompt so tests sharing a database never hit each other's cache rows.
+pub fn unique_prompt(base: &str) -> String {
+    format!("{} {}", base, uuid::Uuid::new_v4())
+}
+
+/// Base64 X-PAYMENT header for a payload the mock facilitator will accept.
+pub fn payment_header(scheme: &str) -> String {
+    let payload = serde_json::json!({
+        "x402Version": 1,
+        "scheme": scheme,
+        "network": "base",
+        "payload": { "signature": "0xsig", "nonce": uuid::Uuid::new_v4().to_string() },
+    });
+    BASE64.encode(serde_json::to_vec(&payload).unwrap())
+}
+
+/// Like `payment_header`, signed by `payer` (the mock facilitator reports it as the payer).
+pub fn payment_header_from(scheme: &str, payer: &str) -> String {
+    let payload = serde_json::json!({
+        "x402Version": 1,
+        "scheme": scheme,
+        "network": "base",
+        "payload": {
+            "signature": "0xsig",
+            "authorization": { "from": payer, "nonce": uuid::Uuid::new_v4().to_string() },
+        },
+    });
+    BASE64.encode(serde_json::to_vec(&payload).unwrap())
+}
+
+/// POST a JSON body to a route, optionally paying.
+pub fn post(route: &str, body: serde_json::Value, payment: Option<&str>) -> test::TestRequest {
+    let mut req = test::TestRequest::post()
+        .uri(route)
+        .peer_addr("127.0.0.1:40000".parse().unwrap())
+        .set_json(body);
+    if let Some(payment) = payment {
+        req = req.insert_header(("X-PAYMENT", payment.to_string()));
+    }
+    req
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+
+use super::mocks::start_moderation;
+use super::{harness_with_config, payment_header, post, unique_prompt};
+
+const BLOCKLIST: &str = r#"(
+    keywords: ["forbidden fruit", "ass"],
+    patterns: [r"\bbad[-_ ]?word\w*"],
+)"#;
+
+#[actix_web::test]
+async fn blocked_prompts_are_rejected_before_payment() {
+    let path = std::env::temp_dir().join(format!("blocklist-{}.ron", uuid::Uuid::new_v4()));
+    std::fs::write(&path, BLOCKLIST).unwrap();
+    let (moderation_url, moderation) = start_moderation("kill");
+    let Some(h) = harness_with_config(|config| {
+        config.moderation.blocklist_path = Some(path.to_string_lossy().to_string());
+        config.moderation.provider = Some("openai".to_string());
+        config.moderation.api_key = Some("test-moderation-key".to_string());
+        config.moderation.url = moderation_url;
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+
+    for (body, reason) in [
+        (serde_json::json!({"prompt": "a Forbidden  Fruit bowl"}), "Forbidden  Fruit"),
+        (serde_json::json!({"prompt": "some BAD-WORDS here"}), "BAD-WORDS"),
+        (
+            serde_json::json!({"prompt": "a cat", "quality": "medium", "negative_prompt": "ass"}),
+            "'ass'",
+        ),
+        (serde_json::json!({"prompt": "kill the cat"}), "violence"),
+    ] {
+        let resp = test::call_service(
+            &app,
+            post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 400, "{}", body);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["error"], "Prompt rejected by content policy");
+        assert!(error["reason"].as_str().unwrap().contains(reason), "{}", error);
+    }
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+
+    // Whole words only: "class" doesn't match "ass", and clean prompts reach the provider
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": unique_prompt("a cat in class")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    // Blocklisted prompts never reach the external API
+    let inputs = moderation.inputs.lock().unwrap().clone();
+    assert_eq!(inputs.len(), 2);
+    assert_eq!(inputs[0], "kill the cat");
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[actix_web::test]
+async fn moderation_outage_refuses_requests_unpaid() {
+    let (moderation_url, moderation) = start_moderation("kill");
+    moderation.down.store(true, Ordering::SeqCst);
+    let Some(h) = harness_with_config(|config| {
+        config.moderation.provider = Some("openai".to_string());
+        config.moderation.api_key = Some("test-moderation-key".to_string());
+        config.moderation.url = moderation_url;
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat"}), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+    assert_eq!(h.fal.request_count(), 0);
+}
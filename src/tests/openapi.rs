@@ -0,0 +1,69 @@
+use actix_web::test;
+
+use super::harness;
+
+#[actix_web::test]
+async fn openapi_document_describes_routes_and_payments() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/openapi.json").to_request()).await;
+    assert_eq!(resp.status(), 200);
+    let doc: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(doc["openapi"], "3.0.3");
+    assert!(doc["paths"]["/jobs/{id}"]["get"].is_object());
+
+    let image = &doc["paths"]["/generate_image"]["post"];
+    let qualities = &image["requestBody"]["content"]["application/json"]["schema"]["properties"]["quality"];
+    assert_eq!(qualities["enum"], serde_json::json!(["high", "low", "medium"]));
+    assert_eq!(
+        image["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/GenerateResponse"
+    );
+    assert!(image["responses"]["202"].is_null());
+
+    let accepts = image["x-x402"]["qualities"]["low"]["accepts"].as_array().unwrap();
+    assert_eq!(accepts.len(), 2);
+    assert_eq!(accepts[0]["resource"], "/generate_image/low");
+    assert_eq!(accepts[0]["scheme"], "permit");
+
+    // Async routes answer with a job instead
+    let video = &doc["paths"]["/generate_video"]["post"];
+    assert!(video["responses"]["200"].is_null());
+    assert_eq!(
+        video["responses"]["202"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/JobResponse"
+    );
+}
+
+#[actix_web::test]
+async fn params_declared_differently_per_quality_get_a_schema_per_quality() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/openapi.json").to_request()).await;
+    let doc: serde_json::Value = test::read_body_json(resp).await;
+    let schema = &doc["paths"]["/generate_video"]["post"]["requestBody"]["content"]["application/json"]["schema"];
+
+    // Declared alike wherever it is declared: one shared schema
+    assert_eq!(schema["properties"]["aspect_ratio"]["enum"], serde_json::json!(["16:9", "9:16", "1:1"]));
+    // low takes duration as a string, medium and high as an integer
+    assert!(schema["properties"]["duration"].is_null());
+    let variants = schema["oneOf"].as_array().unwrap();
+    assert_eq!(variants.len(), 2);
+    let integer = &variants[0];
+    assert_eq!(integer["properties"]["quality"]["enum"], serde_json::json!(["high", "medium"]));
+    assert_eq!(integer["properties"]["duration"]["type"], "integer");
+    assert_eq!(integer["properties"]["duration"]["enum"], serde_json::json!([5, 10]));
+    assert_eq!(integer["required"], serde_json::json!(["quality"]));
+    let string = &variants[1];
+    assert_eq!(string["properties"]["quality"]["enum"], serde_json::json!(["low"]));
+    assert_eq!(string["properties"]["duration"]["type"], "string");
+    assert_eq!(string["properties"]["duration"]["enum"], serde_json::json!(["6", "10"]));
+    assert!(string["required"].is_null());
+
+    // Routes whose qualities agree keep a flat schema
+    let image = &doc["paths"]["/generate_image"]["post"]["requestBody"]["content"]["application/json"]["schema"];
+    assert!(image["oneOf"].is_null());
+}
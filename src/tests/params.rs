@@ -0,0 +1,186 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+
+use crate::endpoints::{ParamKind, ParamSpec, PricingRule};
+use crate::{params, pricing};
+
+use super::{harness, harness_with_endpoints, payment_header, post, unique_prompt};
+
+#[actix_web::test]
+async fn invalid_params_are_rejected_before_payment() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": "a cat", "seed": -1, "image_size": "huge", "steps": 50}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "Invalid parameters");
+    let rejected: Vec<&str> = body["details"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["param"].as_str().unwrap())
+        .collect();
+    assert_eq!(rejected, vec!["image_size", "seed", "steps"]);
+    assert!(body["allowed_params"]["seed"].is_object());
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+    assert_eq!(h.fal.request_count(), 0);
+}
+
+#[actix_web::test]
+async fn chosen_values_scale_the_price() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let quote = |body: serde_json::Value| {
+        let req = post("/generate_image", body, None).to_request();
+        let app = &app;
+        async move {
+            let resp = test::call_service(app, req).await;
+            assert_eq!(resp.status(), 402);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            body["accepts"][0]["maxAmountRequired"].as_str().unwrap().to_string()
+        }
+    };
+
+    let base = quote(serde_json::json!({"prompt": "a cat"})).await;
+    let hd = quote(serde_json::json!({"prompt": "a cat", "image_size": "square_hd"})).await;
+    assert_eq!(base, "1000000000000000000000");
+    assert_eq!(hd, "2000000000000000000000");
+}
+
+#[actix_web::test]
+async fn params_reach_the_provider_and_split_the_cache() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("a seeded cat");
+
+    for seed in ["7", "7", "8"] {
+        // Query-string style string values are coerced to the declared kind
+        let resp = test::call_service(
+            &app,
+            post(
+                "/generate_image",
+                serde_json::json!({"prompt": prompt, "seed": seed}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    let requests = h.fal.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].1["seed"], 7);
+    assert_eq!(requests[1].1["seed"], 8);
+    assert_eq!(requests[0].1["num_inference_steps"], 4);
+}
+
+#[actix_web::test]
+async fn pricing_formula_follows_the_requested_duration() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let mut amounts = Vec::new();
+    for body in [
+        serde_json::json!({"quality": "medium"}),
+        serde_json::json!({"quality": "medium", "duration": 10}),
+        serde_json::json!({"quality": "high", "duration": "5"}),
+    ] {
+        let resp = test::call_service(&app, post("/generate_video", body, None).to_request()).await;
+        assert_eq!(resp.status(), 402);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        amounts.push(body["accepts"][0]["maxAmountRequired"].as_str().unwrap().to_string());
+    }
+
+    // 25000/s × 5s default, 25000/s × 10s, 40000/s × 5s
+    assert_eq!(
+        amounts,
+        vec![
+            "125000000000000000000000",
+            "250000000000000000000000",
+            "200000000000000000000000"
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn request_params_defaults_must_be_allowed_values() {
+    let Some(h) = harness().await else { return };
+    for endpoint in h.state.endpoints.iter() {
+        params::check_specs(endpoint).unwrap();
+    }
+
+    let mut endpoint = h.endpoint("/generate_video/medium").clone();
+    endpoint.request_params.insert("duration".to_string(), serde_json::json!(6));
+    let error = params::check_specs(&endpoint).unwrap_err();
+    assert!(error.contains("duration"), "{}", error);
+}
+
+#[actix_web::test]
+async fn formula_params_take_any_float_and_need_a_max() {
+    let spec = ParamSpec {
+        kind: ParamKind::Number,
+        description: None,
+        one_of: Vec::new(),
+        min: Some(0.0),
+        max: Some(1e22),
+        default: Some(serde_json::json!(1)),
+        price_multipliers: Default::default(),
+    };
+    let unbounded = ParamSpec { max: None, ..spec.clone() };
+    let Some(h) = harness_with_endpoints(|endpoints| {
+        let ep = endpoints.iter_mut().find(|ep| ep.path == "/generate_image/low").unwrap();
+        ep.pricing = Some(PricingRule {
+            base: "0".to_string(),
+            per_unit: [("units".to_string(), "1".to_string())].into(),
+        });
+        ep.allowed_params.insert("units".to_string(), spec);
+    })
+    .await
+    else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+
+    // Floats serde_json would print in exponent form are priced, not a config error
+    for (units, amount) in [
+        (serde_json::json!(1e21), "1000000000000000000000000000000000000000000"),
+        (serde_json::json!(2.5e-3), "2500000000000000000"),
+        // Digits past the sixth decimal round the price up, never to nearest
+        (serde_json::json!(1.0000004), "1000001000000000000000"),
+        (serde_json::json!(4.9999996), "5000000000000000000000"),
+    ] {
+        let body = serde_json::json!({"prompt": "a cat", "units": units});
+        let resp = test::call_service(&app, post("/generate_image", body, None).to_request()).await;
+        assert_eq!(resp.status(), 402, "{}", units);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["accepts"][0]["maxAmountRequired"], amount, "{}", units);
+    }
+
+    let mut endpoint = h.endpoint("/generate_image/low").clone();
+    pricing::check_rule(&endpoint).unwrap();
+
+    // A term worth less than the last fixed-point digit still rounds the price up
+    let mut fractional = endpoint.clone();
+    fractional.pricing.as_mut().unwrap().per_unit.insert("units".to_string(), "0.5".to_string());
+    let params = serde_json::json!({"units": 0.000001});
+    let quotes = pricing::quotes(&h.state.config, &fractional, params.as_object().unwrap()).unwrap();
+    assert_eq!(quotes[0].amount.0.to_string(), "1000000000000000");
+    endpoint.allowed_params.insert("units".to_string(), unbounded);
+    let error = pricing::check_rule(&endpoint).unwrap_err();
+    assert!(error.contains("max"), "{}", error);
+}
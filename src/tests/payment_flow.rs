@@ -0,0 +1,436 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::test;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::pipeline::GenerationRequest;
+use crate::{db, jobs};
+
+use super::mocks::{MEDIA_BYTES, MOCK_PAYER, MOCK_TX};
+use super::{TEST_BUCKET, harness, harness_with_storage, payment_header, post, unique_prompt};
+
+fn receipt(resp: &actix_web::dev::ServiceResponse) -> Option<serde_json::Value> {
+    let header = resp.headers().get("X-PAYMENT-RESPONSE")?.to_str().ok()?;
+    serde_json::from_slice(&BASE64.decode(header).ok()?).ok()
+}
+
+#[actix_web::test]
+async fn unpaid_request_gets_402_with_every_scheme() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": "a cat"}), None).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 402);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let schemes: Vec<&str> = body["accepts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["scheme"].as_str().unwrap())
+        .collect();
+    assert_eq!(schemes, vec!["permit", "exact"]);
+    assert_eq!(body["accepts"][0]["resource"], "/generate_image/low");
+    assert_eq!(h.fal.request_count(), 0);
+}
+
+#[actix_web::test]
+async fn paid_generation_uploads_settles_and_returns_receipt() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("a paid cat");
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": prompt, "quality": "low"}),
+            Some(&payment_header("exact")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    let receipt = receipt(&resp).expect("X-PAYMENT-RESPONSE header");
+    assert_eq!(receipt["success"], true);
+    assert_eq!(receipt["transaction"], MOCK_TX);
+    assert_eq!(receipt["payer"], MOCK_PAYER);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["cached"], false);
+    assert_eq!(body["prompt"], prompt);
+
+    // The provider got the endpoint's params plus the prompt, with our key
+    let (model, request) = h.fal.requests.lock().unwrap()[0].clone();
+    assert_eq!(model, "fal-ai/flux/schnell");
+    assert_eq!(request["prompt"], prompt);
+    assert_eq!(request["num_inference_steps"], 4);
+    assert_eq!(h.fal.auth_headers.lock().unwrap()[0], "Key test-fal-key");
+
+    // Verified against the requirement matching the payload's scheme, then settled once
+    let verified = h.facilitator.last_verify.lock().unwrap().clone().unwrap();
+    assert_eq!(verified["paymentRequirements"]["scheme"], "exact");
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+
+    // The media landed in the bucket and in the DB with its payment
+    let url = body["url"].as_str().unwrap();
+    let key = url.split(&format!("/{}/", TEST_BUCKET)).nth(1).unwrap();
+    let stored = h.s3.objects.lock().unwrap()[&format!("{}/{}", TEST_BUCKET, key)].clone();
+    assert_eq!(stored, MEDIA_BYTES);
+
+    let request = GenerationRequest::new(h.endpoint("/generate_image/low"), &prompt, &serde_json::Map::new());
+    let records = crate::db::find_by_cache_key(&h.state.db_pool, &request.cache_key, "/generate_image/low")
+        .await
+        .unwrap();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.payment_tx.as_deref(), Some(MOCK_TX));
+    assert_eq!(record.s3_url, url);
+}
+
+#[actix_web::test]
+async fn cache_hit_skips_the_provider_but_still_settles() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let prompt = unique_prompt("a cached cat");
+    let body = serde_json::json!({"prompt": prompt});
+
+    let first = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(first.status(), 200);
+
+    let second = test::call_service(
+        &app,
+        post("/generate_image", body, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(second.status(), 200);
+    assert!(receipt(&second).is_some());
+    let second: serde_json::Value = test::read_body_json(second).await;
+    assert_eq!(second["cached"], true);
+
+    assert_eq!(h.fal.request_count(), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn every_output_is_stored_and_returned_in_order() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let body = serde_json::json!({"prompt": unique_prompt("three cats"), "num_images": 3});
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body.clone(), Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let first: serde_json::Value = test::read_body_json(resp).await;
+    let urls = first["urls"].as_array().unwrap().clone();
+    assert_eq!(urls.len(), 3);
+    assert_eq!(first["url"], urls[0]);
+    assert_eq!(h.stored_objects(), 3);
+
+    // The siblings come back together, in the same order, on a cache hit
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", body, Some(&payment_header("permit"))).to_request(),
+    )
+    .await;
+    let second: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(second["cached"], true);
+    assert_eq!(second["urls"].as_array().unwrap(), &urls);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn failed_generation_is_never_settled() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    h.fal.fail.store(true, Ordering::SeqCst);
+    let prompt = unique_prompt("a doomed cat");
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": prompt}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 500);
+    assert!(receipt(&resp).is_none());
+
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+    assert_eq!(h.stored_objects(), 0);
+
+    let failures: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM generation_failures WHERE prompt = $1")
+            .bind(&prompt)
+            .fetch_one(&h.state.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(failures, 1);
+}
+
+#[actix_web::test]
+async fn invalid_payment_is_rejected_before_generation() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    h.facilitator.valid.store(false, Ordering::SeqCst);
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": unique_prompt("an unpaid cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 402);
+    assert_eq!(h.fal.request_count(), 0);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn async_job_completes_and_settles() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a running cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    assert!(receipt(&resp).is_none());
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let status_url = job["status_url"].as_str().unwrap().to_string();
+
+    let mut last = serde_json::Value::Null;
+    for _ in 0..50 {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&status_url).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let settled = receipt(&resp);
+        last = test::read_body_json(resp).await;
+        if last["status"] == "succeeded" {
+            assert_eq!(settled.expect("receipt once settled")["transaction"], MOCK_TX);
+            break;
+        }
+        assert_ne!(last["status"], "failed", "job failed: {}", last);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_eq!(last["status"], "succeeded");
+    assert_eq!(last["result"]["type"], "video");
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.stored_objects(), 1);
+}
+
+#[actix_web::test]
+async fn resumed_job_with_settled_payment_is_not_paid_or_generated_again() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a resumed cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let id: uuid::Uuid = job["id"].as_str().unwrap().parse().unwrap();
+    let mut finished = None;
+    for _ in 0..50 {
+        let job = db::find_job(&h.state.db_pool, id).await.unwrap().unwrap();
+        if job.status == jobs::STATUS_SUCCEEDED {
+            finished = Some(job);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let first_url = finished.expect("job succeeded").result.unwrap()["url"].clone();
+
+    // The process died after settling, before the job was marked succeeded
+    sqlx::query(
+        "UPDATE generation_jobs SET status = 'running', result = NULL, payment_tx = NULL, payment_response = NULL
+         WHERE id = $1",
+    )
+    .bind(id)
+    .execute(&h.state.db_pool)
+    .await
+    .unwrap();
+    let job = db::find_job(&h.state.db_pool, id).await.unwrap().unwrap();
+    jobs::resume_job(&h.state, job).await;
+
+    let mut resumed = None;
+    for _ in 0..50 {
+        let job = db::find_job(&h.state.db_pool, id).await.unwrap().unwrap();
+        if job.status != "running" {
+            resumed = Some(job);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let resumed = resumed.expect("resumed job finished");
+    assert_eq!(resumed.status, jobs::STATUS_SUCCEEDED, "job failed: {:?}", resumed.error);
+    assert_eq!(resumed.result.unwrap()["url"], first_url);
+    assert_eq!(resumed.payment_tx.as_deref(), Some(MOCK_TX));
+    assert_eq!(resumed.payment_response.unwrap()["transaction"], MOCK_TX);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+    assert_eq!(h.stored_objects(), 1);
+}
+
+/// Poll a job until it succeeds or fails.
+async fn finished_job(pool: &sqlx::PgPool, id: uuid::Uuid) -> db::JobRecord {
+    for _ in 0..50 {
+        let job = db::find_job(pool, id).await.unwrap().unwrap();
+        if [jobs::STATUS_SUCCEEDED, jobs::STATUS_FAILED].contains(&job.status.as_str()) {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("job {} never finished", id);
+}
+
+/// Put a finished job back to `running` with its payload left mid-settlement, as if the
+/// process died while settling, and resume it.
+async fn resume_mid_settlement(h: &super::Harness, id: uuid::Uuid) -> db::JobRecord {
+    sqlx::query(
+        "UPDATE generation_jobs SET status = 'running', result = NULL, error = NULL, payment_tx = NULL,
+         payment_response = NULL WHERE id = $1",
+    )
+    .bind(id)
+    .execute(&h.state.db_pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "UPDATE payment_payloads SET state = 'settling'
+         WHERE payload_hash = (SELECT payload_hash FROM generation_jobs WHERE id = $1)",
+    )
+    .bind(id)
+    .execute(&h.state.db_pool)
+    .await
+    .unwrap();
+    let job = db::find_job(&h.state.db_pool, id).await.unwrap().unwrap();
+    jobs::resume_job(&h.state, job).await;
+    finished_job(&h.state.db_pool, id).await
+}
+
+#[actix_web::test]
+async fn resumed_job_settled_per_the_ledger_finishes_from_recorded_media() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a half-recorded cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let id: uuid::Uuid = job["id"].as_str().unwrap().parse().unwrap();
+    let first = finished_job(&h.state.db_pool, id).await;
+    assert_eq!(first.status, jobs::STATUS_SUCCEEDED);
+
+    // Settled in the ledger, but the process died before the payload was marked settled
+    let resumed = resume_mid_settlement(&h, id).await;
+    assert_eq!(resumed.status, jobs::STATUS_SUCCEEDED, "job failed: {:?}", resumed.error);
+    assert_eq!(resumed.result.unwrap()["url"], first.result.unwrap()["url"]);
+    assert_eq!(resumed.payment_tx.as_deref(), Some(MOCK_TX));
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn resumed_job_with_unknown_settlement_outcome_fails_without_paying_again() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    // Settle errors out: the outcome is unknown and the job fails
+    h.facilitator.settle_down.store(true, Ordering::SeqCst);
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a limbo cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let id: uuid::Uuid = job["id"].as_str().unwrap().parse().unwrap();
+    assert_eq!(finished_job(&h.state.db_pool, id).await.status, jobs::STATUS_FAILED);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    h.facilitator.settle_down.store(false, Ordering::SeqCst);
+
+    // Resumed while still settling: neither generated nor settled again
+    let resumed = resume_mid_settlement(&h, id).await;
+    assert_eq!(resumed.status, jobs::STATUS_FAILED);
+    assert!(resumed.error.unwrap().contains("outcome is unknown"));
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn local_storage_serves_media_from_the_router() {
+    let dir = std::env::temp_dir().join(format!("x402-media-{}", uuid::Uuid::new_v4()));
+    let Some(h) = harness_with_storage(Some(&dir)).await else { return };
+    let app = test::init_service(h.app()).await;
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_image",
+            serde_json::json!({"prompt": unique_prompt("a local cat")}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(h.stored_objects(), 0);
+
+    let url = body["url"].as_str().unwrap();
+    let path = url.strip_prefix("http://localhost:3402").expect("served under PUBLIC_URL");
+    assert!(path.starts_with("/media/generate_image/low/"));
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(test::read_body(resp).await, MEDIA_BYTES);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,227 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::test;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::{db, jobs, x402};
+
+use super::mocks::MOCK_TX;
+use super::{harness, payment_header, payment_header_from, post, unique_prompt};
+
+async fn payload_state(pool: &sqlx::PgPool, header: &str) -> Option<String> {
+    let payload: serde_json::Value = serde_json::from_slice(&BASE64.decode(header).unwrap()).unwrap();
+    sqlx::query_scalar("SELECT state FROM payment_payloads WHERE payload_hash = $1")
+        .bind(x402::payload_hash(&payload))
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn settled_payload_cannot_be_replayed() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": unique_prompt("a paid cat")}), Some(&payment)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("settled"));
+
+    // Same payload again, even for another prompt or route: refused before the facilitator
+    for route in ["/generate_image", "/generate_video"] {
+        let resp = test::call_service(
+            &app,
+            post(route, serde_json::json!({"prompt": unique_prompt("a free cat")}), Some(&payment)).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 409);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains(MOCK_TX));
+    }
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.fal.request_count(), 1);
+}
+
+#[actix_web::test]
+async fn settled_authorization_cannot_be_replayed_in_a_new_envelope() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header_from("exact", "0x00000000000000000000000000000000000000a1");
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": unique_prompt("a paid cat")}), Some(&payment)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+
+    // Same signed authorization, with an unsigned field added to the envelope
+    let mut rewrapped: serde_json::Value = serde_json::from_slice(&BASE64.decode(&payment).unwrap()).unwrap();
+    rewrapped["extra"] = serde_json::json!("padding");
+    let rewrapped = BASE64.encode(serde_json::to_vec(&rewrapped).unwrap());
+    assert_ne!(rewrapped, payment);
+
+    let resp = test::call_service(
+        &app,
+        post("/generate_image", serde_json::json!({"prompt": unique_prompt("a free cat")}), Some(&rewrapped)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 409);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn unsettled_payload_can_be_presented_again() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let body = serde_json::json!({"prompt": unique_prompt("a retried cat")});
+
+    // Generation fails: never settled, so the same signature may be retried
+    h.fal.fail.store(true, Ordering::SeqCst);
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 500);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("failed"));
+
+    h.fal.fail.store(false, Ordering::SeqCst);
+    let resp = test::call_service(&app, post("/generate_image", body, Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("settled"));
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn payload_with_unknown_settlement_stays_blocked() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let body = serde_json::json!({"prompt": unique_prompt("a limbo cat")});
+
+    // Settle errors out: it may have gone on-chain, so the payload is never reusable
+    h.facilitator.settle_down.store(true, Ordering::SeqCst);
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 502);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("settling"));
+
+    h.facilitator.settle_down.store(false, Ordering::SeqCst);
+    let resp = test::call_service(&app, post("/generate_image", body, Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 409);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn payload_in_use_is_refused_until_its_claim_goes_stale() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let decoded: serde_json::Value = serde_json::from_slice(&BASE64.decode(&payment).unwrap()).unwrap();
+    let hash = x402::payload_hash(&decoded);
+    let body = serde_json::json!({"prompt": unique_prompt("a contested cat")});
+
+    // Another request is verifying it right now
+    sqlx::query("INSERT INTO payment_payloads (payload_hash, state, resource) VALUES ($1, 'verifying', '/generate_image/low')")
+        .bind(&hash)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 409);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+
+    // That request died mid-verification
+    sqlx::query("UPDATE payment_payloads SET updated_at = NOW() - INTERVAL '1 hour' WHERE payload_hash = $1")
+        .bind(&hash)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    let resp = test::call_service(&app, post("/generate_image", body, Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn payload_left_verified_is_released_unless_a_job_holds_it() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let decoded: serde_json::Value = serde_json::from_slice(&BASE64.decode(&payment).unwrap()).unwrap();
+    let hash = x402::payload_hash(&decoded);
+    let body = serde_json::json!({"prompt": unique_prompt("an abandoned cat")});
+
+    // A request verified it, then died before generating or releasing it
+    sqlx::query(
+        "INSERT INTO payment_payloads (payload_hash, state, resource, updated_at)
+         VALUES ($1, 'verified', '/generate_video/low', NOW() - INTERVAL '1 hour')",
+    )
+    .bind(&hash)
+    .execute(&h.state.db_pool)
+    .await
+    .unwrap();
+
+    // An async job still waiting to settle it keeps it, however old
+    let job = db::insert_job(
+        &h.state.db_pool,
+        "/generate_video/low",
+        "low",
+        "an abandoned cat",
+        "hash",
+        "key",
+        &serde_json::json!({}),
+        jobs::STATUS_PENDING,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(&hash),
+    )
+    .await
+    .unwrap();
+    let resp = test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 409);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+
+    // Once no unfinished job holds it, the stale claim is released
+    db::mark_job_failed(&h.state.db_pool, job, "test").await.unwrap();
+    let resp = test::call_service(&app, post("/generate_image", body, Some(&payment)).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("settled"));
+}
+
+#[actix_web::test]
+async fn request_whose_claim_went_stale_mid_generation_does_not_settle() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let payment = payment_header("permit");
+    let decoded: serde_json::Value = serde_json::from_slice(&BASE64.decode(&payment).unwrap()).unwrap();
+    let hash = x402::payload_hash(&decoded);
+    let body = serde_json::json!({"prompt": unique_prompt("a slow cat")});
+
+    h.fal.delay_ms.store(1000, Ordering::SeqCst);
+    let slow = test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request());
+    let takeover = async {
+        // While the first request generates, its claim is taken for a dead one's
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        sqlx::query("UPDATE payment_payloads SET updated_at = NOW() - INTERVAL '1 hour' WHERE payload_hash = $1")
+            .bind(&hash)
+            .execute(&h.state.db_pool)
+            .await
+            .unwrap();
+        h.fal.delay_ms.store(0, Ordering::SeqCst);
+        test::call_service(&app, post("/generate_image", body.clone(), Some(&payment)).to_request()).await
+    };
+    let (slow, takeover) = futures_util::future::join(slow, takeover).await;
+
+    assert_eq!(takeover.status(), 200);
+    assert_eq!(slow.status(), 409);
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(h.facilitator.settle_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(payload_state(&h.state.db_pool, &payment).await.as_deref(), Some("settled"));
+}
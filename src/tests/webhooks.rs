@@ -0,0 +1,145 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::test;
+
+use crate::{outbound, webhooks};
+
+use super::mocks::start_webhook_receiver;
+use super::{TEST_WEBHOOK_SECRET, harness, harness_with_config, payment_header, post, unique_prompt};
+
+#[actix_web::test]
+async fn finished_job_is_posted_signed_and_retried() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+    let (hook_url, receiver) = start_webhook_receiver();
+    receiver.failures_left.store(1, Ordering::SeqCst);
+
+    let resp = test::call_service(
+        &app,
+        post(
+            "/generate_video",
+            serde_json::json!({"prompt": unique_prompt("a hooked cat"), "callback_url": hook_url}),
+            Some(&payment_header("permit")),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 202);
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    let job_id = uuid::Uuid::parse_str(job["id"].as_str().unwrap()).unwrap();
+
+    // First attempt happens as soon as the job finishes, and fails
+    for _ in 0..50 {
+        if !receiver.received.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, attempts, code): (String, i32, Option<i32>) = sqlx::query_as(
+        "SELECT status, attempts, last_status_code FROM webhook_deliveries WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_one(&h.state.db_pool)
+    .await
+    .unwrap();
+    assert_eq!((status.as_str(), attempts, code), ("pending", 1, Some(500)));
+
+    // Once the backoff is due, the worker delivers it
+    sqlx::query("UPDATE webhook_deliveries SET next_attempt_at = NOW() WHERE job_id = $1")
+        .bind(job_id)
+        .execute(&h.state.db_pool)
+        .await
+        .unwrap();
+    webhooks::deliver_due(&h.state).await;
+
+    let (status, attempts): (String, i32) =
+        sqlx::query_as("SELECT status, attempts FROM webhook_deliveries WHERE job_id = $1")
+            .bind(job_id)
+            .fetch_one(&h.state.db_pool)
+            .await
+            .unwrap();
+    assert_eq!((status.as_str(), attempts), ("delivered", 2));
+    let logged: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM webhook_attempts a JOIN webhook_deliveries d ON d.id = a.delivery_id WHERE d.job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_one(&h.state.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(logged, 2);
+
+    // The body is the finished job, signed over "<t>.<body>"
+    let (signature, body) = receiver.received.lock().unwrap().last().cloned().unwrap();
+    let timestamp: i64 = signature
+        .strip_prefix("t=")
+        .and_then(|s| s.split(',').next())
+        .and_then(|t| t.parse().ok())
+        .unwrap();
+    assert_eq!(signature, webhooks::sign(TEST_WEBHOOK_SECRET, timestamp, &body));
+    let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(payload["id"], job["id"]);
+    assert_eq!(payload["status"], "succeeded");
+    assert!(payload["result"]["url"].is_string());
+}
+
+#[actix_web::test]
+async fn callback_url_is_checked_before_payment() {
+    let Some(h) = harness().await else { return };
+    let app = test::init_service(h.app()).await;
+
+    for (route, url) in [
+        ("/generate_image", "https://example.com/hook"),
+        ("/generate_video", "ftp://example.com/hook"),
+        ("/generate_video", "not a url"),
+    ] {
+        let resp = test::call_service(
+            &app,
+            post(
+                route,
+                serde_json::json!({"prompt": "a cat", "callback_url": url}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 400, "{} {}", route, url);
+    }
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn callback_url_to_a_private_address_is_refused() {
+    let Some(h) = harness_with_config(|config| config.allow_private_urls = false).await else {
+        return;
+    };
+    let app = test::init_service(h.app()).await;
+
+    for url in [
+        "http://127.0.0.1/hook",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://10.0.0.5/hook",
+        "http://[::1]/hook",
+        "http://localhost:8080/hook",
+    ] {
+        let resp = test::call_service(
+            &app,
+            post(
+                "/generate_video",
+                serde_json::json!({"prompt": "a cat", "callback_url": url}),
+                Some(&payment_header("permit")),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), 400, "{}", url);
+    }
+    assert_eq!(h.facilitator.verify_calls.load(Ordering::SeqCst), 0);
+
+    // Deliveries re-resolve when connecting, so a host that now points inward is refused too
+    let (hook_url, receiver) = start_webhook_receiver();
+    let rebound = hook_url.replace("127.0.0.1", "localhost");
+    assert!(outbound::client(false).post(&rebound).send().await.is_err());
+    assert!(receiver.received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::broadcast;
+
+use crate::AppState;
+use crate::config::Config;
+use crate::db::{self, JobRecord, WebhookDelivery};
+use crate::jobs::JobResponse;
+use crate::outbound;
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`, keyed with WEBHOOK_SECRET.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Id";
+
+/// Attempts before a delivery is given up on.
+const MAX_ATTEMPTS: i32 = 8;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay after the first failure; tripled after each further one, up to `MAX_BACKOFF`.
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+/// How long a claimed delivery is hidden from other workers while it is attempted.
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+const WORKER_INTERVAL: Duration = Duration::from_secs(15);
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_DELIVERED: &str = "delivered";
+const STATUS_FAILED: &str = "failed";
+
+/// Check a requested callback URL before payment. Its host must resolve to public addresses.
+pub async fn check_callback_url(config: &Config, url: &str) -> Result<(), String> {
+    if config.webhook_secret.is_none() {
+        return Err("Webhooks are not enabled on this router (callback_url)".to_string());
+    }
+    outbound::check_url(url, "callback_url", config.allow_private_urls).await?;
+    Ok(())
+}
+
+/// Signature header value for a body sent at `timestamp`.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let factor = 3u32.saturating_pow(attempts.saturating_sub(1) as u32);
+    BASE_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Queue the final job state for its callback URL, if it has one, and attempt it right away.
+pub async fn notify_job(state: &web::Data<AppState>, job: JobRecord) {
+    let Some(url) = job.callback_url.clone() else { return };
+    let job_id = job.id;
+    let payload = serde_json::to_value(JobResponse::from(job)).unwrap_or_default();
+
+    match db::insert_webhook_delivery(&state.db_pool, job_id, &url, &payload, CLAIM_LEASE).await {
+        Ok(delivery) => {
+            tracing::info!("[job {}] Webhook {} queued for {}", job_id, delivery.id, url);
+            tokio::spawn(attempt(state.clone(), delivery));
+        }
+        Err(e) => tracing::error!("[job {}] Failed to queue webhook: {}", job_id, e),
+    }
+}
+
+/// POST a delivery once and record the outcome, scheduling a retry on failure.
+async fn attempt(state: web::Data<AppState>, delivery: WebhookDelivery) {
+    let Some(secret) = state.config.webhook_secret.as_deref() else {
+        tracing::warn!("[webhook {}] WEBHOOK_SECRET is no longer set; leaving it pending", delivery.id);
+        return;
+    };
+    let attempt = delivery.attempts + 1;
+    let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+    let signature = sign(secret, chrono::Utc::now().timestamp(), &body);
+
+    // Checked again on every attempt: the host may resolve elsewhere than when it was accepted
+    let started = Instant::now();
+    let result = match outbound::check_url(&delivery.url, "callback_url", state.config.allow_private_urls).await {
+        Ok(_) => state
+            .outbound_client
+            .post(&delivery.url)
+            .timeout(ATTEMPT_TIMEOUT)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("Request failed: {}", e)),
+        Err(e) => Err(format!("Refused: {}", e)),
+    };
+    let duration_ms = started.elapsed().as_millis() as i64;
+
+    let (status_code, error) = match result {
+        Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16() as i32), None),
+        Ok(resp) => (
+            Some(resp.status().as_u16() as i32),
+            Some(format!("Receiver answered {}", resp.status())),
+        ),
+        Err(e) => (None, Some(e)),
+    };
+
+    if let Err(e) = db::insert_webhook_attempt(
+        &state.db_pool,
+        delivery.id,
+        attempt,
+        status_code,
+        error.as_deref(),
+        duration_ms,
+    )
+    .await
+    {
+        tracing::error!("[webhook {}] Failed to record attempt: {}", delivery.id, e);
+    }
+
+    let (status, retry_in) = match &error {
+        None => {
+            tracing::info!("[webhook {}] Delivered to {} (attempt {})", delivery.id, delivery.url, attempt);
+            (STATUS_DELIVERED, None)
+        }
+        Some(e) if attempt >= MAX_ATTEMPTS => {
+            tracing::error!("[webhook {}] Giving up after {} attempts: {}", delivery.id, attempt, e);
+            (STATUS_FAILED, None)
+        }
+        Some(e) => {
+            let delay = backoff(attempt);
+            tracing::warn!(
+                "[webhook {}] Attempt {} failed: {}; retrying in {}s",
+                delivery.id,
+                attempt,
+                e,
+                delay.as_secs()
+            );
+            (STATUS_PENDING, Some(delay))
+        }
+    };
+
+    if let Err(e) = db::update_webhook_delivery(
+        &state.db_pool,
+        delivery.id,
+        status,
+        attempt,
+        status_code,
+        error.as_deref(),
+        retry_in,
+    )
+    .await
+    {
+        tracing::error!("[webhook {}] Failed to record delivery status: {}", delivery.id, e);
+    }
+}
+
+/// Attempt every delivery whose retry is due.
+pub async fn deliver_due(state: &web::Data<AppState>) {
+    let due = match db::claim_due_webhooks(&state.db_pool, 50, CLAIM_LEASE).await {
+        Ok(due) => due,
+        Err(e) => {
+            tracing::error!("Failed to query due webhooks: {}", e);
+            return;
+        }
+    };
+    for delivery in due {
+        attempt(state.clone(), delivery).await;
+    }
+}
+
+pub async fn run_webhook_worker(state: web::Data<AppState>, mut shutdown: broadcast::Receiver<()>) {
+    let mut interval = tokio::time::interval(WORKER_INTERVAL);
+
+    tracing::info!("Webhook worker started (runs every {}s)", WORKER_INTERVAL.as_secs());
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                deliver_due(&state).await;
+            }
+            _ = shutdown.recv() => {
+                tracing::info!("Webhook worker shutting down");
+                break;
+            }
+        }
+    }
+}
@@ -1,14 +1,49 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
 use actix_web::http::header::HeaderMap;
 use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use serde::{Deserialize, Serialize};
 
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
 use crate::config::Config;
-use crate::domain_types::DomainU256;
+use crate::db::{self, NewPayment};
+use crate::pricing::Quote;
 
 // ── x402 Protocol Types ──
 
+/// Payment schemes an asset can be paid with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentScheme {
+    /// ERC-20 permit signature, pulled by the facilitator signer.
+    Permit,
+    /// Standard x402 `exact` scheme: EIP-3009 transferWithAuthorization (e.g. USDC).
+    Exact,
+}
+
+impl PaymentScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentScheme::Permit => "permit",
+            PaymentScheme::Exact => "exact",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "permit" => Ok(PaymentScheme::Permit),
+            "exact" => Ok(PaymentScheme::Exact),
+            other => Err(format!("Unknown payment scheme '{}'", other)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PaymentRequiredResponse {
@@ -62,48 +97,134 @@ pub struct SettleResponse {
     pub error_reason: Option<String>,
     #[serde(default)]
     pub payer: Option<String>,
+    /// Row of this settlement in the payments ledger; not part of the receipt.
+    #[serde(skip)]
+    pub payment_id: Option<Uuid>,
 }
 
 // ── Helpers ──
 
 fn build_payment_requirements(
     config: &Config,
-    amount: DomainU256,
+    quote: &Quote,
+    scheme: PaymentScheme,
     resource: &str,
     description: &str,
+    max_timeout_seconds: u64,
 ) -> PaymentRequirements {
+    let asset = quote.asset;
+    let mut extra = match scheme {
+        PaymentScheme::Permit => serde_json::json!({
+            "token": asset.symbol,
+            "address": asset.address,
+            "decimals": asset.decimals,
+            "name": asset.name,
+            "version": asset.version,
+            "facilitatorSigner": config.facilitator_signer,
+            "minimum_amount": true
+        }),
+        // EIP-712 domain of the token, as expected by `exact` EVM clients
+        PaymentScheme::Exact => serde_json::json!({
+            "name": asset.name,
+            "version": asset.version
+        }),
+    };
+    // Whether the price is the endpoint's cached one, so clients see why it differs
+    if let Some(cache_hit) = quote.cache_hit {
+        extra["cacheHit"] = serde_json::Value::Bool(cache_hit);
+    }
+
     PaymentRequirements {
-        scheme: "permit".to_string(),
-        network: config.payment_network.clone(),
-        max_amount_required: amount.to_string(),
+        scheme: scheme.as_str().to_string(),
+        network: asset.network.clone(),
+        max_amount_required: quote.amount.to_string(),
         resource: resource.to_string(),
         description: description.to_string(),
         mime_type: "application/json".to_string(),
-        pay_to: config.wallet_address.clone(),
-        max_timeout_seconds: 300,
-        asset: config.payment_token_address.clone(),
-        extra: Some(serde_json::json!({
-            "token": config.payment_token_symbol,
-            "address": config.payment_token_address,
-            "decimals": config.payment_token_decimals,
-            "name": config.payment_token_name,
-            "version": config.payment_token_version,
-            "facilitatorSigner": config.facilitator_signer,
-            "minimum_amount": true
-        })),
+        pay_to: asset.pay_to.clone().unwrap_or_else(|| config.wallet_address.clone()),
+        max_timeout_seconds,
+        asset: asset.address.clone(),
+        extra: Some(extra),
     }
 }
 
+/// One requirement per (asset, scheme) pair, in configured order.
+pub fn build_accepts(
+    config: &Config,
+    quotes: &[Quote],
+    resource: &str,
+    description: &str,
+    max_timeout_seconds: u64,
+) -> Vec<PaymentRequirements> {
+    quotes
+        .iter()
+        .flat_map(|quote| {
+            quote.asset.schemes.iter().map(move |scheme| {
+                build_payment_requirements(
+                    config,
+                    quote,
+                    *scheme,
+                    resource,
+                    description,
+                    max_timeout_seconds,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Requirements an X-PAYMENT payload may have been signed against, matched on its
+/// `scheme`, `network` and (when present) `asset`. Several assets can share a scheme and
+/// network, so more than one candidate may remain; the facilitator decides which is valid.
+/// Payloads without a scheme are accepted only when a single requirement is on offer.
+fn select_requirements(
+    accepts: &[PaymentRequirements],
+    payment_payload: &serde_json::Value,
+) -> Result<Vec<PaymentRequirements>, String> {
+    let scheme = payment_payload.get("scheme").and_then(|s| s.as_str());
+    let network = payment_payload.get("network").and_then(|n| n.as_str());
+    let asset = payment_payload.get("asset").and_then(|a| a.as_str());
+
+    let Some(scheme) = scheme else {
+        return match accepts {
+            [only] => Ok(vec![only.clone()]),
+            _ => Err("Payment payload is missing 'scheme'".to_string()),
+        };
+    };
+
+    let candidates: Vec<PaymentRequirements> = accepts
+        .iter()
+        .filter(|r| r.scheme == scheme)
+        .filter(|r| network.is_none_or(|n| r.network == n))
+        .filter(|r| asset.is_none_or(|a| r.asset.eq_ignore_ascii_case(a)))
+        .cloned()
+        .collect();
+
+    if candidates.is_empty() {
+        let offered: Vec<String> = accepts
+            .iter()
+            .map(|r| format!("{}/{}/{}", r.scheme, r.network, r.asset))
+            .collect();
+        return Err(format!(
+            "Unsupported payment scheme '{}' on network '{}'. Accepted: {:?}",
+            scheme,
+            network.unwrap_or("-"),
+            offered
+        ));
+    }
+    Ok(candidates)
+}
+
 fn payment_required_response(
     config: &Config,
-    amount: DomainU256,
+    quotes: &[Quote],
     resource: &str,
     description: &str,
+    max_timeout_seconds: u64,
 ) -> HttpResponse {
-    let requirements = build_payment_requirements(config, amount, resource, description);
     let response = PaymentRequiredResponse {
         x402_version: 1,
-        accepts: vec![requirements],
+        accepts: build_accepts(config, quotes, resource, description, max_timeout_seconds),
         error: None,
     };
 
@@ -120,12 +241,11 @@ fn error_response(status: StatusCode, message: &str) -> HttpResponse {
 
 async fn verify_payment(
     http_client: &reqwest::Client,
-    facilitator_url: &str,
+    url: &str,
     verify_request: &VerifyRequest,
 ) -> Result<VerifyResponse, String> {
-    let url = format!("{}/verify", facilitator_url);
     let response = http_client
-        .post(&url)
+        .post(url)
         .json(verify_request)
         .send()
         .await
@@ -145,12 +265,11 @@ async fn verify_payment(
 
 async fn settle_payment(
     http_client: &reqwest::Client,
-    facilitator_url: &str,
+    url: &str,
     settle_request: &VerifyRequest,
 ) -> Result<SettleResponse, String> {
-    let url = format!("{}/settle", facilitator_url);
     let response = http_client
-        .post(&url)
+        .post(url)
         .json(settle_request)
         .send()
         .await
@@ -170,87 +289,451 @@ async fn settle_payment(
 
 // ── Public API ──
 
-/// Check X-PAYMENT header, verify with facilitator, and settle.
-/// Returns Ok((tx_hash, payer_address)) on success, Err(HttpResponse) if payment is missing/invalid.
-/// When TEST_MODE is enabled, payment is skipped entirely.
-pub async fn require_x402_payment(
+/// Response header carrying the base64-encoded settlement receipt.
+pub const PAYMENT_RESPONSE_HEADER: &str = "X-PAYMENT-RESPONSE";
+
+/// Encode a settlement as the value of the X-PAYMENT-RESPONSE header.
+pub fn encode_payment_response(settlement: &SettleResponse) -> String {
+    BASE64.encode(serde_json::to_vec(settlement).unwrap_or_default())
+}
+
+/// A payment the facilitator has verified but that has not been settled yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedPayment {
+    pub request: VerifyRequest,
+    pub payer: Option<String>,
+    /// Row of the successful verification in the payments ledger.
+    #[serde(default)]
+    pub payment_id: Option<Uuid>,
+}
+
+/// Authorization fields signed under each scheme, as (name hashed, names it may have in the
+/// payload). Payloads without a recognised scheme are read as permits.
+fn signed_fields(scheme: Option<PaymentScheme>) -> &'static [(&'static str, &'static [&'static str])] {
+    match scheme {
+        Some(PaymentScheme::Exact) => &[
+            ("from", &["from"]),
+            ("to", &["to"]),
+            ("value", &["value"]),
+            ("validAfter", &["validAfter"]),
+            ("validBefore", &["validBefore"]),
+            ("nonce", &["nonce"]),
+        ],
+        Some(PaymentScheme::Permit) | None => &[
+            ("owner", &["owner", "from"]),
+            ("spender", &["spender", "to"]),
+            ("value", &["value"]),
+            ("deadline", &["deadline", "validBefore"]),
+            ("nonce", &["nonce"]),
+        ],
+    }
+}
+
+/// sha256 identifying the signed payment in a payload across its verify and settle calls: the
+/// signature plus its scheme's authorization fields. Unsigned envelope fields are left out, so
+/// re-wrapping the same authorization can't make it look new. Unsigned payloads are hashed whole.
+pub fn payload_hash(payload: &serde_json::Value) -> String {
+    let inner = &payload["payload"];
+    let Some(signature) = inner.get("signature").and_then(|s| s.as_str()) else {
+        return hex::encode(Sha256::digest(serde_json::to_vec(payload).unwrap_or_default()));
+    };
+    // `exact` nests the signed fields under `authorization`; accept them flat as well
+    let authorization = inner.get("authorization").unwrap_or(inner);
+    let scheme = payload
+        .get("scheme")
+        .and_then(|s| s.as_str())
+        .and_then(|s| PaymentScheme::parse(s).ok());
+
+    let mut h = Sha256::new();
+    h.update(signature.trim().to_lowercase().as_bytes());
+    for (name, aliases) in signed_fields(scheme) {
+        // Addresses and hex are case-insensitive; amounts may be sent as strings or numbers
+        let value = match aliases.iter().find_map(|a| authorization.get(*a)) {
+            Some(serde_json::Value::String(s)) => s.trim().to_lowercase(),
+            Some(serde_json::Value::Null) | None => String::new(),
+            Some(other) => other.to_string(),
+        };
+        h.update(format!("\n{}={}", name, value).as_bytes());
+    }
+    hex::encode(h.finalize())
+}
+
+// Replay protection: every payload presented is claimed in `payment_payloads` before the
+// facilitator sees it, and can only be presented again once it is known to be unsettled.
+const PAYLOAD_VERIFYING: &str = "verifying";
+const PAYLOAD_VERIFIED: &str = "verified";
+const PAYLOAD_SETTLING: &str = "settling";
+const PAYLOAD_SETTLED: &str = "settled";
+const PAYLOAD_FAILED: &str = "failed";
+/// A payload left verifying, or verified but never settled or released, after this long is
+/// assumed to have died with its request. Sync payments are only requested valid for as long.
+const STALE_CLAIM: Duration = Duration::from_secs(300);
+/// How often a request still generating refreshes its claim, well within `STALE_CLAIM`.
+const CLAIM_REFRESH: Duration = Duration::from_secs(60);
+
+/// Claim a payload for this request, or answer 409 if it was settled or is in use elsewhere.
+async fn claim_payload(db_pool: &PgPool, hash: &str, resource: &str) -> Result<(), HttpResponse> {
+    match db::claim_payment_payload(db_pool, hash, resource, STALE_CLAIM).await {
+        Ok(None) => Ok(()),
+        Ok(Some(existing)) if existing.state == PAYLOAD_SETTLED => {
+            tracing::warn!("Replayed payment payload {} (settled for {})", hash, existing.resource);
+            Err(error_response(
+                StatusCode::CONFLICT,
+                &format!(
+                    "Payment already settled (transaction {}); sign a new payment",
+                    existing.tx_hash.as_deref().unwrap_or("unknown")
+                ),
+            ))
+        }
+        Ok(Some(existing)) => {
+            tracing::warn!("Payment payload {} presented while {} for {}", hash, existing.state, existing.resource);
+            Err(error_response(
+                StatusCode::CONFLICT,
+                "Payment is already being used by another request",
+            ))
+        }
+        Err(e) => {
+            tracing::error!("Failed to claim payment payload: {}", e);
+            Err(error_response(StatusCode::SERVICE_UNAVAILABLE, "Payment replay check unavailable"))
+        }
+    }
+}
+
+/// Move a payload to `state` if it is in one of `from`; false if it was not (or the DB failed).
+async fn set_payload_state(db_pool: &PgPool, hash: &str, from: &[&str], state: &str, tx_hash: Option<&str>) -> bool {
+    match db::update_payment_payload(db_pool, hash, from, state, tx_hash).await {
+        Ok(moved) => moved,
+        Err(e) => {
+            tracing::error!("Failed to mark payment payload {} as {}: {}", hash, state, e);
+            false
+        }
+    }
+}
+
+/// Run `work` while keeping a verified payment's claim fresh, so a generation that outlasts
+/// `STALE_CLAIM` is never taken for a dead request and its payload claimed by another.
+pub async fn keep_claimed<F: Future>(db_pool: &PgPool, payment: Option<&VerifiedPayment>, work: F) -> F::Output {
+    let Some(payment) = payment else {
+        return work.await;
+    };
+    let hash = payload_hash(&payment.request.payment_payload);
+    let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + CLAIM_REFRESH, CLAIM_REFRESH);
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            output = &mut work => return output,
+            _ = refresh.tick() => {
+                set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFIED], PAYLOAD_VERIFIED, None).await;
+            }
+        }
+    }
+}
+
+/// Give up on a verified payment that will never be settled (the generation failed), so the
+/// client may present the same payload again.
+pub async fn release_payment(db_pool: &PgPool, payment: &VerifiedPayment) {
+    let hash = payload_hash(&payment.request.payment_payload);
+    set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFIED], PAYLOAD_FAILED, None).await;
+}
+
+/// `payload_hash` of the X-PAYMENT header, if it holds a decodable payload.
+pub fn header_payload_hash(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get("X-PAYMENT")?.to_str().ok()?;
+    let payload: serde_json::Value = serde_json::from_slice(&BASE64.decode(header).ok()?).ok()?;
+    Some(payload_hash(&payload))
+}
+
+/// Whether a presented payload may have been charged: it was verified and not released.
+/// An unknown settle outcome counts as charged.
+pub async fn payload_may_be_charged(db_pool: &PgPool, hash: &str) -> bool {
+    match db::find_payment_payload(db_pool, hash).await {
+        Ok(payload) => payload.is_some_and(|p| {
+            [PAYLOAD_VERIFIED, PAYLOAD_SETTLING, PAYLOAD_SETTLED].contains(&p.state.as_str())
+        }),
+        Err(e) => {
+            tracing::error!("Failed to look up payment payload {}: {}", hash, e);
+            true
+        }
+    }
+}
+
+/// The settlement of a verified payment made by a run that died before finishing its job;
+/// None while it has not been settled. Rebuilt from the payments ledger, falling back to the
+/// payload's own tx hash when the ledger row is missing. A payload left `settling` is settled
+/// if the ledger recorded it so; otherwise its outcome is unknown and this is an error, since
+/// settling it again could charge twice.
+pub async fn find_settlement(db_pool: &PgPool, payment: &VerifiedPayment) -> Result<Option<SettleResponse>, String> {
+    let hash = payload_hash(&payment.request.payment_payload);
+    let payload = match db::find_payment_payload(db_pool, &hash).await {
+        Ok(Some(payload)) if [PAYLOAD_SETTLING, PAYLOAD_SETTLED].contains(&payload.state.as_str()) => payload,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            tracing::error!("Failed to look up payment payload {}: {}", hash, e);
+            return Ok(None);
+        }
+    };
+    let row = match db::find_settlement(db_pool, &hash).await {
+        Ok(row) => row,
+        Err(e) => {
+            tracing::error!("Failed to look up settlement of {}: {}", hash, e);
+            None
+        }
+    };
+    if payload.state == PAYLOAD_SETTLING {
+        let Some((_, _, _, tx_hash)) = &row else {
+            return Err(
+                "Payment settlement was interrupted and its outcome is unknown; not generating again".to_string(),
+            );
+        };
+        set_payload_state(db_pool, &hash, &[PAYLOAD_SETTLING], PAYLOAD_SETTLED, tx_hash.as_deref()).await;
+    }
+    Ok(Some(match row {
+        Some((id, network, payer, tx_hash)) => SettleResponse {
+            success: true,
+            network,
+            transaction: tx_hash.or(payload.tx_hash),
+            error_reason: None,
+            payer: payer.or_else(|| payment.payer.clone()),
+            payment_id: Some(id),
+        },
+        None => SettleResponse {
+            success: true,
+            network: payment.request.payment_requirements.network.clone(),
+            transaction: payload.tx_hash,
+            error_reason: None,
+            payer: payment.payer.clone(),
+            payment_id: None,
+        },
+    }))
+}
+
+/// Append a facilitator call to the payments ledger. A ledger failure never blocks the payment.
+async fn record_payment(db_pool: &PgPool, payment: NewPayment<'_>) -> Option<Uuid> {
+    match db::insert_payment(db_pool, &payment).await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            tracing::error!("Failed to record {} in the payments ledger: {}", payment.operation, e);
+            None
+        }
+    }
+}
+
+/// Why a verified payment could not be settled.
+#[derive(Debug, Clone)]
+pub enum SettlementError {
+    /// The facilitator could not be reached or returned an error status.
+    Facilitator(String),
+    /// The facilitator refused to settle the payment.
+    Rejected(String),
+    /// The payload is no longer this request's to settle: its claim went stale and another
+    /// request took it over. Never sent to the facilitator.
+    Reclaimed,
+}
+
+impl SettlementError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            SettlementError::Facilitator(e) => error_response(StatusCode::BAD_GATEWAY, e),
+            SettlementError::Rejected(reason) => error_response(
+                StatusCode::PAYMENT_REQUIRED,
+                &format!("Settlement failed: {}", reason),
+            ),
+            SettlementError::Reclaimed => error_response(
+                StatusCode::CONFLICT,
+                "Payment was claimed by another request; sign a new payment",
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SettlementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettlementError::Facilitator(e) => write!(f, "{}", e),
+            SettlementError::Rejected(reason) => write!(f, "Settlement failed: {}", reason),
+            SettlementError::Reclaimed => write!(f, "Payment was claimed by another request"),
+        }
+    }
+}
+
+/// Check the X-PAYMENT header and verify it with the facilitator, without settling.
+/// The payload is matched against every (asset, scheme) requirement built from `quotes`.
+/// Returns Ok(Some(payment)) once verified, Err(HttpResponse) if payment is missing/invalid.
+/// When TEST_MODE is enabled, payment is skipped entirely and Ok(None) is returned.
+/// Every facilitator call is recorded in the payments ledger.
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_x402_payment(
     config: &Config,
     http_client: &reqwest::Client,
+    db_pool: &PgPool,
     headers: &HeaderMap,
-    amount: DomainU256,
+    quotes: &[Quote<'_>],
     resource: &str,
     description: &str,
-) -> Result<(Option<String>, Option<String>), HttpResponse> {
+    max_timeout_seconds: u64,
+) -> Result<Option<VerifiedPayment>, HttpResponse> {
     if config.test_mode {
         tracing::debug!("TEST_MODE: skipping x402 payment for {}", resource);
-        return Ok((None, None));
+        return Ok(None);
     }
 
     let payment_header = headers.get("X-PAYMENT").and_then(|v| v.to_str().ok());
 
-    match payment_header {
-        None => Err(payment_required_response(config, amount, resource, description)),
-        Some(payment) => {
-            let requirements = build_payment_requirements(config, amount, resource, description);
+    let Some(payment) = payment_header else {
+        return Err(payment_required_response(
+            config,
+            quotes,
+            resource,
+            description,
+            max_timeout_seconds,
+        ));
+    };
 
-            let payload_bytes = BASE64.decode(payment).map_err(|e| {
-                error_response(
-                    StatusCode::BAD_REQUEST,
-                    &format!("Invalid payment encoding: {}", e),
-                )
-            })?;
-
-            let payment_payload: serde_json::Value =
-                serde_json::from_slice(&payload_bytes).map_err(|e| {
-                    error_response(
-                        StatusCode::BAD_REQUEST,
-                        &format!("Invalid payment JSON: {}", e),
-                    )
-                })?;
-
-            let verify_request = VerifyRequest {
-                x402_version: 1,
-                payment_payload,
-                payment_requirements: requirements,
-            };
-
-            let verify_resp =
-                verify_payment(http_client, &config.facilitator_url, &verify_request)
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("Verification error: {}", e);
-                        error_response(StatusCode::BAD_GATEWAY, &e)
-                    })?;
-
-            if !verify_resp.is_valid {
-                let reason = verify_resp.invalid_reason.unwrap_or_default();
-                tracing::warn!("Payment invalid: {}", reason);
-                return Err(error_response(
-                    StatusCode::PAYMENT_REQUIRED,
-                    &format!("Payment invalid: {}", reason),
-                ));
-            }
+    let payload_bytes = BASE64.decode(payment).map_err(|e| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Invalid payment encoding: {}", e),
+        )
+    })?;
+
+    let payment_payload: serde_json::Value =
+        serde_json::from_slice(&payload_bytes).map_err(|e| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid payment JSON: {}", e),
+            )
+        })?;
+
+    let accepts = build_accepts(config, quotes, resource, description, max_timeout_seconds);
+    let candidates = select_requirements(&accepts, &payment_payload)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e))?;
+
+    let x402_version = payment_payload
+        .get("x402Version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1) as u32;
+
+    let verify_url = config.facilitator_endpoint(&config.facilitator_verify_path);
+    let hash = payload_hash(&payment_payload);
+    claim_payload(db_pool, &hash, resource).await?;
+    let mut invalid_reason = String::new();
+    for requirements in candidates {
+        let verify_request = VerifyRequest {
+            x402_version,
+            payment_payload: payment_payload.clone(),
+            payment_requirements: requirements,
+        };
 
-            // Settle synchronously
-            let settle_resp =
-                settle_payment(http_client, &config.facilitator_url, &verify_request)
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("Settlement error: {}", e);
-                        error_response(StatusCode::BAD_GATEWAY, &e)
-                    })?;
-
-            if settle_resp.success {
-                tracing::info!("Payment settled: {:?}", settle_resp.transaction);
-                Ok((settle_resp.transaction, settle_resp.payer))
-            } else {
-                let reason = settle_resp.error_reason.unwrap_or_default();
-                tracing::error!("Settlement failed: {}", reason);
-                Err(error_response(
-                    StatusCode::PAYMENT_REQUIRED,
-                    &format!("Settlement failed: {}", reason),
-                ))
+        let started = Instant::now();
+        let result = verify_payment(http_client, &verify_url, &verify_request).await;
+        let mut entry = NewPayment {
+            operation: "verify",
+            outcome: "error",
+            requirements: &verify_request.payment_requirements,
+            payload_hash: &hash,
+            payer_address: None,
+            tx_hash: None,
+            error: None,
+            verification_id: None,
+            latency: started.elapsed(),
+        };
+        let verify_resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                tracing::error!("Verification error: {}", e);
+                entry.error = Some(&e);
+                record_payment(db_pool, entry).await;
+                set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFYING], PAYLOAD_FAILED, None).await;
+                return Err(error_response(StatusCode::BAD_GATEWAY, &e));
             }
+        };
+        entry.payer_address = verify_resp.payer.as_deref();
+        entry.outcome = if verify_resp.is_valid { "verified" } else { "invalid" };
+        entry.error = verify_resp.invalid_reason.as_deref();
+        let payment_id = record_payment(db_pool, entry).await;
+
+        if verify_resp.is_valid {
+            tracing::info!(
+                "Payment verified for {} via {} {} on {} (payer: {:?})",
+                resource,
+                verify_request.payment_requirements.scheme,
+                verify_request.payment_requirements.asset,
+                verify_request.payment_requirements.network,
+                verify_resp.payer
+            );
+            set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFYING], PAYLOAD_VERIFIED, None).await;
+            return Ok(Some(VerifiedPayment {
+                request: verify_request,
+                payer: verify_resp.payer,
+                payment_id,
+            }));
+        }
+        invalid_reason = verify_resp.invalid_reason.unwrap_or_default();
+    }
+
+    tracing::warn!("Payment invalid: {}", invalid_reason);
+    set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFYING], PAYLOAD_FAILED, None).await;
+    Err(error_response(
+        StatusCode::PAYMENT_REQUIRED,
+        &format!("Payment invalid: {}", invalid_reason),
+    ))
+}
+
+/// Settle a previously verified payment. Call only once the paid-for content has been produced.
+/// The attempt is recorded in the payments ledger; the receipt carries its row id.
+pub async fn settle_x402_payment(
+    config: &Config,
+    http_client: &reqwest::Client,
+    db_pool: &PgPool,
+    payment: &VerifiedPayment,
+) -> Result<SettleResponse, SettlementError> {
+    let settle_url = config.facilitator_endpoint(&config.facilitator_settle_path);
+    let hash = payload_hash(&payment.request.payment_payload);
+    if !set_payload_state(db_pool, &hash, &[PAYLOAD_VERIFIED], PAYLOAD_SETTLING, None).await {
+        tracing::error!("Payment payload {} is no longer verified for this request; not settling", hash);
+        return Err(SettlementError::Reclaimed);
+    }
+    let started = Instant::now();
+    let result = settle_payment(http_client, &settle_url, &payment.request).await;
+    let mut entry = NewPayment {
+        operation: "settle",
+        outcome: "error",
+        requirements: &payment.request.payment_requirements,
+        payload_hash: &hash,
+        payer_address: payment.payer.as_deref(),
+        tx_hash: None,
+        error: None,
+        verification_id: payment.payment_id,
+        latency: started.elapsed(),
+    };
+    let mut settle_resp = match result {
+        Ok(resp) => resp,
+        Err(e) => {
+            // It may still have gone on-chain: the payload stays `settling`, never reusable
+            tracing::error!("Settlement error: {}", e);
+            entry.error = Some(&e);
+            record_payment(db_pool, entry).await;
+            return Err(SettlementError::Facilitator(e));
         }
+    };
+    entry.outcome = if settle_resp.success { "settled" } else { "rejected" };
+    entry.payer_address = settle_resp.payer.as_deref().or(entry.payer_address);
+    entry.tx_hash = settle_resp.transaction.as_deref();
+    entry.error = settle_resp.error_reason.as_deref();
+    settle_resp.payment_id = record_payment(db_pool, entry).await;
+    if settle_resp.success {
+        let tx_hash = settle_resp.transaction.as_deref();
+        set_payload_state(db_pool, &hash, &[PAYLOAD_SETTLING], PAYLOAD_SETTLED, tx_hash).await;
+    } else {
+        set_payload_state(db_pool, &hash, &[PAYLOAD_SETTLING], PAYLOAD_FAILED, None).await;
+    }
+
+    if settle_resp.success {
+        tracing::info!("Payment settled: {:?}", settle_resp.transaction);
+        Ok(settle_resp)
+    } else {
+        let reason = settle_resp.error_reason.unwrap_or_default();
+        tracing::error!("Settlement failed: {}", reason);
+        Err(SettlementError::Rejected(reason))
     }
 }